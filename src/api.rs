@@ -0,0 +1,696 @@
+use std::{collections::BTreeMap, fmt::Debug, sync::Mutex};
+
+use actix::{dev::ToEnvelope, fut, prelude::*};
+use actix_files::NamedFile;
+use actix_web::{
+    get,
+    http::{header::WWW_AUTHENTICATE, StatusCode},
+    post,
+    web::{self, Data, Json, Path, Query},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth,
+    distance_scan::{ScanResult, Scanner, ScannerMessage},
+    drive::{Drive, DriveMessage, DriveState, GetDriveState},
+    estop::{Cause, EStop, ResetEmergencyStop},
+    hc_sr04::{HcSr04, HcSr04Query},
+    mission::{
+        Mission, MissionLibrary, MissionMessage, MissionProgress, MissionRunner, MissionStatus,
+    },
+    mode::{Acquire, Command, GetMode, Mode, ModeManager, Preempted},
+    movement_calibration::{Calibrator, CalibratorMessage},
+    protocol::{ErrorCode, SocketError, SocketPayload},
+    recorder::{GetSessionFile, ListSessionFiles, Recorder},
+    sessions::{GetSessions, Sessions, SessionsState},
+    shutdown::Shutdown,
+    telemetry::Telemetry,
+};
+
+/// Number of jobs kept for `GET /api/state`, older ones are forgotten
+const MAX_JOBS: usize = 32;
+
+/// Most measurements averaged by one distance request, which blocks the sensor meanwhile
+const MAX_SAMPLES: usize = 50;
+
+impl ResponseError for SocketError {
+    fn status_code(&self) -> StatusCode {
+        match self.code {
+            ErrorCode::InvalidJson | ErrorCode::InvalidMessage | ErrorCode::UnsupportedVersion => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::DeviceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::DriveError | ErrorCode::SensorError | ErrorCode::ApproachFailed => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ErrorCode::NotPilot
+            | ErrorCode::ControlUnavailable
+            | ErrorCode::ModeConflict
+            | ErrorCode::EmergencyStopped => StatusCode::CONFLICT,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        // Lets browsers ask for the password
+        if self.code == ErrorCode::Unauthorized {
+            response.insert_header((WWW_AUTHENTICATE, auth::CHALLENGE));
+        }
+        response.json(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Scan,
+    Calibration,
+    Mission,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Finished,
+    /// Stopped by a request or superseded by a newer job of the same kind
+    Stopped,
+}
+
+/// Long-running action started through the REST API
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    id: usize,
+    kind: JobKind,
+    status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<SocketPayload>,
+}
+
+/// Runs the scanner and calibrator on behalf of REST clients, and missions on behalf of all
+/// clients, and keeps track of their jobs
+pub struct Jobs {
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    drive_addr: Addr<Drive>,
+    hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    sessions_addr: Addr<Sessions>,
+    telemetry_addr: Addr<Telemetry>,
+    missions: MissionLibrary,
+    scanner_addr: Option<Addr<Scanner>>,
+    calibrator_addr: Option<Addr<Calibrator>>,
+    mission_addr: Option<Addr<MissionRunner>>,
+    jobs: BTreeMap<usize, Job>,
+    next_id: usize,
+}
+
+impl Jobs {
+    pub fn new(
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+        drive_addr: Addr<Drive>,
+        hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        sessions_addr: Addr<Sessions>,
+        telemetry_addr: Addr<Telemetry>,
+        missions: MissionLibrary,
+    ) -> Self {
+        Self {
+            mode_manager_data,
+            drive_addr,
+            hc_sr04_data,
+            sessions_addr,
+            telemetry_addr,
+            missions,
+            scanner_addr: None,
+            calibrator_addr: None,
+            mission_addr: None,
+            jobs: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn running_job(&mut self, kind: JobKind) -> Option<&mut Job> {
+        self.jobs
+            .values_mut()
+            .rev()
+            .find(|job| job.kind == kind && job.status == JobStatus::Running)
+    }
+
+    /// Marks the running job of `kind` as `status`, returns it
+    fn end_job(&mut self, kind: JobKind, status: JobStatus) -> Option<Job> {
+        let job = self.running_job(kind)?;
+        job.status = status;
+        Some(job.clone())
+    }
+
+    /// Registers a new running job, superseding the previous one of the same kind
+    fn start_job(&mut self, kind: JobKind) -> Job {
+        self.end_job(kind, JobStatus::Stopped);
+        let job = Job {
+            id: self.next_id,
+            kind,
+            status: JobStatus::Running,
+            result: None,
+        };
+        self.next_id += 1;
+        self.jobs.insert(job.id, job.clone());
+        while self.jobs.len() > MAX_JOBS {
+            self.jobs.pop_first();
+        }
+        job
+    }
+
+    /// The calibrator stops itself once it has gone through all duty cycles, the scanner only stops
+    /// when it's preempted. Missions report how they ended themselves
+    fn update_jobs(&mut self) {
+        if self
+            .calibrator_addr
+            .as_ref()
+            .is_some_and(|addr| !addr.connected())
+        {
+            self.calibrator_addr = None;
+            self.end_job(JobKind::Calibration, JobStatus::Finished);
+        }
+        if self
+            .scanner_addr
+            .as_ref()
+            .is_some_and(|addr| !addr.connected())
+        {
+            self.scanner_addr = None;
+            self.end_job(JobKind::Scan, JobStatus::Stopped);
+        }
+        if self
+            .mission_addr
+            .as_ref()
+            .is_some_and(|addr| !addr.connected())
+        {
+            self.mission_addr = None;
+        }
+    }
+
+    /// Creates a runner for the mission file `name` or the given `steps`. Only one mission runs at
+    /// a time, it has to be aborted before another one is started
+    fn create_mission(
+        &self,
+        msg: &MissionMessage,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<Addr<MissionRunner>, SocketError> {
+        if self.mission_addr.is_some() {
+            return Err(SocketError::new(
+                ErrorCode::ModeConflict,
+                "a mission is already running, abort it first",
+            ));
+        }
+        let (name, mission) = match msg {
+            MissionMessage::Start { name } => (Some(name.clone()), self.missions.load(name)?),
+            MissionMessage::Run { steps } => {
+                let mission = Mission {
+                    steps: steps.clone(),
+                };
+                mission.validate()?;
+                (None, mission)
+            }
+            _ => unreachable!("missions are created by Start or Run"),
+        };
+        info!("creating mission runner");
+        Ok(MissionRunner::new(
+            self.mode_manager_data.clone(),
+            self.drive_addr.clone(),
+            self.hc_sr04_data.clone(),
+            self.telemetry_addr.clone(),
+            ctx.address().recipient(),
+            name,
+            mission,
+        )
+        .start())
+    }
+
+    /// Acquires `mode` for the behaviour `addr`, then sends it `start` and registers a job of
+    /// `kind`. A behaviour which didn't get the mode is sent `stop`
+    fn start_behaviour<B, M>(
+        &self,
+        kind: JobKind,
+        mode: Mode,
+        addr: Addr<B>,
+        start: M,
+        stop: M,
+    ) -> ResponseActFuture<Self, Result<Option<Job>, SocketError>>
+    where
+        B: Actor + Handler<M> + Handler<Preempted>,
+        B::Context: ToEnvelope<B, M> + ToEnvelope<B, Preempted>,
+        M: Message<Result = ()> + Send + Debug + 'static,
+    {
+        let acquire = Acquire {
+            mode,
+            owner: addr.clone().recipient(),
+        };
+        let request = match self.mode_manager_data.lock() {
+            Ok(mode_manager_addr) => mode_manager_addr.send(acquire),
+            Err(e) => return Box::pin(fut::ready(Err(e.into()))),
+        };
+        Box::pin(
+            fut::wrap_future(request).map(move |result, act: &mut Self, _ctx| {
+                if let Err(e) = result.map_err(SocketError::from).and_then(|result| result) {
+                    addr.do_send(stop);
+                    return Err(e);
+                }
+                addr.try_send(start)?;
+                Ok(Some(act.start_job(kind)))
+            }),
+        )
+    }
+}
+
+impl Actor for Jobs {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("actor started");
+    }
+}
+
+/// Starts or stops a job, results in the started or stopped job, if any
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Option<Job>, SocketError>")]
+pub enum JobMessage {
+    Scan(ScannerMessage),
+    Calibration(CalibratorMessage),
+    /// Results in the mission job for every message, `Pause`, `Resume` and `Skip` only take
+    /// effect once its progress is reported
+    Mission(MissionMessage),
+}
+
+impl Handler<JobMessage> for Jobs {
+    type Result = ResponseActFuture<Self, Result<Option<Job>, SocketError>>;
+
+    fn handle(&mut self, msg: JobMessage, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        self.update_jobs();
+        let result = match msg {
+            JobMessage::Scan(
+                msg @ ScannerMessage::Start {
+                    speed,
+                    slip,
+                    resolution,
+                },
+            ) => {
+                if let Err(e) = msg.validate() {
+                    return Box::pin(fut::ready(Err(e)));
+                }
+                let addr = self.scanner_addr.get_or_insert_with(|| {
+                    Scanner::new(
+                        self.mode_manager_data.clone(),
+                        self.hc_sr04_data.clone(),
+                        self.telemetry_addr.clone(),
+                        ctx.address().recipient(),
+                        speed,
+                        slip,
+                        resolution,
+                    )
+                    .start()
+                });
+                let addr = addr.clone();
+                return self.start_behaviour(
+                    JobKind::Scan,
+                    Mode::Scanning,
+                    addr,
+                    msg,
+                    ScannerMessage::Stop,
+                );
+            }
+            JobMessage::Scan(ScannerMessage::Stop) => self
+                .scanner_addr
+                .take()
+                .map_or(Ok(()), |addr| addr.try_send(ScannerMessage::Stop))
+                .map(|()| self.end_job(JobKind::Scan, JobStatus::Stopped))
+                .map_err(SocketError::from),
+            JobMessage::Calibration(msg @ CalibratorMessage::Start(params)) => {
+                if let Err(e) = params.validate() {
+                    return Box::pin(fut::ready(Err(e)));
+                }
+                let addr = self.calibrator_addr.get_or_insert_with(|| {
+                    Calibrator::new(
+                        self.mode_manager_data.clone(),
+                        self.hc_sr04_data.clone(),
+                        self.telemetry_addr.clone(),
+                        params,
+                    )
+                    .start()
+                });
+                let addr = addr.clone();
+                return self.start_behaviour(
+                    JobKind::Calibration,
+                    Mode::Calibrating,
+                    addr,
+                    msg,
+                    CalibratorMessage::Stop,
+                );
+            }
+            JobMessage::Calibration(CalibratorMessage::Stop) => self
+                .calibrator_addr
+                .take()
+                .map_or(Ok(()), |addr| addr.try_send(CalibratorMessage::Stop))
+                .map(|()| self.end_job(JobKind::Calibration, JobStatus::Stopped))
+                .map_err(SocketError::from),
+            JobMessage::Mission(
+                msg @ (MissionMessage::Start { .. } | MissionMessage::Run { .. }),
+            ) => {
+                let addr = match self.create_mission(&msg, ctx) {
+                    Ok(addr) => addr,
+                    Err(e) => return Box::pin(fut::ready(Err(e))),
+                };
+                self.mission_addr = Some(addr.clone());
+                return self.start_behaviour(
+                    JobKind::Mission,
+                    Mode::Mission,
+                    addr,
+                    msg,
+                    MissionMessage::Abort,
+                );
+            }
+            JobMessage::Mission(MissionMessage::Abort) => self
+                .mission_addr
+                .take()
+                .map_or(Ok(()), |addr| addr.try_send(MissionMessage::Abort))
+                .map(|()| self.end_job(JobKind::Mission, JobStatus::Stopped))
+                .map_err(SocketError::from),
+            JobMessage::Mission(msg) => match &self.mission_addr {
+                Some(addr) => addr
+                    .try_send(msg)
+                    .map(|()| self.running_job(JobKind::Mission).cloned())
+                    .map_err(SocketError::from),
+                None => Err(SocketError::new(
+                    ErrorCode::NotFound,
+                    "no mission is running",
+                )),
+            },
+        };
+        Box::pin(fut::ready(result))
+    }
+}
+
+impl Handler<Shutdown> for Jobs {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(addr) = self.scanner_addr.take() {
+            addr.do_send(ScannerMessage::Stop);
+        }
+        if let Some(addr) = self.calibrator_addr.take() {
+            addr.do_send(CalibratorMessage::Stop);
+        }
+        if let Some(addr) = self.mission_addr.take() {
+            addr.do_send(MissionMessage::Abort);
+        }
+        self.end_job(JobKind::Scan, JobStatus::Stopped);
+        self.end_job(JobKind::Calibration, JobStatus::Stopped);
+        self.end_job(JobKind::Mission, JobStatus::Stopped);
+    }
+}
+
+impl Handler<ScanResult> for Jobs {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScanResult, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(job) = self.running_job(JobKind::Scan) {
+            job.status = JobStatus::Finished;
+            job.result = Some(SocketPayload::ScanDistance {
+                measurements: msg.measurements.clone(),
+            });
+        }
+        // WebSocket clients see the scan as well
+        self.sessions_addr.do_send(msg);
+    }
+}
+
+impl Handler<MissionProgress> for Jobs {
+    type Result = ();
+
+    fn handle(&mut self, msg: MissionProgress, _ctx: &mut Self::Context) -> Self::Result {
+        let status = match msg.status {
+            MissionStatus::Finished => Some(JobStatus::Finished),
+            MissionStatus::Aborted | MissionStatus::Failed => Some(JobStatus::Stopped),
+            MissionStatus::Idle | MissionStatus::Running | MissionStatus::Paused => None,
+        };
+        // An aborted mission's job already ended, it still gets the final progress
+        let job = self
+            .jobs
+            .values_mut()
+            .rev()
+            .find(|job| job.kind == JobKind::Mission);
+        if let Some(job) = job {
+            job.result = Some(SocketPayload::Mission(msg.clone()));
+            if let Some(status) = status.filter(|_| job.status == JobStatus::Running) {
+                job.status = status;
+            }
+        }
+        // WebSocket clients follow the mission as well
+        self.sessions_addr.do_send(msg);
+    }
+}
+
+/// Requests the names of all mission files
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Vec<String>, SocketError>")]
+pub struct ListMissions;
+
+impl Handler<ListMissions> for Jobs {
+    type Result = Result<Vec<String>, SocketError>;
+
+    fn handle(&mut self, _msg: ListMissions, _ctx: &mut Self::Context) -> Self::Result {
+        self.missions
+            .list()
+            .map_err(|e| SocketError::new(ErrorCode::DeviceUnavailable, e))
+    }
+}
+
+/// Requests all remembered jobs, oldest first
+#[derive(Debug, Message)]
+#[rtype(result = "Vec<Job>")]
+pub struct GetJobs;
+
+impl Handler<GetJobs> for Jobs {
+    type Result = Vec<Job>;
+
+    fn handle(&mut self, _msg: GetJobs, _ctx: &mut Self::Context) -> Self::Result {
+        self.update_jobs();
+        self.jobs.values().cloned().collect()
+    }
+}
+
+/// REST clients may only control the robot while no WebSocket session is the pilot
+async fn check_pilot(sessions_data: &Data<Mutex<Addr<Sessions>>>) -> Result<(), SocketError> {
+    let sessions_addr = sessions_data.lock()?.clone();
+    match sessions_addr.send(GetSessions).await?.pilot {
+        Some(pilot) => Err(SocketError::new(
+            ErrorCode::NotPilot,
+            format!("session {pilot} is the pilot, ask it to release control"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Started jobs are accepted, stopped ones returned as they ended
+fn job_response(job: Option<Job>) -> HttpResponse {
+    match job {
+        Some(job) if job.status == JobStatus::Running => HttpResponse::Accepted().json(job),
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+/// Runs a `DriveMessage`, responds once the drive has handled it
+#[post("/api/drive")]
+async fn post_drive(
+    message: Json<DriveMessage>,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+) -> Result<HttpResponse, SocketError> {
+    check_pilot(&sessions_data).await?;
+    let message = message.into_inner();
+    info!("sending {message:?} to mode manager");
+    let mode_manager_addr = mode_manager_data.lock()?.clone();
+    let duty_cycles = mode_manager_addr.send(Command::manual(message)).await??;
+    Ok(HttpResponse::Ok().json(SocketPayload::Move {
+        command: message,
+        duty_cycles,
+    }))
+}
+
+/// Engages the emergency stop, allowed even while a WebSocket session is the pilot
+#[post("/api/estop")]
+async fn post_estop(estop: Data<EStop>) -> Result<HttpResponse, SocketError> {
+    let reason = "requested over the REST API";
+    info!("emergency stop {reason}");
+    // Brakes right away, even if the mode manager or the drive are busy
+    estop.trigger(Cause::Client, reason);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/api/estop/reset")]
+async fn post_estop_reset(
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+) -> Result<HttpResponse, SocketError> {
+    check_pilot(&sessions_data).await?;
+    let mode_manager_addr = mode_manager_data.lock()?.clone();
+    mode_manager_addr.send(ResetEmergencyStop).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct DistanceQuery {
+    /// Number of measurements to average
+    samples: Option<usize>,
+}
+
+#[get("/api/distance")]
+async fn get_distance(
+    query: Query<DistanceQuery>,
+    hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+) -> Result<HttpResponse, SocketError> {
+    let samples = query.samples.unwrap_or(1);
+    if !(1..=MAX_SAMPLES).contains(&samples) {
+        return Err(SocketError::new(
+            ErrorCode::InvalidMessage,
+            format!("samples must be within 1..={MAX_SAMPLES}"),
+        ));
+    }
+    let hc_sr04_addr = hc_sr04_data.lock()?.clone();
+    let measurements = hc_sr04_addr
+        .send(HcSr04Query(samples))
+        .await?
+        .map_err(|e| SocketError::new(ErrorCode::SensorError, e))?;
+    Ok(HttpResponse::Ok().json(SocketPayload::measure_distance(
+        measurements.iter().map(|x| x.distance).collect(),
+    )))
+}
+
+#[post("/api/scan")]
+async fn post_scan(
+    message: Json<ScannerMessage>,
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+) -> Result<HttpResponse, SocketError> {
+    check_pilot(&sessions_data).await?;
+    let jobs_addr = jobs_data.lock()?.clone();
+    let job = jobs_addr
+        .send(JobMessage::Scan(message.into_inner()))
+        .await??;
+    Ok(job_response(job))
+}
+
+#[post("/api/calibration")]
+async fn post_calibration(
+    message: Json<CalibratorMessage>,
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+) -> Result<HttpResponse, SocketError> {
+    check_pilot(&sessions_data).await?;
+    let jobs_addr = jobs_data.lock()?.clone();
+    let job = jobs_addr
+        .send(JobMessage::Calibration(message.into_inner()))
+        .await??;
+    Ok(job_response(job))
+}
+
+/// Names of the mission files, which can be started with `Start`
+#[get("/api/missions")]
+async fn get_missions(jobs_data: Data<Mutex<Addr<Jobs>>>) -> Result<HttpResponse, SocketError> {
+    let jobs_addr = jobs_data.lock()?.clone();
+    let names = jobs_addr.send(ListMissions).await??;
+    Ok(HttpResponse::Ok().json(names))
+}
+
+#[post("/api/mission")]
+async fn post_mission(
+    message: Json<MissionMessage>,
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+) -> Result<HttpResponse, SocketError> {
+    check_pilot(&sessions_data).await?;
+    let jobs_addr = jobs_data.lock()?.clone();
+    let job = jobs_addr
+        .send(JobMessage::Mission(message.into_inner()))
+        .await??;
+    Ok(job_response(job))
+}
+
+#[derive(Debug, Serialize)]
+struct State {
+    mode: Mode,
+    #[serde(flatten)]
+    drive: DriveState,
+    #[serde(flatten)]
+    sessions: SessionsState,
+    jobs: Vec<Job>,
+}
+
+#[get("/api/state")]
+async fn get_state(
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    drive_data: Data<Mutex<Addr<Drive>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+) -> Result<HttpResponse, SocketError> {
+    let mode_manager_addr = mode_manager_data.lock()?.clone();
+    let drive_addr = drive_data.lock()?.clone();
+    let sessions_addr = sessions_data.lock()?.clone();
+    let jobs_addr = jobs_data.lock()?.clone();
+    let state = State {
+        mode: mode_manager_addr.send(GetMode).await?,
+        drive: drive_addr.send(GetDriveState).await?,
+        sessions: sessions_addr.send(GetSessions).await?,
+        jobs: jobs_addr.send(GetJobs).await?,
+    };
+    Ok(HttpResponse::Ok().json(state))
+}
+
+/// Flight recorder session files, oldest first
+#[get("/api/recordings")]
+async fn get_recordings() -> Result<HttpResponse, SocketError> {
+    let files = Recorder::from_registry()
+        .send(ListSessionFiles)
+        .await?
+        .map_err(|e| SocketError::new(ErrorCode::DeviceUnavailable, e))?;
+    Ok(HttpResponse::Ok().json(files))
+}
+
+/// Downloads a flight recorder session file
+#[get("/api/recordings/{name}")]
+async fn get_recording(req: HttpRequest, name: Path<String>) -> Result<HttpResponse, SocketError> {
+    let name = name.into_inner();
+    let not_found = || SocketError::new(ErrorCode::NotFound, format!("no recording {name}"));
+    let path = Recorder::from_registry()
+        .send(GetSessionFile(name.clone()))
+        .await?
+        .ok_or_else(not_found)?;
+    let file = NamedFile::open_async(path).await.map_err(|_| not_found())?;
+    Ok(file.into_response(&req))
+}
+
+/// Registers the REST API routes, invalid requests are answered with `SocketError`s
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(
+        web::JsonConfig::default()
+            .error_handler(|e, _| SocketError::new(ErrorCode::InvalidMessage, e).into()),
+    )
+    .app_data(
+        web::QueryConfig::default()
+            .error_handler(|e, _| SocketError::new(ErrorCode::InvalidMessage, e).into()),
+    )
+    .service(post_drive)
+    .service(post_estop)
+    .service(post_estop_reset)
+    .service(get_distance)
+    .service(post_scan)
+    .service(post_calibration)
+    .service(get_missions)
+    .service(post_mission)
+    .service(get_state)
+    .service(get_recordings)
+    .service(get_recording);
+}
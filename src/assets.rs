@@ -0,0 +1,129 @@
+use std::path::{Component, Path, PathBuf};
+
+use actix_files::{file_extension_to_mime, NamedFile};
+use actix_web::{
+    get,
+    web::{self, Data},
+    HttpRequest, HttpResponse,
+};
+use rust_embed::RustEmbed;
+use serde::Serialize;
+
+use crate::drive::{
+    Speed, ROBOT_RADIUS, VELOCITY_EXPONENT, VELOCITY_OFFSET, VELOCITY_SCALE, WHEEL_CIRCUMFERENCE,
+};
+
+/// Web UI compiled into the binary
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Assets;
+
+/// How the web UI is served
+#[derive(Clone, Debug)]
+pub struct AssetsConfig {
+    /// Files in this directory take precedence over the embedded ones, for development
+    pub override_dir: Option<PathBuf>,
+    /// Shown by the web UI
+    pub robot_name: String,
+}
+
+/// Serves `path` from the override directory or the embedded assets
+async fn serve(req: &HttpRequest, config: &AssetsConfig, path: &str) -> HttpResponse {
+    // Only plain relative paths, so that the override directory can't be escaped
+    let is_relative = Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !is_relative {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Some(dir) = &config.override_dir {
+        if let Ok(file) = NamedFile::open_async(dir.join(path)).await {
+            return file.into_response(req);
+        }
+    }
+    match Assets::get(path) {
+        Some(file) => {
+            let extension = Path::new(path)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or_default();
+            HttpResponse::Ok()
+                .content_type(file_extension_to_mime(extension))
+                .body(file.data.into_owned())
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/")]
+async fn index(req: HttpRequest, config: Data<AssetsConfig>) -> HttpResponse {
+    serve(&req, &config, "index.html").await
+}
+
+#[get("/static/{path:.*}")]
+async fn static_file(
+    req: HttpRequest,
+    path: web::Path<String>,
+    config: Data<AssetsConfig>,
+) -> HttpResponse {
+    serve(&req, &config, &path).await
+}
+
+/// Duty cycles allowed by the speed slider
+#[derive(Debug, Serialize)]
+struct SpeedLimits {
+    min_duty_cycle: f64,
+    max_duty_cycle: f64,
+}
+
+/// See `Speed::get_velocity`
+#[derive(Debug, Serialize)]
+struct VelocityModel {
+    scale: f64,
+    offset: f64,
+    exponent: f64,
+    wheel_circumference: f64,
+    robot_radius: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct ClientConfig {
+    robot_name: String,
+    /// WebSocket URL on the host and scheme the page was loaded from
+    websocket_url: String,
+    speed_limits: SpeedLimits,
+    velocity_model: VelocityModel,
+}
+
+/// Tells the web UI where to connect and how the robot moves
+#[get("/config.json")]
+async fn client_config(req: HttpRequest, config: Data<AssetsConfig>) -> HttpResponse {
+    let connection = req.connection_info();
+    let scheme = if connection.scheme() == "https" {
+        "wss"
+    } else {
+        "ws"
+    };
+    HttpResponse::Ok().json(ClientConfig {
+        robot_name: config.robot_name.clone(),
+        websocket_url: format!("{scheme}://{}/ws", connection.host()),
+        speed_limits: SpeedLimits {
+            min_duty_cycle: Speed::Low.get_duty_cycle(),
+            max_duty_cycle: Speed::High.get_duty_cycle(),
+        },
+        velocity_model: VelocityModel {
+            scale: VELOCITY_SCALE,
+            offset: VELOCITY_OFFSET,
+            exponent: VELOCITY_EXPONENT,
+            wheel_circumference: WHEEL_CIRCUMFERENCE,
+            robot_radius: ROBOT_RADIUS,
+        },
+    })
+}
+
+/// Registers the web UI routes
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(index)
+        .service(static_file)
+        .service(client_config);
+}
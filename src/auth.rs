@@ -0,0 +1,112 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::Header, Method},
+    middleware::Next,
+    web::{Data, Query},
+    HttpMessage,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic, Bearer};
+use log::debug;
+use serde::Deserialize;
+
+use crate::protocol::{ErrorCode, SocketError};
+
+/// `WWW-Authenticate` challenge sent with `401 Unauthorized`
+pub const CHALLENGE: &str = r#"Basic realm="mecanum-car""#;
+
+/// Who may use the robot
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    /// Bearer token or password, `None` disables authentication
+    pub token: Option<String>,
+    /// Whether unauthenticated clients may still watch the robot
+    pub read_only_access: bool,
+}
+
+/// Granted to each request by `authorize`, available to handlers as `ReqData<Access>`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Full,
+    /// Only `GET` requests, WebSocket sessions can't become the pilot
+    ReadOnly,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+/// Compares in constant time, so that the token can't be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Token of an `Authorization: Bearer` header, the password of an `Authorization: Basic` header
+/// (the user name is ignored) or the `token` query parameter, as browsers can't set headers on
+/// WebSocket handshakes
+fn credentials(req: &ServiceRequest) -> Option<String> {
+    if let Ok(bearer) = Authorization::<Bearer>::parse(req) {
+        return Some(bearer.as_ref().token().to_string());
+    }
+    if let Ok(basic) = Authorization::<Basic>::parse(req) {
+        return basic
+            .as_ref()
+            .password()
+            .map(|password| password.to_string());
+    }
+    Query::<TokenQuery>::from_query(req.query_string())
+        .ok()
+        .map(|query| query.into_inner().token)
+}
+
+impl AuthConfig {
+    fn access(&self, req: &ServiceRequest) -> Option<Access> {
+        let Some(token) = &self.token else {
+            return Some(Access::Full);
+        };
+        match credentials(req) {
+            Some(credentials) if constant_time_eq(credentials.as_bytes(), token.as_bytes()) => {
+                Some(Access::Full)
+            }
+            _ if self.read_only_access && req.method() == Method::GET => Some(Access::ReadOnly),
+            _ => None,
+        }
+    }
+}
+
+/// Middleware rejecting requests without a valid token, unless read-only access is allowed
+pub async fn authorize(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let config = req
+        .app_data::<Data<AuthConfig>>()
+        .expect("AuthConfig not registered");
+    let Some(access) = config.access(&req) else {
+        debug!("rejecting unauthorized request to {}", req.path());
+        return Err(SocketError::new(ErrorCode::Unauthorized, "missing or invalid token").into());
+    };
+    req.extensions_mut().insert(access);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+    }
+
+    #[test]
+    fn constant_time_eq_rejects_different_lengths() {
+        assert!(!constant_time_eq(b"secret", b"secre"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+}
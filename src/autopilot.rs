@@ -0,0 +1,418 @@
+use std::{sync::Mutex, time::Duration};
+
+use actix::prelude::*;
+use actix_web::web::Data;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    distance_scan::{self, ScanResult, Scanner, ScannerMessage},
+    drive::{DriveMessage, Motion, Speed},
+    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response, MAX_RANGE},
+    mode::{Command, Mode, ModeManager, Preempted, Release},
+    protocol::{ErrorCode, SocketError},
+    sessions::Sessions,
+    telemetry::{Behaviour, BehaviourMessage, Telemetry},
+};
+
+/// How often the distance is measured while driving
+const MEASUREMENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait after a rotation before driving forward again
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Shortest rotation towards a clockwise angle in degrees, which may lie outside 0..360
+fn turn_direction(angle: f32) -> (Motion, f64) {
+    let angle = angle.rem_euclid(360.) as f64;
+    if angle <= 180. {
+        (Motion::RightRot, angle)
+    } else {
+        (Motion::LeftRot, 360. - angle)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct AutopilotParams {
+    /// Speed used for driving, scanning and turning
+    speed: Speed,
+    /// Distance to an obstacle (in meters) at which the robot stops and looks around
+    obstacle_distance: f32,
+    /// Rotation slip, as set with the frontend `Slip` slider
+    slip: f64,
+    /// Number of measurements taken during a scan
+    resolution: usize,
+}
+
+impl AutopilotParams {
+    /// Checks the parameters, so that an invalid autopilot is rejected before it acquires its mode
+    pub fn validate(&self) -> Result<(), SocketError> {
+        if !(0. < self.obstacle_distance && self.obstacle_distance <= MAX_RANGE) {
+            return Err(SocketError::new(
+                ErrorCode::InvalidMessage,
+                format!(
+                    "obstacle distance {} is not within the sensor range",
+                    self.obstacle_distance
+                ),
+            ));
+        }
+        distance_scan::validate_params(self.speed, self.slip, self.resolution)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum AutopilotState {
+    Idle,
+    Driving,
+    Scanning,
+    Turning,
+    /// Only reported, the autopilot stops right after entering it
+    Stopped,
+}
+
+/// Drives forward until an obstacle is encountered, then scans the surroundings and proceeds in the
+/// direction with the most free space
+pub struct Autopilot {
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    sessions_addr: Addr<Sessions>,
+    telemetry_addr: Addr<Telemetry>,
+    scanner_addr: Option<Addr<Scanner>>,
+    params: AutopilotParams,
+    state: AutopilotState,
+}
+
+impl Autopilot {
+    pub fn new(
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+        hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        sessions_addr: Addr<Sessions>,
+        telemetry_addr: Addr<Telemetry>,
+        params: AutopilotParams,
+    ) -> Self {
+        Self {
+            mode_manager_data,
+            hc_sr04_data,
+            sessions_addr,
+            telemetry_addr,
+            scanner_addr: None,
+            params,
+            state: AutopilotState::Idle,
+        }
+    }
+
+    /// Sends a drive command on behalf of the `Autonomous` mode
+    fn send_command(&self, ctx: &mut <Self as Actor>::Context, message: DriveMessage) {
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Command::behaviour(
+                Mode::Autonomous,
+                ctx.address().recipient(),
+                message,
+            ));
+    }
+
+    /// Logs a decision and reports it to the clients
+    fn report(&self, response: AutopilotResponse) {
+        info!("{}", response.description);
+        self.sessions_addr.do_send(response);
+    }
+
+    /// Starts driving forward and measuring the distance to the nearest obstacle
+    fn drive(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.state = AutopilotState::Driving;
+        self.send_command(
+            ctx,
+            DriveMessage::Enable {
+                motion: Motion::Forward,
+                speed: self.params.speed,
+            },
+        );
+        self.report(AutopilotResponse::new(
+            AutopilotState::Driving,
+            format!("Driving forward with {:?} speed", self.params.speed),
+        ));
+        self.measure_distance(ctx);
+    }
+
+    fn measure_distance(&self, ctx: &mut <Self as Actor>::Context) {
+        self.hc_sr04_data
+            .lock()
+            .unwrap()
+            .do_send(HcSr04Message::Single(ctx.address().recipient()));
+    }
+
+    /// Stops the robot and scans the surroundings
+    fn scan(&mut self, ctx: &mut <Self as Actor>::Context, distance: f32) {
+        self.state = AutopilotState::Scanning;
+        self.send_command(ctx, DriveMessage::Disable);
+        self.report(AutopilotResponse {
+            distance: Some(distance),
+            ..AutopilotResponse::new(
+                AutopilotState::Scanning,
+                format!("Obstacle detected {distance} m ahead, scanning"),
+            )
+        });
+
+        let AutopilotParams {
+            speed,
+            slip,
+            resolution,
+            ..
+        } = self.params;
+        let scanner_addr = self.scanner_addr.get_or_insert_with(|| {
+            Scanner::new(
+                self.mode_manager_data.clone(),
+                self.hc_sr04_data.clone(),
+                self.telemetry_addr.clone(),
+                ctx.address().recipient(),
+                speed,
+                slip,
+                resolution,
+            )
+            .with_owner(Mode::Autonomous, ctx.address().recipient())
+            .start()
+        });
+        scanner_addr.do_send(ScannerMessage::Start {
+            speed,
+            slip,
+            resolution,
+        });
+    }
+
+    /// Rotates the robot towards `angle` (clockwise, in degrees), then resumes driving
+    fn turn(&mut self, ctx: &mut <Self as Actor>::Context, angle: f32) {
+        self.state = AutopilotState::Turning;
+        let (motion, angle) = turn_direction(angle);
+        let angle = angle * (1. + self.params.slip);
+        let speed = self.params.speed;
+        let time = match speed.get_rotation_time(angle) {
+            Ok(time) => time,
+            Err(e) => {
+                self.report(AutopilotResponse::failed(
+                    e.to_string(),
+                    format!("Can't turn {angle:.0} deg: {e}, stopping"),
+                ));
+                ctx.stop();
+                return;
+            }
+        };
+        self.send_command(
+            ctx,
+            DriveMessage::Rotate {
+                motion,
+                speed,
+                angle,
+            },
+        );
+        let clockwise = if motion == Motion::RightRot {
+            angle
+        } else {
+            -angle
+        };
+        self.report(AutopilotResponse {
+            angle: Some(clockwise),
+            ..AutopilotResponse::new(
+                AutopilotState::Turning,
+                format!("Turning {motion:?} {angle:.0} deg"),
+            )
+        });
+
+        ctx.run_later(time + SETTLE_TIME, |act, ctx| {
+            if act.state == AutopilotState::Turning {
+                act.drive(ctx);
+            }
+        });
+    }
+}
+
+impl Actor for Autopilot {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Started(Behaviour::Autopilot));
+        info!("actor started");
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Stopped(Behaviour::Autopilot));
+        if let Some(addr) = &self.scanner_addr {
+            addr.do_send(ScannerMessage::Stop);
+        }
+        // Stops the robot
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Release(ctx.address().recipient()));
+        info!("actor stopped");
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Message)]
+#[rtype(result = "()")]
+#[serde(tag = "variant")]
+pub enum AutopilotMessage {
+    Start(AutopilotParams),
+    Stop,
+}
+
+impl Handler<AutopilotMessage> for Autopilot {
+    type Result = ();
+
+    fn handle(&mut self, msg: AutopilotMessage, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        match msg {
+            AutopilotMessage::Start(params) => {
+                self.params = params;
+                if self.state == AutopilotState::Idle {
+                    self.drive(ctx);
+                }
+            }
+            AutopilotMessage::Stop => {
+                self.report(AutopilotResponse::new(
+                    AutopilotState::Stopped,
+                    "Autopilot stopped".to_string(),
+                ));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Handler<Preempted> for Autopilot {
+    type Result = ();
+
+    fn handle(&mut self, msg: Preempted, ctx: &mut Self::Context) -> Self::Result {
+        self.report(AutopilotResponse::failed(
+            format!("preempted by {:?} mode", msg.0),
+            format!("Autopilot preempted by {:?} mode", msg.0),
+        ));
+        ctx.stop();
+    }
+}
+
+impl Handler<HcSr04Response> for Autopilot {
+    type Result = ();
+
+    fn handle(&mut self, msg: HcSr04Response, ctx: &mut Self::Context) -> Self::Result {
+        // Measurements may still arrive after an obstacle was detected
+        if self.state != AutopilotState::Driving {
+            return;
+        }
+        match msg {
+            HcSr04Response::Ok(HcSr04Measurement::Single(result))
+                if result.distance < self.params.obstacle_distance =>
+            {
+                self.scan(ctx, result.distance);
+            }
+            HcSr04Response::Ok(_) => {
+                ctx.run_later(MEASUREMENT_INTERVAL, |act, ctx| {
+                    if act.state == AutopilotState::Driving {
+                        act.measure_distance(ctx);
+                    }
+                });
+            }
+            HcSr04Response::Err(e) => {
+                self.report(AutopilotResponse::failed(
+                    e.to_string(),
+                    format!("Distance measurement failed: {e}, stopping"),
+                ));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Handler<ScanResult> for Autopilot {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScanResult, ctx: &mut Self::Context) -> Self::Result {
+        if self.state != AutopilotState::Scanning {
+            return;
+        }
+        // Timed out measurements are reported as infinite distance, so they count as free space
+        let best = msg
+            .measurements
+            .iter()
+            .copied()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        match best {
+            Some((angle, distance)) => {
+                self.report(AutopilotResponse {
+                    distance: Some(distance),
+                    angle: Some(angle as f64),
+                    ..AutopilotResponse::new(
+                        AutopilotState::Scanning,
+                        format!("Most free space ({distance} m) found at {angle:.0} deg"),
+                    )
+                });
+                self.turn(ctx, angle);
+            }
+            None => {
+                self.report(AutopilotResponse::failed(
+                    "scan returned no measurements".to_string(),
+                    "Scan returned no measurements, stopping".to_string(),
+                ));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+/// Decision made by the autopilot
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+pub struct AutopilotResponse {
+    /// State the autopilot entered
+    pub state: AutopilotState,
+    /// Distance (in meters) of the detected obstacle or of the most free space
+    pub distance: Option<f32>,
+    /// Clockwise angle (in degrees) of the most free space or of the turn
+    pub angle: Option<f64>,
+    /// Why the autopilot stopped, unless it was asked to
+    pub error: Option<String>,
+    /// Human-readable summary, only meant to be displayed
+    pub description: String,
+}
+
+impl AutopilotResponse {
+    fn new(state: AutopilotState, description: String) -> Self {
+        Self {
+            state,
+            distance: None,
+            angle: None,
+            error: None,
+            description,
+        }
+    }
+
+    fn failed(error: String, description: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::new(AutopilotState::Stopped, description)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turn_direction_takes_shortest_rotation() {
+        assert_eq!(turn_direction(90.), (Motion::RightRot, 90.));
+        assert_eq!(turn_direction(270.), (Motion::LeftRot, 90.));
+    }
+
+    #[test]
+    fn turn_direction_normalizes_angles_above_full_rotation() {
+        let (motion, angle) = turn_direction(400.);
+        assert_eq!(motion, Motion::RightRot);
+        assert!((angle - 40.).abs() < 1e-9);
+        let (motion, angle) = turn_direction(630.);
+        assert_eq!(motion, Motion::LeftRot);
+        assert!((angle - 90.).abs() < 1e-9);
+    }
+}
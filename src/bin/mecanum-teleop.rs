@@ -0,0 +1,446 @@
+//! Terminal client for driving the robot over its `/ws` endpoint, e.g. through SSH.
+//!
+//! Usage: `mecanum-teleop [ws://<address>:7878/ws]`
+//!
+//! Hold `QWE`/`AD`/`ZXC` to drive in the matching direction and the left/right arrows to rotate,
+//! the robot stops when the key is released. `S` or space stops, up/down change the speed, `T`
+//! takes control and `Esc` quits.
+
+use std::{
+    collections::VecDeque,
+    env, io,
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Borders, List, Paragraph},
+    Frame, Terminal,
+};
+use serde_json::{json, Value};
+use tungstenite::{client::IntoClientRequest, Message, WebSocket};
+
+const DEFAULT_URL: &str = "ws://localhost:7878/ws";
+
+/// How often the screen is redrawn and the socket polled
+const TICK: Duration = Duration::from_millis(50);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Terminals without key release events only repeat held keys, the robot stops when no repeat
+/// arrives within this time. It has to exceed the initial key repeat delay
+const RELEASE_TIMEOUT: Duration = Duration::from_millis(600);
+
+const MIN_SPEED: f64 = 0.3;
+const MAX_SPEED: f64 = 1.0;
+const SPEED_STEP: f64 = 0.1;
+
+/// Number of drive responses and errors kept in the log panel
+const LOG_LENGTH: usize = 100;
+
+/// Maps keys to `Motion`s
+fn motion(code: KeyCode) -> Option<&'static str> {
+    let motion = match code {
+        KeyCode::Char(c) => match c.to_ascii_lowercase() {
+            'q' => "ForwardLeft",
+            'w' => "Forward",
+            'e' => "ForwardRight",
+            'a' => "Left",
+            's' => "Stop",
+            'd' => "Right",
+            'z' => "BackwardLeft",
+            'x' => "Backward",
+            'c' => "BackwardRight",
+            _ => return None,
+        },
+        KeyCode::Left => "LeftRot",
+        KeyCode::Right => "RightRot",
+        _ => return None,
+    };
+    Some(motion)
+}
+
+enum NetworkEvent {
+    Connecting,
+    Connected,
+    Disconnected(String),
+    Frame(Value),
+}
+
+fn connect(url: &str) -> Result<WebSocket<TcpStream>, String> {
+    let request = url.into_client_request().map_err(|e| e.to_string())?;
+    let host = request.uri().host().ok_or("URL has no host")?;
+    let port = request.uri().port_u16().unwrap_or(80);
+    let address = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or("host has no address")?;
+    let stream =
+        TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).map_err(|e| e.to_string())?;
+    // Reads time out, so that commands can be sent in between
+    stream
+        .set_read_timeout(Some(TICK))
+        .map_err(|e| e.to_string())?;
+    let (socket, _) = tungstenite::client(request, stream).map_err(|e| e.to_string())?;
+    Ok(socket)
+}
+
+/// Sends `commands` and receives frames until the connection fails
+fn communicate(
+    socket: &mut WebSocket<TcpStream>,
+    commands: &Receiver<String>,
+    events: &Sender<NetworkEvent>,
+) -> Result<(), String> {
+    loop {
+        loop {
+            match commands.try_recv() {
+                Ok(command) => socket
+                    .send(Message::text(command))
+                    .map_err(|e| e.to_string())?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    return Ok(());
+                }
+            }
+        }
+        match socket.read() {
+            Ok(Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(frame) => {
+                    let _ = events.send(NetworkEvent::Frame(frame));
+                }
+                Err(e) => return Err(format!("invalid frame: {e}")),
+            },
+            Ok(Message::Close(_)) => return Err("closed by the robot".to_string()),
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Keeps a connection to the robot open until `commands` is dropped
+fn network(url: String, commands: Receiver<String>, events: Sender<NetworkEvent>) {
+    loop {
+        // Commands issued while disconnected are outdated
+        loop {
+            match commands.try_recv() {
+                Ok(_) => (),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        let _ = events.send(NetworkEvent::Connecting);
+        let result = connect(&url).and_then(|mut socket| {
+            let _ = events.send(NetworkEvent::Connected);
+            let subscribe = json!({"message": "SubscribeTelemetry"}).to_string();
+            socket
+                .send(Message::text(subscribe))
+                .map_err(|e| e.to_string())?;
+            communicate(&mut socket, &commands, &events)
+        });
+        match result {
+            Ok(()) => return,
+            Err(e) => {
+                let _ = events.send(NetworkEvent::Disconnected(e));
+            }
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Disconnected(String),
+}
+
+struct App {
+    url: String,
+    status: ConnectionStatus,
+    commands: Sender<String>,
+    /// Whether the terminal reports key releases
+    release_events: bool,
+    /// Id of this client's session and the pilot session
+    session: Option<usize>,
+    pilot: Option<usize>,
+    speed: f64,
+    /// Motion of the held key, the key and when it was last pressed or repeated
+    held: Option<(&'static str, KeyCode, Instant)>,
+    distance: Option<f64>,
+    sensor_health: Option<String>,
+    log: VecDeque<String>,
+}
+
+impl App {
+    fn new(url: String, commands: Sender<String>, release_events: bool) -> Self {
+        Self {
+            url,
+            status: ConnectionStatus::Connecting,
+            commands,
+            release_events,
+            session: None,
+            pilot: None,
+            speed: 0.5,
+            held: None,
+            distance: None,
+            sensor_health: None,
+            log: VecDeque::new(),
+        }
+    }
+
+    fn push_log(&mut self, line: String) {
+        if self.log.len() == LOG_LENGTH {
+            self.log.pop_back();
+        }
+        self.log.push_front(line);
+    }
+
+    fn send(&self, message: Value) {
+        let _ = self.commands.send(message.to_string());
+    }
+
+    fn enable(&self, motion: &str) {
+        self.send(json!({
+            "message": "Move",
+            "variant": "Enable",
+            "motion": motion,
+            "speed": {"Manual": self.speed},
+        }));
+    }
+
+    fn stop(&mut self) {
+        self.held = None;
+        self.send(json!({"message": "Move", "variant": "Disable"}));
+    }
+
+    fn change_speed(&mut self, step: f64) {
+        self.speed = (self.speed + step).clamp(MIN_SPEED, MAX_SPEED);
+        if let Some((motion, _, _)) = self.held {
+            self.enable(motion);
+        }
+    }
+
+    /// Handles a key, returns `false` to quit
+    fn key_handler(&mut self, key: KeyEvent) -> bool {
+        if key.kind == KeyEventKind::Release {
+            if matches!(self.held, Some((_, code, _)) if code == key.code) {
+                self.stop();
+            }
+            return true;
+        }
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char(' ') => self.stop(),
+            KeyCode::Up | KeyCode::Char('+') => self.change_speed(SPEED_STEP),
+            KeyCode::Down | KeyCode::Char('-') => self.change_speed(-SPEED_STEP),
+            KeyCode::Char('t') | KeyCode::Char('T') => self.send(json!({"message": "TakeControl"})),
+            code => match motion(code) {
+                Some("Stop") => self.stop(),
+                Some(motion) => {
+                    // Repeats of the held key only keep it alive
+                    if !matches!(self.held, Some((held, _, _)) if held == motion) {
+                        self.enable(motion);
+                    }
+                    self.held = Some((motion, code, Instant::now()));
+                }
+                None => (),
+            },
+        }
+        true
+    }
+
+    /// Stops once the held key stopped repeating, if releases aren't reported
+    fn tick(&mut self) {
+        if self.release_events {
+            return;
+        }
+        if matches!(self.held, Some((_, _, pressed)) if pressed.elapsed() > RELEASE_TIMEOUT) {
+            self.stop();
+        }
+    }
+
+    fn network_handler(&mut self, event: NetworkEvent) {
+        match event {
+            NetworkEvent::Connecting => self.status = ConnectionStatus::Connecting,
+            NetworkEvent::Connected => self.status = ConnectionStatus::Connected,
+            NetworkEvent::Disconnected(reason) => {
+                self.held = None;
+                self.session = None;
+                self.pilot = None;
+                self.status = ConnectionStatus::Disconnected(reason);
+            }
+            NetworkEvent::Frame(frame) => self.frame_handler(frame),
+        }
+    }
+
+    fn frame_handler(&mut self, frame: Value) {
+        // Payloads and errors are flattened into the frame
+        if frame["type"] == "error" {
+            let line = format!("error {}: {}", frame["code"], frame["description"]);
+            self.push_log(line);
+            return;
+        }
+        match frame["variant"].as_str() {
+            Some("Move") => {
+                let command = &frame["command"];
+                let line = match command["variant"].as_str() {
+                    Some("Enable") => format!("{} {}", command["motion"], command["speed"]),
+                    variant => variant.unwrap_or("?").to_string(),
+                };
+                self.push_log(format!("{line} -> {}", frame["duty_cycles"]));
+            }
+            Some("Sessions") => {
+                self.session = frame["session"].as_u64().map(|id| id as usize);
+                self.pilot = frame["pilot"].as_u64().map(|id| id as usize);
+            }
+            Some("Telemetry") => {
+                self.distance = frame["distance"].as_f64();
+                self.sensor_health = frame["sensor_health"].as_str().map(str::to_string);
+            }
+            Some(variant) => self.push_log(format!("{variant} {frame}")),
+            None => (),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [status_area, drive_area, log_area, help_area] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Length(4),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.size());
+
+        let (status, color) = match &self.status {
+            ConnectionStatus::Connecting => ("connecting".to_string(), Color::Yellow),
+            ConnectionStatus::Connected => ("connected".to_string(), Color::Green),
+            ConnectionStatus::Disconnected(reason) => {
+                (format!("disconnected: {reason}"), Color::Red)
+            }
+        };
+        let control = match (self.session, self.pilot) {
+            (Some(session), Some(pilot)) if session == pilot => "you are the pilot".to_string(),
+            (_, Some(pilot)) => format!("session {pilot} is the pilot, press T to take control"),
+            _ => "nobody is the pilot, press T to take control".to_string(),
+        };
+        let status = Paragraph::new(vec![
+            Line::styled(format!("{} - {status}", self.url), Style::new().fg(color)),
+            Line::raw(control),
+        ])
+        .block(Block::new().borders(Borders::ALL).title("mecanum-teleop"));
+        frame.render_widget(status, status_area);
+
+        let motion = self.held.map_or("stopped", |(motion, _, _)| motion);
+        let distance = match self.distance {
+            Some(distance) => format!("{distance:.2} m"),
+            None => "-".to_string(),
+        };
+        let drive = Paragraph::new(vec![
+            Line::raw(format!("motion: {motion}, speed: {:.1}", self.speed)),
+            Line::raw(format!(
+                "distance: {distance} ({})",
+                self.sensor_health.as_deref().unwrap_or("unknown")
+            )),
+        ])
+        .block(Block::new().borders(Borders::ALL).title("Drive"));
+        frame.render_widget(drive, drive_area);
+
+        let log = List::new(self.log.iter().map(String::as_str))
+            .block(Block::new().borders(Borders::ALL).title("Responses"));
+        frame.render_widget(log, log_area);
+
+        let help = Paragraph::new(
+            "hold QWE/AD/ZXC: move, left/right: rotate, S/space: stop, up/down: speed, T: take control, Esc: quit",
+        );
+        frame.render_widget(help, help_area);
+    }
+}
+
+fn run(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    app: &mut App,
+    events: &Receiver<NetworkEvent>,
+) -> io::Result<()> {
+    loop {
+        while let Ok(event) = events.try_recv() {
+            app.network_handler(event);
+        }
+        terminal.draw(|frame| app.draw(frame))?;
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if !app.key_handler(key) {
+                    return Ok(());
+                }
+            }
+        }
+        app.tick();
+    }
+}
+
+fn main() -> io::Result<()> {
+    let url = env::args().nth(1).unwrap_or(DEFAULT_URL.to_string());
+    // TLS isn't supported, fail before taking over the terminal
+    if !url.starts_with("ws://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported URL {url}, only plain ws:// URLs are supported, not wss://"),
+        ));
+    }
+
+    let (command_sender, command_receiver) = mpsc::channel();
+    let (event_sender, event_receiver) = mpsc::channel();
+    let network_thread = {
+        let url = url.clone();
+        thread::spawn(move || network(url, command_receiver, event_sender))
+    };
+
+    terminal::enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let release_events = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if release_events {
+        execute!(
+            stdout,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        )?;
+    }
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let mut app = App::new(url, command_sender, release_events);
+    let result = run(&mut terminal, &mut app, &event_receiver);
+
+    // Don't leave the robot moving
+    app.stop();
+    drop(app);
+
+    if release_events {
+        execute!(terminal.backend_mut(), PopKeyboardEnhancementFlags)?;
+    }
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+
+    let _ = network_thread.join();
+    result
+}
@@ -0,0 +1,180 @@
+//! Compact binary WebSocket frames for joystick driving, an alternative to JSON for high-rate
+//! velocity commands and telemetry. All numbers are little-endian, fractions in the range
+//! [-1, 1] are encoded as `i16` scaled by 32767.
+//!
+//! Client to robot velocity command (12 bytes):
+//!
+//! | offset | type  | field                                     |
+//! |--------|-------|-------------------------------------------|
+//! | 0      | `u8`  | frame type, `0x01`                        |
+//! | 1      | `u8`  | protocol version                          |
+//! | 2      | `u32` | sequence number                           |
+//! | 6      | `i16` | `x` - forward (+) / backward (-)          |
+//! | 8      | `i16` | `y` - right (+) / left (-)                |
+//! | 10     | `i16` | `rotation` - clockwise (+) / counterclockwise (-) |
+//!
+//! Robot to client telemetry (29 bytes):
+//!
+//! | offset | type     | field                                            |
+//! |--------|----------|--------------------------------------------------|
+//! | 0      | `u8`     | frame type, `0x81`                               |
+//! | 1      | `u8`     | protocol version                                 |
+//! | 2      | `u32`    | sequence number                                  |
+//! | 6      | `f32`    | uptime in seconds                                |
+//! | 10     | `i16`×4  | wheel duty cycles                                |
+//! | 18     | `u8`     | `Motion` index, `0xFF` when driven by velocity   |
+//! | 19     | `u8`     | bitmask of active `Behaviour` indices            |
+//! | 20     | `f32`    | last valid distance in meters, NaN if unknown    |
+//! | 24     | `u8`     | `SensorHealth` index                             |
+//! | 25     | `f32`    | CPU temperature in °C, NaN if unknown            |
+
+use crate::{
+    protocol::{ErrorCode, SocketError, PROTOCOL_VERSION},
+    telemetry::TelemetrySnapshot,
+};
+
+pub const VELOCITY_FRAME: u8 = 0x01;
+pub const TELEMETRY_FRAME: u8 = 0x81;
+
+const VELOCITY_FRAME_LENGTH: usize = 12;
+
+const FRACTION_SCALE: f64 = i16::MAX as f64;
+
+/// Velocity command, see `DriveMessage::Velocity`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VelocityFrame {
+    pub sequence: u32,
+    pub x: f64,
+    pub y: f64,
+    pub rotation: f64,
+}
+
+impl VelocityFrame {
+    pub fn parse(bytes: &[u8]) -> Result<Self, SocketError> {
+        if bytes.len() != VELOCITY_FRAME_LENGTH {
+            return Err(SocketError::new(
+                ErrorCode::InvalidMessage,
+                format!(
+                    "binary frame has {} bytes, expected {VELOCITY_FRAME_LENGTH}",
+                    bytes.len()
+                ),
+            ));
+        }
+        if bytes[0] != VELOCITY_FRAME {
+            return Err(SocketError::new(
+                ErrorCode::InvalidMessage,
+                format!("unsupported binary frame type {:#04x}", bytes[0]),
+            ));
+        }
+        if bytes[1] as u64 != PROTOCOL_VERSION {
+            return Err(SocketError::new(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "unsupported protocol version {}, expected {PROTOCOL_VERSION}",
+                    bytes[1]
+                ),
+            ));
+        }
+        let fraction = |offset: usize| {
+            let value = i16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+            (value as f64 / FRACTION_SCALE).max(-1.)
+        };
+        Ok(Self {
+            sequence: u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+            x: fraction(6),
+            y: fraction(8),
+            rotation: fraction(10),
+        })
+    }
+
+    pub fn is_stop(&self) -> bool {
+        self.x == 0. && self.y == 0. && self.rotation == 0.
+    }
+}
+
+/// Whether `sequence` comes after `last`, allowing the sequence numbers to wrap around
+pub fn is_newer(sequence: u32, last: u32) -> bool {
+    (sequence.wrapping_sub(last) as i32) > 0
+}
+
+pub fn encode_telemetry(sequence: u32, snapshot: &TelemetrySnapshot) -> Vec<u8> {
+    let mut bytes = vec![TELEMETRY_FRAME, PROTOCOL_VERSION as u8];
+    bytes.extend(sequence.to_le_bytes());
+    bytes.extend((snapshot.uptime as f32).to_le_bytes());
+    for duty_cycle in snapshot.duty_cycles {
+        bytes.extend(((duty_cycle * FRACTION_SCALE).round() as i16).to_le_bytes());
+    }
+    bytes.push(snapshot.motion.map_or(0xFF, |motion| motion as u8));
+    bytes.push(
+        snapshot
+            .behaviours
+            .iter()
+            .fold(0, |mask, behaviour| mask | 1 << *behaviour as u8),
+    );
+    bytes.extend(snapshot.distance.unwrap_or(f32::NAN).to_le_bytes());
+    bytes.push(snapshot.sensor_health as u8);
+    bytes.extend(snapshot.cpu_temperature.unwrap_or(f32::NAN).to_le_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn velocity_frame(sequence: u32, x: i16, y: i16, rotation: i16) -> Vec<u8> {
+        let mut bytes = vec![VELOCITY_FRAME, PROTOCOL_VERSION as u8];
+        bytes.extend(sequence.to_le_bytes());
+        bytes.extend(x.to_le_bytes());
+        bytes.extend(y.to_le_bytes());
+        bytes.extend(rotation.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn parse_decodes_velocity_frames() {
+        let frame = VelocityFrame::parse(&velocity_frame(42, i16::MAX, 0, i16::MIN)).unwrap();
+        assert_eq!(
+            frame,
+            VelocityFrame {
+                sequence: 42,
+                x: 1.,
+                y: 0.,
+                // -32768 would be slightly below -1
+                rotation: -1.,
+            }
+        );
+        assert!(!frame.is_stop());
+        assert!(VelocityFrame::parse(&velocity_frame(43, 0, 0, 0))
+            .unwrap()
+            .is_stop());
+    }
+
+    #[test]
+    fn parse_rejects_malformed_frames() {
+        let code = |bytes: &[u8]| VelocityFrame::parse(bytes).unwrap_err().code;
+        let frame = velocity_frame(1, 0, 0, 0);
+        assert_eq!(code(&frame[..11]), ErrorCode::InvalidMessage);
+        assert_eq!(
+            code(&[frame.as_slice(), &[0]].concat()),
+            ErrorCode::InvalidMessage
+        );
+
+        let mut wrong_type = frame.clone();
+        wrong_type[0] = TELEMETRY_FRAME;
+        assert_eq!(code(&wrong_type), ErrorCode::InvalidMessage);
+
+        let mut wrong_version = frame;
+        wrong_version[1] = PROTOCOL_VERSION as u8 + 1;
+        assert_eq!(code(&wrong_version), ErrorCode::UnsupportedVersion);
+    }
+
+    #[test]
+    fn is_newer_allows_wraparound() {
+        assert!(is_newer(2, 1));
+        assert!(!is_newer(1, 1));
+        assert!(!is_newer(1, 2));
+        assert!(is_newer(0, u32::MAX));
+        assert!(is_newer(5, u32::MAX - 5));
+        assert!(!is_newer(u32::MAX, 0));
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    drive::{Drive, DriveMessage, Motion, MotionFinished, Speed, WatchMotion},
+    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response},
+    sessions::Sessions,
+};
+
+/// How often the distance is measured while the robot moves toward the sensor
+const MEASUREMENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A measurement which wasn't answered within this is requested again
+const MEASUREMENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The range is the median of this many latest readings, so that single bad echoes are ignored
+const FILTER_READINGS: usize = 3;
+
+/// Speeds are scaled in steps, so that the command isn't sent again on every slightly different
+/// reading
+const SCALE_STEPS: f64 = 10.;
+
+/// Older readings aren't used, without recent ones the distance is unknown
+const READING_MAX_AGE: Duration = Duration::from_millis(500);
+
+/// Motion toward the sensor is scaled by this while the distance is unknown, e.g. before the first
+/// reading arrives or while the sensor is failing
+const UNKNOWN_DISTANCE_FACTOR: f64 = 0.5;
+
+/// Slowed speeds don't go below the duty cycle of `Speed::Low`, as the motors stall below it and
+/// timed moves can't be estimated
+const MIN_DUTY_CYCLE: f64 = 0.3;
+
+/// Distances (in meters) from obstacles in front of the sensor at which the guard intervenes
+#[derive(Clone, Copy, Debug)]
+pub struct GuardConfig {
+    /// Motion toward the sensor is scaled down linearly below this
+    pub slow_distance: f32,
+    /// Motion toward the sensor is blocked below this
+    pub stop_distance: f32,
+}
+
+impl GuardConfig {
+    pub fn parse(slow_distance: &str, stop_distance: &str) -> Result<Self, String> {
+        let parse = |distance: &str| {
+            distance
+                .parse::<f32>()
+                .map_err(|e| format!("invalid distance {distance}: {e}"))
+        };
+        let (slow_distance, stop_distance) = (parse(slow_distance)?, parse(stop_distance)?);
+        if !(0. < stop_distance && stop_distance < slow_distance) {
+            return Err(format!(
+                "stop distance {stop_distance} must be positive and below slow distance \
+                 {slow_distance}"
+            ));
+        }
+        Ok(Self {
+            slow_distance,
+            stop_distance,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Intervention {
+    /// Motion toward the sensor is scaled down
+    Slowed,
+    /// Motion toward the sensor is stopped, backing away and strafing still work
+    Blocked,
+}
+
+/// Whether `message` moves the robot toward the sensor, which faces forward
+fn moves_toward_sensor(message: &DriveMessage) -> bool {
+    let forward = |motion| {
+        matches!(
+            motion,
+            Motion::Forward | Motion::ForwardLeft | Motion::ForwardRight
+        )
+    };
+    match *message {
+        DriveMessage::Enable { motion, .. } | DriveMessage::Move { motion, .. } => forward(motion),
+        DriveMessage::Velocity { x, .. } => x > 0.,
+        _ => false,
+    }
+}
+
+/// Scales down or blocks the part of `message` moving toward the sensor, according to the
+/// `distance` of the nearest obstacle, `None` if it's unknown
+fn limit(
+    config: GuardConfig,
+    distance: Option<f32>,
+    message: DriveMessage,
+) -> (DriveMessage, Option<Intervention>) {
+    if !moves_toward_sensor(&message) {
+        return (message, None);
+    }
+    let factor = match distance {
+        Some(distance) => {
+            let GuardConfig {
+                slow_distance,
+                stop_distance,
+            } = config;
+            let factor = ((distance - stop_distance) / (slow_distance - stop_distance)) as f64;
+            (factor.clamp(0., 1.) * SCALE_STEPS).floor() / SCALE_STEPS
+        }
+        None => UNKNOWN_DISTANCE_FACTOR,
+    };
+    if factor >= 1. {
+        return (message, None);
+    }
+    let slowed = |speed: Speed| {
+        let duty_cycle = speed.get_duty_cycle();
+        Speed::Manual((duty_cycle * factor).max(MIN_DUTY_CYCLE.min(duty_cycle)))
+    };
+    let blocked = factor <= 0.;
+    let limited = match message {
+        DriveMessage::Enable { speed, .. } if blocked => DriveMessage::Enable {
+            motion: Motion::Stop,
+            speed,
+        },
+        DriveMessage::Enable { motion, speed } => DriveMessage::Enable {
+            motion,
+            speed: slowed(speed),
+        },
+        // Strafing and rotating are kept
+        DriveMessage::Velocity { x, y, rotation } => DriveMessage::Velocity {
+            x: x * factor,
+            y,
+            rotation,
+        },
+        DriveMessage::Move { .. } if blocked => DriveMessage::Disable,
+        DriveMessage::Move {
+            motion,
+            speed,
+            distance,
+        } => DriveMessage::Move {
+            motion,
+            speed: slowed(speed),
+            distance,
+        },
+        message => message,
+    };
+    let intervention = if blocked {
+        Intervention::Blocked
+    } else {
+        Intervention::Slowed
+    };
+    (limited, Some(intervention))
+}
+
+/// Optional safety layer in front of `Drive`: limits drive commands moving the robot toward an
+/// obstacle in front of the distance sensor. Commands are limited when they arrive and again on
+/// every new reading while they're active
+pub struct CollisionGuard {
+    config: GuardConfig,
+    drive_addr: Addr<Drive>,
+    hc_sr04_addr: Addr<HcSr04>,
+    sessions_addr: Addr<Sessions>,
+    /// Latest readings with the time they arrived, newest last
+    readings: VecDeque<(Instant, f32)>,
+    /// When the pending measurement was requested
+    measurement_requested: Option<Instant>,
+    /// Latest command sent by a command source
+    requested: Option<DriveMessage>,
+    /// `requested` as it was sent to the drive
+    sent: Option<DriveMessage>,
+    /// Number of commands received, identifies the timed move a `MotionFinished` belongs to
+    commands: usize,
+    intervention: Option<Intervention>,
+}
+
+impl CollisionGuard {
+    pub fn new(
+        config: GuardConfig,
+        drive_addr: Addr<Drive>,
+        hc_sr04_addr: Addr<HcSr04>,
+        sessions_addr: Addr<Sessions>,
+    ) -> Self {
+        Self {
+            config,
+            drive_addr,
+            hc_sr04_addr,
+            sessions_addr,
+            readings: VecDeque::with_capacity(FILTER_READINGS),
+            measurement_requested: None,
+            requested: None,
+            sent: None,
+            commands: 0,
+            intervention: None,
+        }
+    }
+
+    /// Median of the recent readings, `None` if there are none
+    fn distance(&self) -> Option<f32> {
+        let mut distances: Vec<f32> = self
+            .readings
+            .iter()
+            .filter(|(time, _)| time.elapsed() < READING_MAX_AGE)
+            .map(|(_, distance)| *distance)
+            .collect();
+        distances.sort_by(f32::total_cmp);
+        distances.get(distances.len() / 2).copied()
+    }
+
+    /// Limits `message` according to the current distance
+    fn limit(&self, message: DriveMessage) -> (DriveMessage, Option<Intervention>) {
+        limit(self.config, self.distance(), message)
+    }
+
+    /// Reports when the guard starts or stops intervening, or the intervention changes
+    fn report(&mut self, intervention: Option<Intervention>) {
+        if intervention == self.intervention {
+            return;
+        }
+        self.intervention = intervention;
+        let distance = self.distance();
+        info!("collision guard intervention {intervention:?} at {distance:?} m");
+        self.sessions_addr.do_send(GuardIntervened {
+            intervention,
+            distance,
+        });
+    }
+
+    /// Requests a measurement while the requested command moves toward the sensor
+    fn measure(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if !self.requested.as_ref().is_some_and(moves_toward_sensor) {
+            return;
+        }
+        if self
+            .measurement_requested
+            .is_some_and(|time| time.elapsed() < MEASUREMENT_TIMEOUT)
+        {
+            return;
+        }
+        self.measurement_requested = Some(Instant::now());
+        self.hc_sr04_addr
+            .do_send(HcSr04Message::Single(ctx.address().recipient()));
+    }
+
+    /// Limits the requested command again after the distance changed
+    fn update(&mut self) {
+        let Some(requested) = self.requested else {
+            return;
+        };
+        let (limited, intervention) = self.limit(requested);
+        self.report(intervention);
+        if Some(limited) == self.sent {
+            return;
+        }
+        // Sending a timed move again would restart it, it's only stopped
+        if matches!(requested, DriveMessage::Move { .. }) && limited != DriveMessage::Disable {
+            return;
+        }
+        info!("sending {limited:?} to drive");
+        self.sent = Some(limited);
+        self.drive_addr.do_send(limited);
+    }
+}
+
+impl Actor for CollisionGuard {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!(
+            "actor started, slowing below {} m, stopping below {} m",
+            self.config.slow_distance, self.config.stop_distance
+        );
+        ctx.run_interval(MEASUREMENT_INTERVAL, |act, ctx| act.measure(ctx));
+    }
+}
+
+impl Handler<DriveMessage> for CollisionGuard {
+    type Result = ResponseFuture<Result<[f64; 4], String>>;
+
+    fn handle(&mut self, msg: DriveMessage, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        let (limited, intervention) = self.limit(msg);
+        self.report(intervention);
+        self.requested = Some(msg);
+        self.sent = Some(limited);
+        self.commands += 1;
+        // Without a recent reading the command is slowed until the first one arrives
+        self.measure(ctx);
+        let request = self.drive_addr.send(limited);
+        // Timed moves end on their own, after which there's nothing left to guard
+        if matches!(msg, DriveMessage::Move { .. }) {
+            self.drive_addr.do_send(WatchMotion {
+                id: self.commands,
+                recipient: ctx.address().recipient(),
+            });
+        }
+        Box::pin(async move { request.await.map_err(|e| e.to_string())? })
+    }
+}
+
+impl Handler<HcSr04Response> for CollisionGuard {
+    type Result = ();
+
+    fn handle(&mut self, msg: HcSr04Response, _ctx: &mut Self::Context) -> Self::Result {
+        self.measurement_requested = None;
+        // Out of range readings are infinite, failed ones are skipped
+        if let HcSr04Response::Ok(HcSr04Measurement::Single(result)) = msg {
+            if self.readings.len() == FILTER_READINGS {
+                self.readings.pop_front();
+            }
+            self.readings.push_back((Instant::now(), result.distance));
+        }
+        self.update();
+    }
+}
+
+impl Handler<MotionFinished> for CollisionGuard {
+    type Result = ();
+
+    fn handle(&mut self, msg: MotionFinished, _ctx: &mut Self::Context) -> Self::Result {
+        // A newer command replaced the move in the meantime
+        if msg.id != self.commands {
+            return;
+        }
+        info!("timed move finished");
+        self.requested = None;
+        self.sent = None;
+        self.report(None);
+    }
+}
+
+/// Sent to `Sessions` when the guard starts or stops intervening, broadcast to all clients
+#[derive(Clone, Copy, Debug, Message)]
+#[rtype(result = "()")]
+pub struct GuardIntervened {
+    /// `None` when the guard stopped intervening
+    pub intervention: Option<Intervention>,
+    /// Filtered range (in meters)
+    pub distance: Option<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: GuardConfig = GuardConfig {
+        slow_distance: 0.5,
+        stop_distance: 0.2,
+    };
+
+    const FORWARD: DriveMessage = DriveMessage::Enable {
+        motion: Motion::Forward,
+        speed: Speed::High,
+    };
+
+    #[test]
+    fn parse_checks_distances() {
+        let config = GuardConfig::parse("0.5", "0.2").unwrap();
+        assert_eq!((config.slow_distance, config.stop_distance), (0.5, 0.2));
+        assert!(GuardConfig::parse("half", "0.2").is_err());
+        assert!(GuardConfig::parse("0.2", "0.5").is_err());
+        assert!(GuardConfig::parse("0.5", "0.5").is_err());
+        assert!(GuardConfig::parse("0.5", "0").is_err());
+        assert!(GuardConfig::parse("NaN", "0.2").is_err());
+    }
+
+    #[test]
+    fn limit_passes_commands_with_free_space() {
+        assert_eq!(limit(CONFIG, Some(0.6), FORWARD), (FORWARD, None));
+        assert_eq!(limit(CONFIG, Some(f32::INFINITY), FORWARD), (FORWARD, None));
+        // Backing away is never limited
+        let backward = DriveMessage::Enable {
+            motion: Motion::Backward,
+            speed: Speed::High,
+        };
+        assert_eq!(limit(CONFIG, Some(0.1), backward), (backward, None));
+        assert_eq!(limit(CONFIG, None, backward), (backward, None));
+    }
+
+    #[test]
+    fn limit_slows_down_near_obstacles() {
+        assert_eq!(
+            limit(CONFIG, Some(0.36), FORWARD),
+            (
+                DriveMessage::Enable {
+                    motion: Motion::Forward,
+                    speed: Speed::Manual(0.5),
+                },
+                Some(Intervention::Slowed)
+            )
+        );
+        let velocity = DriveMessage::Velocity {
+            x: 0.8,
+            y: 0.2,
+            rotation: 0.1,
+        };
+        let (limited, intervention) = limit(CONFIG, Some(0.36), velocity);
+        assert_eq!(intervention, Some(Intervention::Slowed));
+        let DriveMessage::Velocity { x, y, rotation } = limited else {
+            panic!("expected velocity, got {limited:?}");
+        };
+        assert!((x - 0.4).abs() < 1e-9);
+        assert_eq!((y, rotation), (0.2, 0.1));
+    }
+
+    #[test]
+    fn limit_blocks_below_stop_distance() {
+        assert_eq!(
+            limit(CONFIG, Some(0.1), FORWARD),
+            (
+                DriveMessage::Enable {
+                    motion: Motion::Stop,
+                    speed: Speed::High,
+                },
+                Some(Intervention::Blocked)
+            )
+        );
+        let forward_move = DriveMessage::Move {
+            motion: Motion::Forward,
+            speed: Speed::Medium,
+            distance: 1.,
+        };
+        assert_eq!(
+            limit(CONFIG, Some(0.2), forward_move),
+            (DriveMessage::Disable, Some(Intervention::Blocked))
+        );
+        assert_eq!(
+            limit(
+                CONFIG,
+                Some(0.),
+                DriveMessage::Velocity {
+                    x: 1.,
+                    y: -0.5,
+                    rotation: 0.,
+                }
+            ),
+            (
+                DriveMessage::Velocity {
+                    x: 0.,
+                    y: -0.5,
+                    rotation: 0.,
+                },
+                Some(Intervention::Blocked)
+            )
+        );
+    }
+
+    #[test]
+    fn limit_slows_down_without_readings() {
+        assert_eq!(
+            limit(CONFIG, None, FORWARD),
+            (
+                DriveMessage::Enable {
+                    motion: Motion::Forward,
+                    speed: Speed::Manual(UNKNOWN_DISTANCE_FACTOR),
+                },
+                Some(Intervention::Slowed)
+            )
+        );
+    }
+
+    #[test]
+    fn slowed_moves_can_still_be_executed() {
+        for distance in [None, Some(0.23), Some(0.3), Some(0.45)] {
+            for speed in [Speed::Low, Speed::Medium, Speed::High, Speed::Manual(0.28)] {
+                let message = DriveMessage::Move {
+                    motion: Motion::ForwardLeft,
+                    speed,
+                    distance: 0.5,
+                };
+                let (limited, intervention) = limit(CONFIG, distance, message);
+                assert_eq!(intervention, Some(Intervention::Slowed));
+                assert_eq!(limited.validate(), Ok(()), "{limited:?} at {distance:?} m");
+            }
+        }
+    }
+}
@@ -6,78 +6,162 @@ use std::{
 
 use actix::{fut::wrap_future, prelude::*};
 use actix_web::{rt::time, web::Data};
-use serde::Deserialize;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    drive::{Drive, DriveMessage, Motion, Speed, ROBOT_RADIUS},
-    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response, Recipient},
+    drive::{DriveMessage, Motion, Speed, ROBOT_RADIUS},
+    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response},
+    mode::{Command, Mode, ModeManager, Preempted, Release},
+    protocol::{ErrorCode, SocketError},
+    recorder::{self, Record},
+    telemetry::{Behaviour, BehaviourMessage, Telemetry},
 };
 
+/// Highest number of measurements taken during a scan
+const MAX_RESOLUTION: usize = 360;
+
+/// Checks the parameters of a scan: the speed has to rotate the robot, the slip is within `0..=1`
+/// and the resolution within `1..=MAX_RESOLUTION`
+pub fn validate_params(speed: Speed, slip: f64, resolution: usize) -> Result<(), SocketError> {
+    speed
+        .validate_moving()
+        .map_err(|e| SocketError::new(ErrorCode::InvalidMessage, e))?;
+    if !(0. ..=1.).contains(&slip) {
+        return Err(SocketError::new(
+            ErrorCode::InvalidMessage,
+            format!("slip {slip} is not within 0..=1"),
+        ));
+    }
+    if !(1..=MAX_RESOLUTION).contains(&resolution) {
+        return Err(SocketError::new(
+            ErrorCode::InvalidMessage,
+            format!("resolution {resolution} is not within 1..={MAX_RESOLUTION}"),
+        ));
+    }
+    Ok(())
+}
+
 pub struct Scanner {
-    drive_data: Data<Mutex<Addr<Drive>>>,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
     hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    telemetry_addr: Addr<Telemetry>,
+    /// Receives the `ScanResult` of every finished scan
+    recipient: Recipient<ScanResult>,
+    /// Mode the drive commands are sent on behalf of, `Autonomous` when scanning for the autopilot
+    mode: Mode,
+    /// Behaviour holding `mode`, the scanner itself unless it scans for another behaviour
+    owner: Option<Recipient<Preempted>>,
     speed: Speed,
     slip: f64,
     resolution: usize,
     start_time: Option<Duration>,
     time_between_measurements: Duration,
+    responses: usize,
     measurements: Vec<(f32, f32)>,
+    scanning: bool,
 }
 
 impl Scanner {
     pub fn new(
-        drive_data: Data<Mutex<Addr<Drive>>>,
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
         hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        telemetry_addr: Addr<Telemetry>,
+        recipient: Recipient<ScanResult>,
         speed: Speed,
         slip: f64,
         resolution: usize,
     ) -> Self {
         Self {
-            drive_data,
+            mode_manager_data,
             hc_sr04_data,
+            telemetry_addr,
+            recipient,
+            mode: Mode::Scanning,
+            owner: None,
             speed,
             slip,
             resolution,
             start_time: None,
-            time_between_measurements: {
-                let distance = 2. * PI * ROBOT_RADIUS * (1. + slip);
-                let time_s = distance / (speed.get_velocity() * resolution as f64);
-                Duration::from_secs_f64(time_s)
-            },
+            time_between_measurements: Self::get_time_between_measurements(speed, slip, resolution),
+            responses: 0,
             measurements: Vec::<(f32, f32)>::new(),
+            scanning: false,
         }
     }
 
+    /// Sends drive commands on behalf of the behaviour `owner` holding `mode`, instead of its own
+    /// `Mode::Scanning`
+    pub fn with_owner(mut self, mode: Mode, owner: Recipient<Preempted>) -> Self {
+        self.mode = mode;
+        self.owner = Some(owner);
+        self
+    }
+
+    /// Reports the start and end of scanning to telemetry
+    fn set_scanning(&mut self, scanning: bool) {
+        if self.scanning == scanning {
+            return;
+        }
+        self.scanning = scanning;
+        self.telemetry_addr.do_send(if scanning {
+            BehaviourMessage::Started(Behaviour::Scanning)
+        } else {
+            BehaviourMessage::Stopped(Behaviour::Scanning)
+        });
+    }
+
+    /// Computes the time between measurements needed to spread `resolution` of them over a full rotation
+    fn get_time_between_measurements(speed: Speed, slip: f64, resolution: usize) -> Duration {
+        let distance = 2. * PI * ROBOT_RADIUS * (1. + slip);
+        let time_s = distance / (speed.get_velocity() * resolution as f64);
+        Duration::from_secs_f64(time_s)
+    }
+
     fn scan(&mut self, ctx: &mut <Self as Actor>::Context) {
+        // Reset results of a potential previous scan
+        self.responses = 0;
+        self.measurements.clear();
+        self.set_scanning(true);
+
         // Clone necessary data
         let speed = self.speed;
         let resolution = self.resolution;
         let addr = ctx.address();
-        let drive_addr = self.drive_data.lock().unwrap().to_owned();
+        let mode = self.mode;
+        let owner = self
+            .owner
+            .clone()
+            .unwrap_or_else(|| addr.clone().recipient());
+        let mode_manager_addr = self.mode_manager_data.lock().unwrap().to_owned();
         let hc_sr04_addr = self.hc_sr04_data.lock().unwrap().to_owned();
         let time = self.time_between_measurements;
 
         // Define task
         let fut = async move {
-            println!("Started scanning");
-            drive_addr.do_send(DriveMessage::Enable {
-                motion: Motion::RightRot,
-                speed,
-            });
+            info!("started scanning");
+            mode_manager_addr.do_send(Command::behaviour(
+                mode,
+                owner.clone(),
+                DriveMessage::Enable {
+                    motion: Motion::RightRot,
+                    speed,
+                },
+            ));
 
             for i in 0..resolution {
-                println!("{i}");
-                hc_sr04_addr
-                    .try_send(HcSr04Message::Single(Recipient::Scanner(addr.clone())))
-                    .unwrap_or_else(|e| println!("{:?}", e));
+                debug!("measurement {i}");
+                hc_sr04_addr.do_send(HcSr04Message::Single(addr.clone().recipient()));
                 time::sleep(time).await;
             }
 
-            drive_addr
-                .send(DriveMessage::Disable)
+            let stop = Command::behaviour(mode, owner, DriveMessage::Disable);
+            mode_manager_addr
+                .send(stop)
                 .await
-                .unwrap_or_else(|e| println!("{:?}", e));
-            println!("Finished scanning");
+                .map(|_| ())
+                .unwrap_or_else(|e| error!("{e:?}"));
+            info!("finished scanning");
         };
 
         // Run task
@@ -86,8 +170,31 @@ impl Scanner {
                 .duration_since(UNIX_EPOCH)
                 .expect("Time went backwards"),
         );
-        ctx.wait(wrap_future(fut));
-        println!("{:?}", self.measurements);
+        // The robot has stopped once the task finished
+        ctx.spawn(wrap_future(fut).map(|(), act: &mut Self, ctx| act.release(ctx)));
+    }
+
+    /// Sends the measurements to the recipient once all of them have been received
+    fn finish_if_complete(&mut self) {
+        if self.responses < self.resolution {
+            return;
+        }
+        self.set_scanning(false);
+        let result = ScanResult {
+            measurements: std::mem::take(&mut self.measurements),
+        };
+        recorder::record(Record::Scan(result.clone()));
+        info!("sending {result:?} to {:?}", self.recipient);
+        self.recipient.do_send(result);
+    }
+
+    /// Gives up the `Scanning` mode and stops the robot, unless the scan was performed for a
+    /// behaviour holding the mode
+    fn release(&self, ctx: &mut <Self as Actor>::Context) {
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Release(ctx.address().recipient()));
     }
 }
 
@@ -95,16 +202,17 @@ impl Actor for Scanner {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Self::Context) {
-        println!("Scanner actor started");
+        info!("actor started");
     }
 
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
-        let drive_addr = self.drive_data.lock().unwrap();
-        drive_addr.do_send(DriveMessage::Disable);
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.set_scanning(false);
+        // Stops the robot
+        self.release(ctx);
     }
 }
 
-#[derive(Message, Debug, Deserialize)]
+#[derive(Message, Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "variant")]
 #[rtype(result = "()")]
 pub enum ScannerMessage {
@@ -116,6 +224,21 @@ pub enum ScannerMessage {
     Stop,
 }
 
+impl ScannerMessage {
+    /// Checks the parameters of `Start`, so that an invalid scan is rejected before it acquires its
+    /// mode
+    pub fn validate(&self) -> Result<(), SocketError> {
+        match *self {
+            ScannerMessage::Start {
+                speed,
+                slip,
+                resolution,
+            } => validate_params(speed, slip, resolution),
+            ScannerMessage::Stop => Ok(()),
+        }
+    }
+}
+
 impl Handler<ScannerMessage> for Scanner {
     type Result = ();
 
@@ -129,6 +252,8 @@ impl Handler<ScannerMessage> for Scanner {
                 self.speed = speed;
                 self.slip = slip;
                 self.resolution = resolution;
+                self.time_between_measurements =
+                    Self::get_time_between_measurements(speed, slip, resolution);
                 self.scan(ctx);
             }
             ScannerMessage::Stop => ctx.stop(),
@@ -136,23 +261,44 @@ impl Handler<ScannerMessage> for Scanner {
     }
 }
 
+impl Handler<Preempted> for Scanner {
+    type Result = ();
+
+    fn handle(&mut self, msg: Preempted, ctx: &mut Self::Context) -> Self::Result {
+        info!("scan preempted by {:?} mode", msg.0);
+        ctx.stop();
+    }
+}
+
 impl Handler<HcSr04Response> for Scanner {
     type Result = ();
 
     fn handle(&mut self, msg: HcSr04Response, _ctx: &mut Self::Context) -> Self::Result {
+        self.responses += 1;
         match msg {
             HcSr04Response::Ok(measurement) => match measurement {
                 HcSr04Measurement::Single(result) => {
                     let time = result.time.as_millis() - self.start_time.unwrap().as_millis();
                     let distance = result.distance;
-                    let angle = 360. * time as f32
+                    // Late responses can belong to more than a full rotation
+                    let angle = (360. * time as f32
                         / (self.resolution as u128 * self.time_between_measurements.as_millis())
-                            as f32;
+                            as f32)
+                        .rem_euclid(360.);
                     self.measurements.push((angle, distance));
                 }
                 HcSr04Measurement::Multiple(_) => (),
             },
-            HcSr04Response::Err(e) => println!("{:?}", e),
+            HcSr04Response::Err(e) => error!("{e:?}"),
         };
+        self.finish_if_complete();
     }
 }
+
+/// Distances measured during a full rotation, as `(angle, distance)` pairs, with angles in degrees
+/// measured clockwise from the starting heading
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+pub struct ScanResult {
+    pub measurements: Vec<(f32, f32)>,
+}
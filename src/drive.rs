@@ -1,25 +1,68 @@
-use std::{f64::consts::PI, fmt::Display, time::Duration};
+use std::{
+    f64::consts::PI,
+    fmt::Display,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
 
-use actix_web::rt::time;
-use log::{info, debug};
+use log::{debug, error, info, warn};
 use rppal::gpio::{Error, Gpio};
 
 use serde::{Deserialize, Serialize};
 
-use actix::{fut::wrap_future, prelude::*};
+use actix::prelude::*;
 
 use crate::{
-    server::{AddrMessage, WebSocket},
-    Device,
+    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response},
+    metrics::{MailboxProbe, METRICS},
+    recorder::{self, Record},
+    sessions::Sessions,
+    shutdown::Shutdown,
+    supervision::{catch_panic, Device, DeviceRestarted, Restarts},
 };
 
 mod motor;
+mod odometry;
+
+pub use odometry::{Pose, Twist};
 
 pub const WHEEL_CIRCUMFERENCE: f64 = 0.25; // in meters
 pub const ROBOT_RADIUS: f64 = 0.11; // in meters
 
+/// Velocity model fitted to the movement calibration:
+/// `velocity = ln(VELOCITY_SCALE * duty_cycle + VELOCITY_OFFSET) ^ VELOCITY_EXPONENT`
+pub const VELOCITY_SCALE: f64 = 0.3474;
+pub const VELOCITY_OFFSET: f64 = 0.9077;
+pub const VELOCITY_EXPONENT: f64 = 0.25;
+
+/// Maximum difference between the measured and target distance for an approach to be completed
+const APPROACH_TOLERANCE: f32 = 0.02; // in meters
+
+/// Distance from the target at which an approach starts slowing down
+const APPROACH_SLOWDOWN_DISTANCE: f32 = 0.3; // in meters
+
+/// Lowest duty cycle used during an approach, below it the motors stall
+const APPROACH_MIN_DUTY_CYCLE: f64 = 0.3;
+
+/// How long to wait for a distance reading during an approach before giving up
+const APPROACH_READING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of consecutive failed distance readings after which an approach is aborted
+const APPROACH_MAX_FAILED_READINGS: usize = 5;
+
+/// Time an approach may take on top of the travel time to the target from the first reading, it
+/// fails afterwards instead of oscillating around the target forever
+const APPROACH_TIME_MARGIN: Duration = Duration::from_secs(5);
+
+/// Longest timed move or rotation, longer ones are rejected
+pub const MAX_MOVE_TIME: Duration = Duration::from_secs(60);
+
 /// Provides simple API for speed control
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 pub enum Speed {
     Low,
     Medium,
@@ -38,10 +81,38 @@ impl Speed {
         }
     }
 
-    /// Converts `Speed` values to approximate robot velocity
+    /// Checks that the duty cycle is within `0..=1`
+    pub fn validate(&self) -> Result<(), InvalidCommand> {
+        let duty_cycle = self.get_duty_cycle();
+        if !(0. ..=1.).contains(&duty_cycle) {
+            return Err(InvalidCommand(format!(
+                "duty cycle {duty_cycle} is not within 0..=1"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that the speed is valid and moves the robot. The velocity model doesn't hold below a
+    /// duty cycle of about 0.27, where `get_velocity` returns NaN
+    pub fn validate_moving(&self) -> Result<(), InvalidCommand> {
+        self.validate()?;
+        let velocity = self.get_velocity();
+        if !(velocity.is_finite() && velocity > 0.) {
+            return Err(InvalidCommand(format!(
+                "{self:?} speed is too low to move the robot"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Converts `Speed` values to approximate robot velocity, NaN for duty cycles too low to move
+    /// the robot
     pub fn get_velocity(&self) -> f64 {
         let duty_cycle = self.get_duty_cycle();
-        f64::powf(f64::ln(0.3474 * duty_cycle + 0.9077), 0.25)
+        f64::powf(
+            f64::ln(VELOCITY_SCALE * duty_cycle + VELOCITY_OFFSET),
+            VELOCITY_EXPONENT,
+        )
     }
 
     /// Converts `Speed` values to approximate wheel rpm
@@ -50,6 +121,50 @@ impl Speed {
         let velocity = self.get_velocity();
         60. * velocity / WHEEL_CIRCUMFERENCE
     }
+
+    /// Approximate time it takes to travel `distance` (in meters) with this speed, fails if the
+    /// speed doesn't move the robot or it would take longer than `MAX_MOVE_TIME`
+    pub fn get_travel_time(&self, distance: f64) -> Result<Duration, InvalidCommand> {
+        if !(distance.is_finite() && distance >= 0.) {
+            return Err(InvalidCommand(format!("invalid distance {distance}")));
+        }
+        self.validate_moving()?;
+        Duration::try_from_secs_f64(distance / self.get_velocity())
+            .ok()
+            .filter(|time| *time <= MAX_MOVE_TIME)
+            .ok_or_else(|| {
+                InvalidCommand(format!(
+                    "moving {distance} m with {self:?} speed takes longer than {MAX_MOVE_TIME:?}"
+                ))
+            })
+    }
+
+    /// Approximate time it takes to rotate `angle` (in degrees) with this speed, fails like
+    /// `get_travel_time`
+    pub fn get_rotation_time(&self, angle: f64) -> Result<Duration, InvalidCommand> {
+        if !(angle.is_finite() && angle >= 0.) {
+            return Err(InvalidCommand(format!("invalid angle {angle}")));
+        }
+        self.get_travel_time(2. * PI * ROBOT_RADIUS * angle / 360.)
+    }
+}
+
+/// Drive command which can't be executed, e.g. a `Move` with a rotation motion
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidCommand(pub String);
+
+impl Display for InvalidCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCommand {}
+
+impl From<InvalidCommand> for Error {
+    fn from(e: InvalidCommand) -> Self {
+        Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
 }
 
 /// Supported robot motions
@@ -68,6 +183,12 @@ pub enum Motion {
     Stop,
 }
 
+impl Motion {
+    fn is_rotation(self) -> bool {
+        matches!(self, Motion::RightRot | Motion::LeftRot)
+    }
+}
+
 impl Display for Motion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let alias = match self {
@@ -98,9 +219,77 @@ impl Display for Motion {
 /// /-0--1-\\<br>
 /// /------\\<br>
 pub struct Drive {
-    motors: [motor::Motor; 4],
+    gpio: Gpio,
+    motor_pins: [(u8, u8, u8); 4],
+    /// Owns the motors, so that the emergency stop can stop them without waiting for the actor
+    brake: Brake,
     pwm_frequency: f64,
-    websocket_addr: Option<Addr<WebSocket>>,
+    sessions_addr: Addr<Sessions>,
+    hc_sr04_addr: Addr<HcSr04>,
+    approach: Option<Approach>,
+    /// Stops the robot once the time of the latest `DriveMessage::Move` or `DriveMessage::Rotate`
+    /// has passed
+    timed_move: Option<SpawnHandle>,
+    /// Notified once the ongoing timed move or approach ends
+    watchers: Vec<WatchMotion>,
+    /// Motion the wheels were last set to, `None` when driven with `DriveMessage::Velocity`
+    motion: Option<Motion>,
+    /// When the wheels were set to `motion`
+    motion_start: Instant,
+    duty_cycles: [f64; 4],
+    odometry: odometry::Odometry,
+    /// Set on `Shutdown`, commands are rejected afterwards
+    shut_down: bool,
+    restarts: Restarts,
+}
+
+/// Stops the motors from any thread, without going through the mailbox of `Drive`. While it's
+/// engaged, `Drive` refuses to start the motors
+#[derive(Clone)]
+pub struct Brake {
+    /// `None` while waiting to be re-initialized after a restart
+    motors: Arc<Mutex<Option<[motor::Motor; 4]>>>,
+    engaged: Arc<AtomicBool>,
+}
+
+impl Brake {
+    fn new(motors: [motor::Motor; 4]) -> Self {
+        Self {
+            motors: Arc::new(Mutex::new(Some(motors))),
+            engaged: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A panicking command doesn't leave the motors in a state which can't be stopped
+    fn motors(&self) -> MutexGuard<'_, Option<[motor::Motor; 4]>> {
+        self.motors.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stops all motors and keeps them stopped until released
+    pub fn engage(&self) {
+        self.engaged.store(true, Ordering::SeqCst);
+        for motor in self.motors().iter_mut().flatten() {
+            motor.stop();
+        }
+    }
+
+    pub fn release(&self) {
+        self.engaged.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::SeqCst)
+    }
+}
+
+/// State of an ongoing `DriveMessage::ApproachDistance`
+struct Approach {
+    target: f32,
+    speed: Speed,
+    failed_readings: usize,
+    timeout: SpawnHandle,
+    /// When the approach fails if it hasn't reached the target, set by the first valid reading
+    deadline: Option<Instant>,
 }
 
 impl Drive {
@@ -109,36 +298,77 @@ impl Drive {
         gpio: &Gpio,
         motor_pins: [(u8, u8, u8); 4],
         pwm_frequency: f64,
-        websocket_addr: Option<Addr<WebSocket>>,
+        sessions_addr: Addr<Sessions>,
+        hc_sr04_addr: Addr<HcSr04>,
     ) -> Result<Self, Error> {
         Ok(Self {
-            motors: [
-                motor::Motor::new(gpio, motor_pins[0].0, motor_pins[0].1, motor_pins[0].2)?,
-                motor::Motor::new(gpio, motor_pins[1].0, motor_pins[1].1, motor_pins[1].2)?,
-                motor::Motor::new(gpio, motor_pins[2].0, motor_pins[2].1, motor_pins[2].2)?,
-                motor::Motor::new(gpio, motor_pins[3].0, motor_pins[3].1, motor_pins[3].2)?,
-            ],
+            gpio: gpio.clone(),
+            motor_pins,
+            brake: Brake::new(Self::create_motors(gpio, motor_pins)?),
             pwm_frequency,
-            websocket_addr,
+            sessions_addr,
+            hc_sr04_addr,
+            approach: None,
+            timed_move: None,
+            watchers: Vec::new(),
+            motion: Some(Motion::Stop),
+            motion_start: Instant::now(),
+            duty_cycles: [0.; 4],
+            odometry: odometry::Odometry::new(),
+            shut_down: false,
+            restarts: Restarts::default(),
         })
     }
 
+    fn create_motors(gpio: &Gpio, pins: [(u8, u8, u8); 4]) -> Result<[motor::Motor; 4], Error> {
+        Ok([
+            motor::Motor::new(gpio, pins[0].0, pins[0].1, pins[0].2)?,
+            motor::Motor::new(gpio, pins[1].0, pins[1].1, pins[1].2)?,
+            motor::Motor::new(gpio, pins[2].0, pins[2].1, pins[2].2)?,
+            motor::Motor::new(gpio, pins[3].0, pins[3].1, pins[3].2)?,
+        ])
+    }
+
+    /// Requests the motor pins again after a restart, the actor is restarted again if that fails
+    fn reinitialize(&mut self, ctx: &mut <Self as Actor>::Context) {
+        match Self::create_motors(&self.gpio, self.motor_pins) {
+            Ok(motors) => {
+                info!("re-initialized");
+                *self.brake.motors() = Some(motors);
+            }
+            Err(e) => {
+                error!("failed to re-initialize: {e}");
+                ctx.stop();
+            }
+        }
+    }
+
     /// Enables all motors, speeds specified in `motor_speeds` (positive: forward, negative: backward)
     fn enable_motors(&mut self, motor_speeds: &[f64]) -> Result<(), Error> {
+        // Locked before checking the brake, so that it can't be engaged while the motors start
+        let mut motors = self.brake.motors();
+        let Some(motors) = &mut *motors else {
+            return Err(Error::Io(io::Error::other("drive is restarting")));
+        };
+        if self.brake.is_engaged() && motor_speeds.iter().any(|s| *s != 0.) {
+            return Err(Error::Io(io::Error::other("emergency stop is engaged")));
+        }
         motor_speeds
             .iter()
             .enumerate()
             .try_for_each(|(i, duty_cycle)| -> Result<(), Error> {
                 let duty_cycle = *duty_cycle;
                 if duty_cycle > 0. {
-                    self.motors[i].enable_fwd(self.pwm_frequency, duty_cycle)?;
+                    motors[i].enable_fwd(self.pwm_frequency, duty_cycle)?;
                 } else if duty_cycle < 0. {
-                    self.motors[i].enable_bwd(self.pwm_frequency, -duty_cycle)?;
+                    motors[i].enable_bwd(self.pwm_frequency, -duty_cycle)?;
                 } else {
-                    self.motors[i].stop();
+                    motors[i].stop();
                 }
+                self.duty_cycles[i] = duty_cycle;
                 Ok(())
             })?;
+        self.odometry.update(self.duty_cycles);
         debug!("enabled motors with speeds {motor_speeds:?}");
         Ok(())
     }
@@ -159,9 +389,44 @@ impl Drive {
             Motion::Stop => [0., 0., 0., 0.],
         };
         self.enable_motors(&motor_speeds)?;
+        self.set_motion(Some(motion));
         Ok(())
     }
 
+    /// Starts moving with the specified body velocity, given as fractions of the maximum duty cycle.
+    /// Wheel speeds are scaled down proportionally if any of them exceeds the maximum duty cycle:
+    /// * `x` - forward (positive) or backward (negative),
+    /// * `y` - right (positive) or left (negative),
+    /// * `rotation` - clockwise (positive) or counterclockwise (negative)
+    fn enable_with_velocity(&mut self, x: f64, y: f64, rotation: f64) -> Result<(), Error> {
+        let motor_speeds = [
+            x - y + rotation,
+            x + y - rotation,
+            x - y - rotation,
+            x + y + rotation,
+        ];
+        let max_speed = motor_speeds
+            .iter()
+            .fold(1., |max, s| f64::max(max, s.abs()));
+        self.enable_motors(&motor_speeds.map(|s| s / max_speed))?;
+        self.set_motion(None);
+        Ok(())
+    }
+
+    /// Records the time spent in the previous motion
+    fn set_motion(&mut self, motion: Option<Motion>) {
+        let label = match self.motion {
+            Some(motion) => format!("{motion:?}"),
+            None => "Velocity".to_string(),
+        };
+        METRICS
+            .motion_seconds
+            .with_label_values(&[&label])
+            .inc_by(self.motion_start.elapsed().as_secs_f64());
+        self.motion = motion;
+        self.motion_start = Instant::now();
+    }
+
     /// Starts specified `motion` with specified `speed`
     fn enable(&mut self, motion: Motion, speed: Speed) -> Result<(), Error> {
         let duty_cycle = speed.get_duty_cycle();
@@ -179,25 +444,9 @@ impl Drive {
         speed: Speed,
         distance: f64,
     ) -> Result<(), Error> {
-        if motion == Motion::RightRot || motion == Motion::LeftRot {
-            panic!("Invalid motion for moving robot a specified distance");
-        }
-        let time_s = distance / speed.get_velocity();
-        let time = Duration::from_secs_f64(time_s);
-        let addr = ctx.address();
-        let fut = async move {
-            let enable_message = DriveMessage::Enable { motion, speed };
-            info!("sent {enable_message:?} to drive");
-            addr.do_send(enable_message);
-
-            time::sleep(time).await;
-
-            let disable_message = DriveMessage::Disable;
-            info!("sent {disable_message:?} to drive");
-            addr.do_send(disable_message);
-        };
-
-        ctx.spawn(wrap_future(fut));
+        let time = speed.get_travel_time(distance)?;
+        self.enable(motion, speed)?;
+        self.start_timed_move(ctx, time);
         Ok(())
     }
 
@@ -212,36 +461,170 @@ impl Drive {
         speed: Speed,
         angle: f64,
     ) -> Result<(), Error> {
-        if motion != Motion::RightRot && motion != Motion::LeftRot {
-            panic!("Invalid motion for rotating robot a specified angle");
-        }
-        let distance = 2. * PI * ROBOT_RADIUS * angle / 360.;
-        let time_s = distance / speed.get_velocity();
-        let time = Duration::from_secs_f64(time_s);
-        let addr = ctx.address();
-        let fut = async move {
-            let enable_message = DriveMessage::Enable { motion, speed };
-            info!("sent {enable_message:?} to drive");
-            addr.do_send(enable_message);
-
-            time::sleep(time).await;
-
-            let disable_message = DriveMessage::Disable;
-            info!("sent {disable_message:?} to drive");
-            addr.do_send(disable_message);
-        };
+        let time = speed.get_rotation_time(angle)?;
+        self.enable(motion, speed)?;
+        self.start_timed_move(ctx, time);
+        Ok(())
+    }
+
+    /// Stops the robot once `time` has passed, cancelling the previous timed move, whose stop
+    /// would otherwise cut the new move short
+    fn start_timed_move(&mut self, ctx: &mut <Self as Actor>::Context, time: Duration) {
+        let handle = ctx.run_later(time, |act, _ctx| {
+            act.timed_move = None;
+            let result = act.enable(Motion::Stop, Speed::Low);
+            let result = act.report(DriveMessage::Disable, result);
+            act.notify_watchers(result.map(|_| ()));
+        });
+        if let Some(previous) = self.timed_move.replace(handle) {
+            ctx.cancel_future(previous);
+        }
+    }
 
-        ctx.spawn(wrap_future(fut));
+    /// Cancels the ongoing timed move or approach, if any, telling its watchers why
+    fn cancel_motion(&mut self, ctx: &mut <Self as Actor>::Context, reason: &str) {
+        let approach = self.approach.take().map(|approach| approach.timeout);
+        let timed_move = self.timed_move.take();
+        for handle in [approach, timed_move].into_iter().flatten() {
+            ctx.cancel_future(handle);
+        }
+        self.notify_watchers(Err(reason.to_string()));
+    }
+
+    /// Tells everyone waiting for the ongoing timed move or approach how it ended
+    fn notify_watchers(&mut self, result: Result<(), String>) {
+        for watcher in self.watchers.drain(..) {
+            watcher.recipient.do_send(MotionFinished {
+                id: watcher.id,
+                result: result.clone(),
+            });
+        }
+    }
+
+    /// Drive forward or backward until the distance sensor reads `target` meters, slowing down
+    /// as the target gets closer
+    fn approach_distance(
+        &mut self,
+        ctx: &mut <Self as Actor>::Context,
+        target: f32,
+        speed: Speed,
+    ) -> Result<(), Error> {
+        if let Some(approach) = self.approach.take() {
+            ctx.cancel_future(approach.timeout);
+        }
+        let timeout = self.request_approach_reading(ctx);
+        self.approach = Some(Approach {
+            target,
+            speed,
+            failed_readings: 0,
+            timeout,
+            deadline: None,
+        });
         Ok(())
     }
 
+    /// Requests a distance measurement for the ongoing approach, returns the handle of the timeout
+    /// which aborts the approach if no reading arrives in time
+    fn request_approach_reading(&self, ctx: &mut <Self as Actor>::Context) -> SpawnHandle {
+        self.hc_sr04_addr
+            .do_send(HcSr04Message::Single(ctx.address().recipient()));
+        ctx.run_later(APPROACH_READING_TIMEOUT, |act, ctx| {
+            act.approach = None;
+            act.finish_approach(
+                ctx,
+                DriveResponse::ApproachFailed("distance reading timed out".to_string()),
+            );
+        })
+    }
+
+    /// Stops the robot after an approach and reports the outcome
+    fn finish_approach(&mut self, ctx: &mut <Self as Actor>::Context, response: DriveResponse) {
+        if let Some(approach) = self.approach.take() {
+            ctx.cancel_future(approach.timeout);
+        }
+        let response = match self.enable(Motion::Stop, Speed::Low) {
+            Ok(_) => response,
+            Err(e) => DriveResponse::Err(e),
+        };
+        self.notify_watchers(match &response {
+            DriveResponse::Ok { .. } | DriveResponse::Approached(_) => Ok(()),
+            DriveResponse::ApproachFailed(reason) => Err(reason.clone()),
+            DriveResponse::Err(e) => Err(e.to_string()),
+        });
+        info!("sending {response:?} to sessions");
+        self.sessions_addr.do_send(response);
+    }
+
+    /// Records a handled command and reports the outcome to the sessions, returns the duty cycles
+    /// of all wheels on success
+    fn report(&self, msg: DriveMessage, result: Result<(), Error>) -> Result<[f64; 4], String> {
+        let (response, result) = match result {
+            Ok(_) => {
+                recorder::record(Record::Drive {
+                    message: msg,
+                    duty_cycles: self.duty_cycles,
+                });
+                (
+                    DriveResponse::Ok {
+                        message: msg,
+                        duty_cycles: self.duty_cycles,
+                    },
+                    Ok(self.duty_cycles),
+                )
+            }
+            Err(e) => {
+                let description = e.to_string();
+                (DriveResponse::Err(e), Err(description))
+            }
+        };
+        info!("sending {response:?} to sessions");
+        self.sessions_addr.do_send(response);
+        result
+    }
+
+    /// Applies a drive command, which has to be valid, see `DriveMessage::validate`
+    fn apply(
+        &mut self,
+        ctx: &mut <Self as Actor>::Context,
+        msg: DriveMessage,
+    ) -> Result<(), Error> {
+        match msg {
+            DriveMessage::Enable { motion, speed } => self.enable(motion, speed),
+            DriveMessage::Disable => self.enable(Motion::Stop, Speed::Low),
+            DriveMessage::Velocity { x, y, rotation } => self.enable_with_velocity(x, y, rotation),
+            DriveMessage::Move {
+                motion,
+                speed,
+                distance,
+            } => self.move_distance(ctx, motion, speed, distance),
+            DriveMessage::Rotate {
+                motion,
+                speed,
+                angle,
+            } => self.rotate_angle(ctx, motion, speed, angle),
+            DriveMessage::ApproachDistance { target, speed } => {
+                self.approach_distance(ctx, target, speed)
+            }
+        }
+    }
+
+    /// Handle to stop the motors without going through the mailbox, see `Brake`
+    pub fn brake(&self) -> Brake {
+        self.brake.clone()
+    }
+
     /// Prints all motor pins
     pub fn list_motors(&self) {
-        self.motors.iter().enumerate().for_each(|(i, m)| {
-            println!("Motor {}:", i);
-            m.print_pins();
-            println!();
-        });
+        self.brake
+            .motors()
+            .iter()
+            .flatten()
+            .enumerate()
+            .for_each(|(i, m)| {
+                println!("Motor {}:", i);
+                m.print_pins();
+                println!();
+            });
     }
 }
 
@@ -255,30 +638,43 @@ impl Actor for Drive {
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
-        self.enable(Motion::Stop, Speed::Low)
-            .expect("Failed to enable motors");
+        if let Err(e) = self.enable(Motion::Stop, Speed::Low) {
+            error!("failed to stop motors: {e}");
+        }
         info!("actor stopped")
     }
 }
 
-impl Device for Drive {
-    fn set_websocket_addr(&mut self, addr: Addr<WebSocket>) {
-        self.websocket_addr = Some(addr);
-        info!("set WebSocket address");
-    }
-}
-
-impl Handler<AddrMessage> for Drive {
-    type Result = ();
-
-    fn handle(&mut self, msg: AddrMessage, _ctx: &mut Self::Context) -> Self::Result {
-        info!("received {msg:?}");
-        self.set_websocket_addr(msg.0);
+impl Supervised for Drive {
+    fn restarting(&mut self, ctx: &mut Self::Context) {
+        if self.approach.is_some() {
+            self.sessions_addr
+                .do_send(DriveResponse::ApproachFailed("drive restarted".to_string()));
+        }
+        self.cancel_motion(ctx, "drive restarted");
+        // Releases the pins, which stops the motors, they are requested again once the delay
+        // has passed
+        *self.brake.motors() = None;
+        self.duty_cycles = [0.; 4];
+        self.odometry.update(self.duty_cycles);
+        self.set_motion(Some(Motion::Stop));
+        let delay = self.restarts.record();
+        warn!(
+            "restarting (restart {}), re-initializing in {delay:?}",
+            self.restarts.count()
+        );
+        self.sessions_addr.do_send(DeviceRestarted {
+            device: Device::Drive,
+            restarts: self.restarts.count(),
+            delay,
+        });
+        ctx.run_later(delay, |act, ctx| act.reinitialize(ctx));
     }
 }
 
-#[derive(Debug, Deserialize, Message)]
-#[rtype(result = "()")]
+/// Drive commands, result contains duty cycles of all wheels after the command was handled
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Message)]
+#[rtype(result = "Result<[f64; 4], String>")]
 #[serde(tag = "variant")]
 pub enum DriveMessage {
     Enable {
@@ -286,6 +682,11 @@ pub enum DriveMessage {
         speed: Speed,
     },
     Disable,
+    Velocity {
+        x: f64,
+        y: f64,
+        rotation: f64,
+    },
     Move {
         motion: Motion,
         speed: Speed,
@@ -296,47 +697,426 @@ pub enum DriveMessage {
         speed: Speed,
         angle: f64,
     },
+    ApproachDistance {
+        target: f32,
+        speed: Speed,
+    },
 }
 
-impl Handler<DriveMessage> for Drive {
-    type Result = ();
-
-    fn handle(&mut self, msg: DriveMessage, ctx: &mut Self::Context) -> Self::Result {
-        info!("received {msg:?}");
-        let response = match msg {
-            DriveMessage::Enable { motion, speed } => match self.enable(motion, speed) {
-                Ok(_) => DriveResponse::Ok(msg),
-                Err(e) => DriveResponse::Err(e),
-            },
-            DriveMessage::Disable => match self.enable(Motion::Stop, Speed::Low) {
-                Ok(_) => DriveResponse::Ok(msg),
-                Err(e) => DriveResponse::Err(e),
-            },
+impl DriveMessage {
+    /// Checks the parameters of the command, so that invalid ones are rejected before they reach
+    /// the motors
+    pub fn validate(&self) -> Result<(), InvalidCommand> {
+        match *self {
+            DriveMessage::Enable { speed, .. } => speed.validate(),
+            DriveMessage::Disable => Ok(()),
+            DriveMessage::Velocity { x, y, rotation } => {
+                if ![x, y, rotation].iter().all(|v| v.is_finite()) {
+                    return Err(InvalidCommand(format!(
+                        "invalid velocity ({x}, {y}, {rotation})"
+                    )));
+                }
+                Ok(())
+            }
             DriveMessage::Move {
                 motion,
                 speed,
                 distance,
-            } => match self.move_distance(ctx, motion, speed, distance) {
-                Ok(_) => DriveResponse::Ok(msg),
-                Err(e) => DriveResponse::Err(e),
-            },
+            } => {
+                if motion.is_rotation() {
+                    return Err(InvalidCommand(format!(
+                        "{motion:?} can't move a distance, use Rotate"
+                    )));
+                }
+                speed.get_travel_time(distance).map(drop)
+            }
             DriveMessage::Rotate {
                 motion,
                 speed,
                 angle,
-            } => match self.rotate_angle(ctx, motion, speed, angle) {
-                Ok(_) => DriveResponse::Ok(msg),
-                Err(e) => DriveResponse::Err(e),
-            },
+            } => {
+                if !motion.is_rotation() {
+                    return Err(InvalidCommand(format!(
+                        "{motion:?} can't rotate, use RightRot or LeftRot"
+                    )));
+                }
+                speed.get_rotation_time(angle).map(drop)
+            }
+            DriveMessage::ApproachDistance { target, speed } => {
+                if !(target.is_finite() && target >= 0.) {
+                    return Err(InvalidCommand(format!("invalid target distance {target}")));
+                }
+                speed.validate_moving()
+            }
+        }
+    }
+
+    /// Name of the variant, as in the `variant` field
+    pub fn variant(&self) -> &'static str {
+        match self {
+            DriveMessage::Enable { .. } => "Enable",
+            DriveMessage::Disable => "Disable",
+            DriveMessage::Velocity { .. } => "Velocity",
+            DriveMessage::Move { .. } => "Move",
+            DriveMessage::Rotate { .. } => "Rotate",
+            DriveMessage::ApproachDistance { .. } => "ApproachDistance",
+        }
+    }
+}
+
+impl Handler<DriveMessage> for Drive {
+    type Result = Result<[f64; 4], String>;
+
+    fn handle(&mut self, msg: DriveMessage, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        METRICS.handled("drive");
+        METRICS
+            .drive_commands
+            .with_label_values(&[msg.variant()])
+            .inc();
+        if self.shut_down {
+            return Err("drive is shut down".to_string());
+        }
+        // Stopping is always allowed
+        if self.brake.is_engaged() && !matches!(msg, DriveMessage::Disable) {
+            return Err("emergency stop is engaged".to_string());
+        }
+        // Invalid commands don't interrupt anything
+        msg.validate().map_err(|e| e.to_string())?;
+        // Any other command overrides an ongoing approach or timed move
+        self.cancel_motion(ctx, &format!("interrupted by {}", msg.variant()));
+        let result = catch_panic(|| self.apply(ctx, msg)).unwrap_or_else(|panic| {
+            // The motors may be in any state, the restart releases them
+            error!("drive command panicked, restarting: {panic}");
+            ctx.stop();
+            Err(Error::Io(io::Error::other(format!(
+                "drive crashed: {panic}"
+            ))))
+        });
+        self.report(msg, result)
+    }
+}
+
+impl Handler<HcSr04Response> for Drive {
+    type Result = ();
+
+    fn handle(&mut self, msg: HcSr04Response, ctx: &mut Self::Context) -> Self::Result {
+        METRICS.handled("drive");
+        // Readings requested by a cancelled approach are ignored
+        let Some(approach) = &mut self.approach else {
+            return;
+        };
+        ctx.cancel_future(approach.timeout);
+        let distance = match msg {
+            HcSr04Response::Ok(HcSr04Measurement::Single(result))
+                if result.distance.is_finite() =>
+            {
+                Some(result.distance)
+            }
+            _ => None,
+        };
+        let (target, speed) = (approach.target, approach.speed);
+        // The first valid reading decides how long the approach may take
+        if let (None, Some(distance)) = (approach.deadline, distance) {
+            match speed.get_travel_time((distance - target).abs() as f64) {
+                Ok(time) => approach.deadline = Some(Instant::now() + time + APPROACH_TIME_MARGIN),
+                Err(e) => {
+                    self.finish_approach(ctx, DriveResponse::ApproachFailed(e.to_string()));
+                    return;
+                }
+            }
+        }
+        if approach
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.finish_approach(
+                ctx,
+                DriveResponse::ApproachFailed(format!("target {target} m not reached in time")),
+            );
+            return;
+        }
+
+        let result = match distance {
+            Some(distance) if (distance - target).abs() <= APPROACH_TOLERANCE => {
+                self.finish_approach(ctx, DriveResponse::Approached(distance));
+                return;
+            }
+            // Move towards the target, slowing down when getting close
+            Some(distance) => {
+                approach.failed_readings = 0;
+                let error = distance - target;
+                let motion = if error > 0. {
+                    Motion::Forward
+                } else {
+                    Motion::Backward
+                };
+                let max_duty_cycle = speed.get_duty_cycle();
+                let duty_cycle = (max_duty_cycle
+                    * (error.abs() / APPROACH_SLOWDOWN_DISTANCE) as f64)
+                    .clamp(APPROACH_MIN_DUTY_CYCLE.min(max_duty_cycle), max_duty_cycle);
+                self.enable_with_duty_cycle(motion, duty_cycle)
+            }
+            // Don't move blindly, stop until a valid reading arrives
+            None => {
+                approach.failed_readings += 1;
+                if approach.failed_readings >= APPROACH_MAX_FAILED_READINGS {
+                    self.finish_approach(
+                        ctx,
+                        DriveResponse::ApproachFailed(format!(
+                            "{APPROACH_MAX_FAILED_READINGS} distance readings failed"
+                        )),
+                    );
+                    return;
+                }
+                self.enable(Motion::Stop, Speed::Low)
+            }
         };
-        info!("sending {response:?} to WebSocket");
-        self.websocket_addr.as_ref().unwrap().do_send(response);
+        if let Err(e) = result {
+            self.finish_approach(ctx, DriveResponse::Err(e));
+            return;
+        }
+        let timeout = self.request_approach_reading(ctx);
+        if let Some(approach) = &mut self.approach {
+            approach.timeout = timeout;
+        }
+    }
+}
+
+/// Sent after the `Brake` was engaged by the emergency stop, cancels an ongoing approach and
+/// timed moves and resets the drive state to stopped
+#[derive(Clone, Copy, Debug, Message)]
+#[rtype(result = "()")]
+pub struct Halt;
+
+impl Handler<Halt> for Drive {
+    type Result = ();
+
+    fn handle(&mut self, msg: Halt, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        METRICS.handled("drive");
+        if self.approach.is_some() {
+            self.sessions_addr
+                .do_send(DriveResponse::ApproachFailed("emergency stop".to_string()));
+        }
+        self.cancel_motion(ctx, "emergency stop");
+        if let Err(e) = self.enable(Motion::Stop, Speed::Low) {
+            error!("failed to stop motors: {e}");
+        }
+    }
+}
+
+/// Asks to be told with `MotionFinished` once the ongoing timed move or approach ends. Answered
+/// right away if there is none, e.g. because it already ended
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct WatchMotion {
+    /// Sent back in `MotionFinished`
+    pub id: usize,
+    pub recipient: Recipient<MotionFinished>,
+}
+
+impl Handler<WatchMotion> for Drive {
+    type Result = ();
+
+    fn handle(&mut self, msg: WatchMotion, _ctx: &mut Self::Context) -> Self::Result {
+        METRICS.handled("drive");
+        if self.approach.is_none() && self.timed_move.is_none() {
+            msg.recipient.do_send(MotionFinished {
+                id: msg.id,
+                result: Ok(()),
+            });
+            return;
+        }
+        self.watchers.push(msg);
+    }
+}
+
+/// Sent to watchers when a timed move or approach ends, with the reason if it didn't complete,
+/// e.g. because another command interrupted it
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct MotionFinished {
+    pub id: usize,
+    pub result: Result<(), String>,
+}
+
+/// Requests the current `DriveState`
+#[derive(Debug, Message)]
+#[rtype(result = "DriveState")]
+pub struct GetDriveState;
+
+#[derive(Clone, Debug, MessageResponse, Serialize)]
+pub struct DriveState {
+    pub motion: Option<Motion>,
+    /// Duty cycles of all wheels (positive: forward, negative: backward)
+    pub duty_cycles: [f64; 4],
+    /// Target distance of an ongoing approach
+    pub approach_target: Option<f32>,
+    /// Estimated by dead reckoning
+    pub pose: Pose,
+    pub twist: Twist,
+}
+
+impl Handler<GetDriveState> for Drive {
+    type Result = DriveState;
+
+    fn handle(&mut self, _msg: GetDriveState, _ctx: &mut Self::Context) -> Self::Result {
+        METRICS.handled("drive");
+        DriveState {
+            motion: self.motion,
+            duty_cycles: self.duty_cycles,
+            approach_target: self.approach.as_ref().map(|approach| approach.target),
+            pose: self.odometry.pose(),
+            twist: self.odometry.twist(),
+        }
+    }
+}
+
+impl Handler<Shutdown> for Drive {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Shutdown, ctx: &mut Self::Context) -> Self::Result {
+        info!("shutting down");
+        self.shut_down = true;
+        self.cancel_motion(ctx, "drive is shut down");
+        if let Err(e) = self.enable(Motion::Stop, Speed::Low) {
+            error!("failed to stop motors: {e}");
+        }
+        for motor in self.brake.motors().iter_mut().flatten() {
+            if let Err(e) = motor.release() {
+                error!("failed to release motor: {e}");
+            }
+        }
+    }
+}
+
+impl Handler<MailboxProbe> for Drive {
+    type Result = u64;
+
+    fn handle(&mut self, _msg: MailboxProbe, _ctx: &mut Self::Context) -> Self::Result {
+        METRICS.actor_messages.with_label_values(&["drive"]).get()
     }
 }
 
 #[derive(Debug, Message)]
 #[rtype(result = "()")]
 pub enum DriveResponse {
-    Ok(DriveMessage),
+    Ok {
+        message: DriveMessage,
+        duty_cycles: [f64; 4],
+    },
+    /// Approach finished at the reported distance
+    Approached(f32),
+    ApproachFailed(String),
     Err(Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_speeds_do_not_move_the_robot() {
+        assert!(Speed::Low.validate_moving().is_ok());
+        assert!(Speed::Manual(0.2).validate().is_ok());
+        assert!(Speed::Manual(0.2).validate_moving().is_err());
+        assert!(Speed::Manual(1.5).validate().is_err());
+        assert!(Speed::Manual(-0.5).validate().is_err());
+        assert!(Speed::Manual(f64::NAN).validate().is_err());
+    }
+
+    #[test]
+    fn travel_time_is_bounded() {
+        let time = Speed::Medium.get_travel_time(0.5).unwrap();
+        assert!(time > Duration::ZERO && time < MAX_MOVE_TIME);
+        assert_eq!(Speed::High.get_travel_time(0.), Ok(Duration::ZERO));
+        assert!(Speed::Manual(0.2).get_travel_time(0.5).is_err());
+        assert!(Speed::Low.get_travel_time(-1.).is_err());
+        assert!(Speed::Low.get_travel_time(f64::NAN).is_err());
+        assert!(Speed::Low.get_travel_time(1e300).is_err());
+        assert!(Speed::Low.get_rotation_time(f64::INFINITY).is_err());
+        assert!(Speed::Low.get_rotation_time(90.).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_commands_which_can_not_be_executed() {
+        let valid = [
+            DriveMessage::Enable {
+                motion: Motion::Forward,
+                speed: Speed::Manual(0.1),
+            },
+            DriveMessage::Disable,
+            DriveMessage::Velocity {
+                x: 0.5,
+                y: -0.5,
+                rotation: 0.,
+            },
+            DriveMessage::Move {
+                motion: Motion::Left,
+                speed: Speed::Low,
+                distance: 0.3,
+            },
+            DriveMessage::Rotate {
+                motion: Motion::RightRot,
+                speed: Speed::Medium,
+                angle: 90.,
+            },
+            DriveMessage::ApproachDistance {
+                target: 0.2,
+                speed: Speed::Low,
+            },
+        ];
+        for message in valid {
+            assert_eq!(message.validate(), Ok(()), "{message:?}");
+        }
+
+        let invalid = [
+            DriveMessage::Enable {
+                motion: Motion::Forward,
+                speed: Speed::Manual(2.),
+            },
+            DriveMessage::Velocity {
+                x: f64::NAN,
+                y: 0.,
+                rotation: 0.,
+            },
+            DriveMessage::Move {
+                motion: Motion::RightRot,
+                speed: Speed::Low,
+                distance: 0.3,
+            },
+            DriveMessage::Move {
+                motion: Motion::Forward,
+                speed: Speed::Manual(0.2),
+                distance: 0.3,
+            },
+            DriveMessage::Move {
+                motion: Motion::Forward,
+                speed: Speed::Low,
+                distance: -0.3,
+            },
+            DriveMessage::Rotate {
+                motion: Motion::Forward,
+                speed: Speed::Low,
+                angle: 90.,
+            },
+            DriveMessage::Rotate {
+                motion: Motion::LeftRot,
+                speed: Speed::Low,
+                angle: f64::NAN,
+            },
+            DriveMessage::ApproachDistance {
+                target: -1.,
+                speed: Speed::Low,
+            },
+            DriveMessage::ApproachDistance {
+                target: 0.2,
+                speed: Speed::Manual(0.2),
+            },
+        ];
+        for message in invalid {
+            assert!(message.validate().is_err(), "{message:?}");
+        }
+    }
+}
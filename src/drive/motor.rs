@@ -51,6 +51,17 @@ impl Motor {
         self.bwd_pin.set_low();
     }
 
+    /// Stops the motor and keeps its pins low once they're dropped, instead of resetting them to
+    /// inputs, so that the L298N stays disabled after the program exits
+    pub fn release(&mut self) -> Result<(), Error> {
+        self.enable_pin.clear_pwm()?;
+        self.stop();
+        self.fwd_pin.set_reset_on_drop(false);
+        self.bwd_pin.set_reset_on_drop(false);
+        self.enable_pin.set_reset_on_drop(false);
+        Ok(())
+    }
+
     /// Prints pins used by motor
     pub fn print_pins(&self) {
         println!("Enable pin: {}", self.enable_pin.pin());
@@ -0,0 +1,83 @@
+use std::time::Instant;
+
+use serde::Serialize;
+
+use super::{Speed, ROBOT_RADIUS};
+
+/// Position and heading relative to where the robot was at startup, using ROS conventions: `x`
+/// forward, `y` left (in meters), `heading` counterclockwise (in radians)
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Pose {
+    pub x: f64,
+    pub y: f64,
+    pub heading: f64,
+}
+
+/// Body velocity, using ROS conventions: `x` forward, `y` left (in m/s), `rotation`
+/// counterclockwise (in rad/s)
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Twist {
+    pub x: f64,
+    pub y: f64,
+    pub rotation: f64,
+}
+
+/// Dead reckoning from the wheel duty cycles, based on the movement calibration. There is no wheel
+/// feedback, so the estimate drifts quickly
+pub struct Odometry {
+    pose: Pose,
+    twist: Twist,
+    last_update: Instant,
+}
+
+impl Odometry {
+    pub fn new() -> Self {
+        Self {
+            pose: Pose::default(),
+            twist: Twist::default(),
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Advances the pose with the current velocity up to now
+    fn integrate(&mut self) {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+
+        let Twist { x, y, rotation } = self.twist;
+        let heading = self.pose.heading + rotation * dt / 2.;
+        self.pose.x += (x * heading.cos() - y * heading.sin()) * dt;
+        self.pose.y += (x * heading.sin() + y * heading.cos()) * dt;
+        self.pose.heading += rotation * dt;
+    }
+
+    /// Updates the velocity after the wheel duty cycles changed
+    pub fn update(&mut self, duty_cycles: [f64; 4]) {
+        self.integrate();
+        // Wheel velocities, duty cycles too low to move the wheels give NaN
+        let [w0, w1, w2, w3] = duty_cycles.map(|duty_cycle| {
+            let velocity = Speed::Manual(duty_cycle.abs()).get_velocity();
+            if velocity.is_nan() {
+                0.
+            } else {
+                velocity.copysign(duty_cycle)
+            }
+        });
+        // Inverse of the mix in `Drive::enable_with_velocity`
+        self.twist = Twist {
+            x: (w0 + w1 + w2 + w3) / 4.,
+            y: (w0 - w1 + w2 - w3) / 4.,
+            rotation: (-w0 + w1 + w2 - w3) / 4. / ROBOT_RADIUS,
+        };
+    }
+
+    pub fn pose(&mut self) -> Pose {
+        self.integrate();
+        self.pose
+    }
+
+    pub fn twist(&self) -> Twist {
+        self.twist
+    }
+}
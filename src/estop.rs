@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use actix::{fut::wrap_future, prelude::*};
+use log::info;
+use rppal::gpio::{Error, Gpio, InputPin, Trigger};
+use serde::Serialize;
+
+use crate::{
+    drive::{Brake, Drive, GetDriveState},
+    hc_sr04::{GetHcSr04State, HcSr04},
+    mode::{GetMode, Mode, ModeManager},
+    telemetry::{Behaviour, GetBehaviours, Telemetry, SENSOR_MAX_FAILURES},
+};
+
+/// How often the watchdog checks the drive and the distance sensor
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The emergency stop is triggered if the drive doesn't answer within this while the motors run
+const DRIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sensor state requests wait behind measurements, answers taking longer are skipped
+const SENSOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the distance sensor may be failing while a behaviour relies on it
+const SENSOR_FAILURE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What triggered the emergency stop
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cause {
+    /// A WebSocket or REST client
+    Client,
+    /// The physical button
+    Button,
+    /// The distance sensor kept failing while a behaviour relied on it
+    SensorFailure,
+    /// The drive stopped responding while the motors were running
+    Watchdog,
+}
+
+/// Latches the emergency stop: the motors are stopped, behaviours are cancelled and drive
+/// commands are refused until `ResetEmergencyStop`
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct EmergencyStop {
+    pub cause: Cause,
+    pub reason: String,
+}
+
+/// Releases the emergency stop, the robot is idle afterwards
+#[derive(Clone, Copy, Debug, Message)]
+#[rtype(result = "()")]
+pub struct ResetEmergencyStop;
+
+/// Sent to `Sessions` when the emergency stop is engaged, broadcast to all clients
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct EmergencyStopped {
+    pub cause: Cause,
+    pub reason: String,
+}
+
+/// Triggers the emergency stop from anywhere, e.g. the GPIO interrupt thread: the `Brake` stops
+/// the motors right away, even if the drive has commands queued, then the `ModeManager` latches
+/// the stop
+#[derive(Clone)]
+pub struct EStop {
+    brake: Brake,
+    mode_manager_addr: Addr<ModeManager>,
+}
+
+impl EStop {
+    pub fn new(brake: Brake, mode_manager_addr: Addr<ModeManager>) -> Self {
+        Self {
+            brake,
+            mode_manager_addr,
+        }
+    }
+
+    pub fn trigger(&self, cause: Cause, reason: impl Into<String>) {
+        self.brake.engage();
+        self.mode_manager_addr.do_send(EmergencyStop {
+            cause,
+            reason: reason.into(),
+        });
+    }
+}
+
+/// Triggers the emergency stop when the button connecting `pin` to ground is pressed, the pin is
+/// pulled up otherwise. The button is watched until the returned pin is dropped
+pub fn watch_button(gpio: &Gpio, pin: u8, estop: EStop) -> Result<InputPin, Error> {
+    let mut button = gpio.get(pin)?.into_input_pullup();
+    button.set_async_interrupt(Trigger::FallingEdge, move |_level| {
+        estop.trigger(Cause::Button, "emergency stop button pressed");
+    })?;
+    info!("watching emergency stop button on pin {pin}");
+    Ok(button)
+}
+
+/// Periodically checks for internal faults which make driving unsafe and triggers the emergency
+/// stop on them
+pub struct Watchdog {
+    estop: EStop,
+    drive_addr: Addr<Drive>,
+    hc_sr04_addr: Addr<HcSr04>,
+    mode_manager_addr: Addr<ModeManager>,
+    telemetry_addr: Addr<Telemetry>,
+    /// Whether the motors were running when the drive last answered
+    moving: bool,
+    /// When the distance sensor started failing while a behaviour relied on it
+    sensor_failing_since: Option<Instant>,
+    /// Whether the previous check is still running
+    checking: bool,
+}
+
+impl Watchdog {
+    pub fn new(
+        estop: EStop,
+        drive_addr: Addr<Drive>,
+        hc_sr04_addr: Addr<HcSr04>,
+        mode_manager_addr: Addr<ModeManager>,
+        telemetry_addr: Addr<Telemetry>,
+    ) -> Self {
+        Self {
+            estop,
+            drive_addr,
+            hc_sr04_addr,
+            mode_manager_addr,
+            telemetry_addr,
+            moving: false,
+            sensor_failing_since: None,
+            checking: false,
+        }
+    }
+
+    fn check(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.checking {
+            return;
+        }
+        self.checking = true;
+        let mode_request = self.mode_manager_addr.send(GetMode);
+        let behaviours_request = self.telemetry_addr.send(GetBehaviours);
+        let drive_request = self.drive_addr.send(GetDriveState).timeout(DRIVE_TIMEOUT);
+        let hc_sr04_request = self
+            .hc_sr04_addr
+            .send(GetHcSr04State)
+            .timeout(SENSOR_TIMEOUT);
+        let fut = async move {
+            (
+                mode_request.await,
+                behaviours_request.await,
+                drive_request.await,
+                hc_sr04_request.await,
+            )
+        };
+        ctx.spawn(wrap_future(fut).map(
+            |(mode, behaviours, drive, sensor), act: &mut Self, _ctx| {
+                act.checking = false;
+                let Ok(mode) = mode else {
+                    return;
+                };
+                if mode == Mode::EStopped {
+                    return;
+                }
+                let drive = match drive {
+                    Ok(drive) => drive,
+                    Err(MailboxError::Timeout) if act.moving => {
+                        act.estop.trigger(
+                            Cause::Watchdog,
+                            format!("drive didn't respond within {DRIVE_TIMEOUT:?}"),
+                        );
+                        return;
+                    }
+                    Err(_) => return,
+                };
+                act.moving = drive.duty_cycles.iter().any(|d| *d != 0.);
+                // Not every behaviour of a mode measures the distance, e.g. the path follower
+                let relies_on_sensor = drive.approach_target.is_some()
+                    || behaviours.is_ok_and(|behaviours| {
+                        behaviours.into_iter().any(Behaviour::uses_distance_sensor)
+                    });
+                let Ok(sensor) = sensor else {
+                    return;
+                };
+                if !act.moving
+                    || !relies_on_sensor
+                    || sensor.consecutive_failures < SENSOR_MAX_FAILURES
+                {
+                    act.sensor_failing_since = None;
+                    return;
+                }
+                let since = *act.sensor_failing_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= SENSOR_FAILURE_TIMEOUT {
+                    act.sensor_failing_since = None;
+                    act.estop.trigger(
+                        Cause::SensorFailure,
+                        format!(
+                            "distance sensor failed {} measurements in a row",
+                            sensor.consecutive_failures
+                        ),
+                    );
+                }
+            },
+        ));
+    }
+}
+
+impl Actor for Watchdog {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("actor started");
+        ctx.run_interval(WATCHDOG_INTERVAL, |act, ctx| act.check(ctx));
+    }
+}
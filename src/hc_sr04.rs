@@ -1,11 +1,12 @@
 use actix::prelude::*;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 
-use crate::distance_scan::Scanner;
-use crate::movement_calibration::Calibrator;
-use crate::server::WebSocket;
+use crate::metrics::{MailboxProbe, METRICS};
+use crate::recorder::{self, Record};
+use crate::supervision::{catch_panic, Device, DeviceRestarted, Restarts};
 
 use std::f32::INFINITY;
+use std::io;
 use std::thread;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
@@ -13,52 +14,168 @@ use rppal::gpio::{Error, Gpio, InputPin, Level, OutputPin, Trigger};
 
 use serde::Serialize;
 
-pub struct HcSr04 {
+pub const MAX_RANGE: f32 = 4.; // m
+
+/// Outcome of a measurement, the `status` label of its metric
+#[derive(Clone, Copy, Debug)]
+enum MeasurementStatus {
+    Ok,
+    /// Echo pulse didn't start
+    NoEcho,
+    /// Echo pulse didn't end before the timeout
+    EchoTimeout,
+}
+
+impl MeasurementStatus {
+    fn label(self) -> &'static str {
+        match self {
+            MeasurementStatus::Ok => "ok",
+            MeasurementStatus::NoEcho => "no_echo",
+            MeasurementStatus::EchoTimeout => "echo_timeout",
+        }
+    }
+}
+
+/// Sensor pins, released and requested again when the actor restarts
+struct Pins {
     trig: OutputPin,
     echo: InputPin,
+}
+
+impl Pins {
+    fn new(gpio: &Gpio, trig_pin: u8, echo_pin: u8) -> Result<Self, Error> {
+        let mut echo = gpio.get(echo_pin)?.into_input_pulldown();
+        echo.set_interrupt(Trigger::Both)?;
+        Ok(Self {
+            trig: gpio.get(trig_pin)?.into_output_low(),
+            echo,
+        })
+    }
+}
+
+/// Error of measurements which couldn't be performed
+fn unavailable(description: impl Into<String>) -> Error {
+    Error::Io(io::Error::other(description.into()))
+}
+
+pub struct HcSr04 {
+    gpio: Gpio,
+    trig_pin: u8,
+    echo_pin: u8,
+    /// `None` while waiting to be re-initialized after a restart
+    pins: Option<Pins>,
     sound_speed: f32,
     timeout: Duration,
+    last_distance: Option<f32>,
+    consecutive_failures: usize,
+    /// Receives a `DeviceRestarted` whenever the actor restarts
+    restart_recipient: Recipient<DeviceRestarted>,
+    restarts: Restarts,
 }
 
 impl HcSr04 {
     fn calculate_parameters(temperature: f32) -> (f32, Duration) {
         const SOUND_SPEED_0C: f32 = 331.3; // m/s
         const SOUND_SPEED_INCR: f32 = 0.606; // (m/s)/*C
-        const MAX_RANGE: f32 = 4.; // m
         let sound_speed: f32 = SOUND_SPEED_0C + temperature * SOUND_SPEED_INCR;
         let timeout = Duration::from_secs_f32((MAX_RANGE / sound_speed) * 2.5);
         (sound_speed, timeout)
     }
 
-    pub fn new(gpio: &Gpio, trig_pin: u8, echo_pin: u8, temperature: f32) -> Result<Self, Error> {
+    pub fn new(
+        gpio: &Gpio,
+        trig_pin: u8,
+        echo_pin: u8,
+        temperature: f32,
+        restart_recipient: Recipient<DeviceRestarted>,
+    ) -> Result<Self, Error> {
         let (sound_speed, timeout) = Self::calculate_parameters(temperature);
-        let mut echo = gpio.get(echo_pin)?.into_input_pulldown();
-        echo.set_interrupt(Trigger::Both)?;
 
         Ok(Self {
-            trig: gpio.get(trig_pin)?.into_output_low(),
-            echo,
+            gpio: gpio.clone(),
+            trig_pin,
+            echo_pin,
+            pins: Some(Pins::new(gpio, trig_pin, echo_pin)?),
             sound_speed,
             timeout,
+            last_distance: None,
+            consecutive_failures: 0,
+            restart_recipient,
+            restarts: Restarts::default(),
         })
     }
 
-    /// Perform a single distance measurement
+    /// Requests the pins again after a restart, the actor is restarted again if that fails
+    fn reinitialize(&mut self, ctx: &mut <Self as Actor>::Context) {
+        match Pins::new(&self.gpio, self.trig_pin, self.echo_pin) {
+            Ok(pins) => {
+                info!("re-initialized");
+                self.pins = Some(pins);
+            }
+            Err(e) => {
+                error!("failed to re-initialize: {e}");
+                ctx.stop();
+            }
+        }
+    }
+
+    /// Runs a measurement `f`, a panic stops the actor so that it gets restarted
+    fn supervised<T>(
+        &mut self,
+        ctx: &mut <Self as Actor>::Context,
+        f: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        catch_panic(|| f(self)).unwrap_or_else(|panic| {
+            error!("measurement panicked, restarting: {panic}");
+            ctx.stop();
+            Err(unavailable(format!("sensor crashed: {panic}")))
+        })
+    }
+
+    /// Perform a single distance measurement, keeping track of the sensor health
     pub fn measure_distance(&mut self) -> Result<HcSr04Result, Error> {
+        let start = Instant::now();
+        let result = self.perform_measurement();
+        let status = match &result {
+            Ok((_, status)) => status.label(),
+            Err(_) => "error",
+        };
+        METRICS
+            .hc_sr04_measurements
+            .with_label_values(&[status])
+            .observe(start.elapsed().as_secs_f64());
+        let result = result.map(|(measurement, _)| measurement);
+        if let Ok(measurement) = &result {
+            recorder::record(Record::Distance(*measurement));
+        }
+        // Readings without an echo are valid, nothing is within range
+        match &result {
+            Ok(measurement) => {
+                if measurement.distance.is_finite() {
+                    self.last_distance = Some(measurement.distance);
+                }
+                self.consecutive_failures = 0;
+            }
+            Err(_) => self.consecutive_failures += 1,
+        }
+        result
+    }
+
+    fn perform_measurement(&mut self) -> Result<(HcSr04Result, MeasurementStatus), Error> {
+        let Some(Pins { trig, echo }) = &mut self.pins else {
+            return Err(unavailable("sensor is restarting"));
+        };
+
         // Wait for end of potential previous echo pulse
-        if self.echo.is_high() {
+        if echo.is_high() {
             debug!("Waiting for echo reset");
-            if self
-                .echo
-                .poll_interrupt(true, Some(Duration::from_millis(250)))?
-                != Some(Level::Low)
-            {
+            if echo.poll_interrupt(true, Some(Duration::from_millis(250)))? != Some(Level::Low) {
                 panic!("HcSr04 echo pin blocked");
             }
         }
 
         // Reset trig pin, make sure enough time passed before next measurement
-        self.trig.set_low();
+        trig.set_low();
         thread::sleep(Duration::from_millis(10));
 
         // Set measurement time
@@ -68,41 +185,43 @@ impl HcSr04 {
         debug!("performing measurement {}", time.as_millis());
 
         // Send trig signal
-        self.trig.set_high();
+        trig.set_high();
         thread::sleep(Duration::from_micros(10));
-        self.trig.set_low();
+        trig.set_low();
 
         // Wait for start of echo pulse
-        if self
-            .echo
-            .poll_interrupt(false, Some(Duration::from_millis(250)))?
-            != Some(Level::High)
-        {
+        if echo.poll_interrupt(false, Some(Duration::from_millis(250)))? != Some(Level::High) {
             // Return if echo wasn't started before timeout
-            return Ok(HcSr04Result {
-                time,
-                distance: INFINITY,
-            });
+            return Ok((
+                HcSr04Result {
+                    time,
+                    distance: INFINITY,
+                },
+                MeasurementStatus::NoEcho,
+            ));
         }
 
         // Echo pulse start
         let instant = Instant::now();
 
         // Wait for echo pulse end
-        if self.echo.poll_interrupt(false, Some(self.timeout))? != Some(Level::Low) {
+        if echo.poll_interrupt(false, Some(self.timeout))? != Some(Level::Low) {
             debug!("performed measurement {}, {}", time.as_millis(), INFINITY);
             // Return if pulse hasn't finished before timeout
-            return Ok(HcSr04Result {
-                time,
-                distance: INFINITY,
-            });
+            return Ok((
+                HcSr04Result {
+                    time,
+                    distance: INFINITY,
+                },
+                MeasurementStatus::EchoTimeout,
+            ));
         }
 
         // Compute measured distance
         let distance = self.sound_speed * instant.elapsed().as_secs_f32() * 0.5;
         debug!("performed measurement {}, {}", time.as_millis(), distance);
 
-        Ok(HcSr04Result { time, distance })
+        Ok((HcSr04Result { time, distance }, MeasurementStatus::Ok))
     }
 
     /// Perform `n` distance measurements, return a vector containing them
@@ -128,38 +247,53 @@ impl Actor for HcSr04 {
     }
 }
 
-#[derive(Debug)]
-pub enum Recipient {
-    WebSocket(Addr<WebSocket>),
-    Calibrator(Addr<Calibrator>),
-    Scanner(Addr<Scanner>),
+impl Supervised for HcSr04 {
+    fn restarting(&mut self, ctx: &mut Self::Context) {
+        // Releases the pins, they are requested again once the delay has passed
+        self.pins = None;
+        let delay = self.restarts.record();
+        warn!(
+            "restarting (restart {}), re-initializing in {delay:?}",
+            self.restarts.count()
+        );
+        self.restart_recipient.do_send(DeviceRestarted {
+            device: Device::HcSr04,
+            restarts: self.restarts.count(),
+            delay,
+        });
+        ctx.run_later(delay, |act, ctx| act.reinitialize(ctx));
+    }
 }
 
+/// Requests distance measurements, the `HcSr04Response` is sent to the recipient, e.g.
+/// `addr.recipient()` of any actor handling it
 #[derive(Debug, Message)]
 #[rtype(result = "()")]
 pub enum HcSr04Message {
-    Single(Recipient),
-    Multiple(usize, Recipient),
+    Single(Recipient<HcSr04Response>),
+    Multiple(usize, Recipient<HcSr04Response>),
 }
 
 impl Handler<HcSr04Message> for HcSr04 {
     type Result = ();
 
-    fn handle(&mut self, msg: HcSr04Message, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: HcSr04Message, ctx: &mut Self::Context) -> Self::Result {
         info!("received {msg:?}");
+        METRICS.handled("hc_sr04");
         let response;
         let recipient;
+        // Recipients are answered even if the sensor crashed, as they may be waiting for it
         match msg {
             HcSr04Message::Single(m_recipient) => {
                 recipient = m_recipient;
-                response = match self.measure_distance() {
+                response = match self.supervised(ctx, Self::measure_distance) {
                     Ok(dist) => HcSr04Response::Ok(HcSr04Measurement::Single(dist)),
                     Err(e) => HcSr04Response::Err(e),
                 };
             }
             HcSr04Message::Multiple(n, m_recipient) => {
                 recipient = m_recipient;
-                response = match self.measure_distance_n(n) {
+                response = match self.supervised(ctx, |act| act.measure_distance_n(n)) {
                     Ok(dist) => HcSr04Response::Ok(HcSr04Measurement::Multiple(dist)),
                     Err(e) => HcSr04Response::Err(e),
                 };
@@ -167,15 +301,60 @@ impl Handler<HcSr04Message> for HcSr04 {
         };
 
         info!("sending {response:?} to {recipient:?}");
-        match recipient {
-            Recipient::WebSocket(addr) => addr.do_send(response),
-            Recipient::Calibrator(addr) => addr.do_send(response),
-            Recipient::Scanner(addr) => addr.do_send(response),
-        };
+        recipient.do_send(response);
     }
 }
 
-#[derive(Debug, Serialize)]
+/// Performs `n` distance measurements and replies with them directly, instead of sending them to a
+/// recipient
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Vec<HcSr04Result>, Error>")]
+pub struct HcSr04Query(pub usize);
+
+impl Handler<HcSr04Query> for HcSr04 {
+    type Result = Result<Vec<HcSr04Result>, Error>;
+
+    fn handle(&mut self, msg: HcSr04Query, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        METRICS.handled("hc_sr04");
+        self.supervised(ctx, |act| act.measure_distance_n(msg.0))
+    }
+}
+
+/// Requests the current `HcSr04State`
+#[derive(Debug, Message)]
+#[rtype(result = "HcSr04State")]
+pub struct GetHcSr04State;
+
+#[derive(Clone, Copy, Debug, MessageResponse, Serialize)]
+pub struct HcSr04State {
+    /// Most recent valid distance (in meters)
+    pub last_distance: Option<f32>,
+    /// Number of measurements in a row which failed, e.g. because of GPIO errors or a stuck echo pin
+    pub consecutive_failures: usize,
+}
+
+impl Handler<GetHcSr04State> for HcSr04 {
+    type Result = HcSr04State;
+
+    fn handle(&mut self, _msg: GetHcSr04State, _ctx: &mut Self::Context) -> Self::Result {
+        METRICS.handled("hc_sr04");
+        HcSr04State {
+            last_distance: self.last_distance,
+            consecutive_failures: self.consecutive_failures,
+        }
+    }
+}
+
+impl Handler<MailboxProbe> for HcSr04 {
+    type Result = u64;
+
+    fn handle(&mut self, _msg: MailboxProbe, _ctx: &mut Self::Context) -> Self::Result {
+        METRICS.actor_messages.with_label_values(&["hc_sr04"]).get()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
 pub struct HcSr04Result {
     pub time: Duration,
     pub distance: f32,
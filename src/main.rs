@@ -1,26 +1,61 @@
-use std::{io, sync::Mutex};
+use std::{env, io, sync::Mutex, time::Duration};
 
-use actix::prelude::*;
+use actix::{prelude::*, registry::SystemRegistry};
 
-use actix_files::{Files, NamedFile};
 use actix_web::{
+    dev::{Server, ServerHandle},
     get, middleware,
-    web::{self, Data},
-    App, HttpRequest, HttpResponse, HttpServer, Responder,
+    web::{self, Data, ReqData},
+    App, HttpRequest, HttpResponse, HttpServer,
 };
 use actix_web_actors::ws;
+use log::{error, info, warn};
 
 use rppal::gpio::Gpio;
 
+use api::Jobs;
+use assets::AssetsConfig;
+use auth::{Access, AuthConfig};
+use collision_guard::{CollisionGuard, GuardConfig};
 use drive::Drive;
+use estop::{EStop, Watchdog};
 use hc_sr04::HcSr04;
+use mission::MissionLibrary;
+use mode::ModeManager;
+use mqtt::{Mqtt, MqttConfig};
+use recorder::Recorder;
+use rosbridge::RosBridge;
 use server::WebSocket;
+use sessions::Sessions;
+use shutdown::Shutdown;
+use telemetry::Telemetry;
 
+mod api;
+mod assets;
+mod auth;
+mod autopilot;
+mod binary_protocol;
+mod collision_guard;
 mod distance_scan;
 mod drive;
+mod estop;
 mod hc_sr04;
+mod metrics;
+mod mission;
+mod mode;
 mod movement_calibration;
+mod mqtt;
+mod path_following;
+mod protocol;
+mod recorder;
+mod rosbridge;
 mod server;
+mod sessions;
+mod shutdown;
+mod supervision;
+mod telemetry;
+mod tls;
+mod wall_following;
 
 const MOTOR0_FWD: u8 = 4;
 const MOTOR0_BWD: u8 = 17;
@@ -43,44 +78,203 @@ const MOTOR_PWM_FREQUENCY: f64 = 100.0;
 const DISTANCE_SENSOR_TRIG: u8 = 26;
 const DISTANCE_SENSOR_ECHO: u8 = 20;
 
-trait Device {
-    fn set_websocket_addr(&mut self, addr: Addr<WebSocket>);
-}
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(500);
+const CPU_TEMPERATURE_PATH: &str = "/sys/class/thermal/thermal_zone0/temp";
 
-#[get("/")]
-async fn index() -> impl Responder {
-    NamedFile::open_async("static/index.html").await.unwrap()
-}
+/// `host` or `host:port` of the MQTT broker, the bridge is disabled if unset
+const MQTT_BROKER_VAR: &str = "MQTT_BROKER";
+const MQTT_CLIENT_ID: &str = "mecanum-car";
+const MQTT_TOPIC_PREFIX: &str = "mecanum-car";
+
+/// Bearer token or password required by all routes, authentication is disabled if unset
+const AUTH_TOKEN_VAR: &str = "AUTH_TOKEN";
+/// Set to `1` or `true` to let unauthenticated clients watch the robot
+const READ_ONLY_ACCESS_VAR: &str = "READ_ONLY_ACCESS";
+/// PEM certificate chain and private key, the server uses TLS if both are set
+const TLS_CERT_VAR: &str = "TLS_CERT";
+const TLS_KEY_VAR: &str = "TLS_KEY";
+
+const ADDRESS: (&str, u16) = ("0.0.0.0", 7878);
+
+/// Access log format - like the default one, but with the path instead of the request line and
+/// without the referer, as both may contain the `token` query parameter
+const ACCESS_LOG_FORMAT: &str = r#"%a "%{method}xi %U" %s %b "%{User-Agent}i" %T"#;
+
+/// How long open connections may take to close on shutdown
+const SERVER_SHUTDOWN_TIMEOUT: u64 = 2; // in seconds
+/// The process exits if shutting down takes longer, even if the robot may not have stopped
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Directory whose files are served instead of the embedded web UI, for development
+const STATIC_DIR_VAR: &str = "STATIC_DIR";
+/// Name shown by the web UI
+const ROBOT_NAME_VAR: &str = "ROBOT_NAME";
+const DEFAULT_ROBOT_NAME: &str = "mecanum-car";
+
+/// Distances (in meters) below which the collision guard slows down and blocks motion toward the
+/// distance sensor, the guard is disabled unless both are set
+const COLLISION_GUARD_SLOW_VAR: &str = "COLLISION_GUARD_SLOW";
+const COLLISION_GUARD_STOP_VAR: &str = "COLLISION_GUARD_STOP";
+
+/// BCM number of the pin with the emergency stop button, the button is disabled if unset
+const ESTOP_BUTTON_PIN_VAR: &str = "ESTOP_BUTTON_PIN";
+
+/// Directory of the flight recorder session files
+const RECORDINGS_DIR_VAR: &str = "RECORDINGS_DIR";
+
+/// Directory of the mission files
+const MISSIONS_DIR_VAR: &str = "MISSIONS_DIR";
 
 /// Websocket handshake, start `WebSocket` actor
+#[allow(clippy::too_many_arguments)] // extractors
 #[get("/ws")]
 async fn ws_connect(
     req: HttpRequest,
     stream: web::Payload,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
     drive_data: Data<Mutex<Addr<Drive>>>,
     hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+    telemetry_data: Data<Mutex<Addr<Telemetry>>>,
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+    estop: Data<EStop>,
+    access: ReqData<Access>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    ws::start(WebSocket::new(drive_data, hc_sr04_data), &req, stream)
+    let read_only = *access == Access::ReadOnly;
+    ws::start(
+        WebSocket::new(
+            mode_manager_data,
+            drive_data,
+            hc_sr04_data,
+            sessions_data,
+            telemetry_data,
+            jobs_data,
+            estop,
+            read_only,
+        ),
+        &req,
+        stream,
+    )
 }
 
-async fn start_server(
+/// rosbridge v2 WebSocket handshake, start `RosBridge` actor
+#[get("/rosbridge")]
+async fn rosbridge_connect(
+    req: HttpRequest,
+    stream: web::Payload,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
     drive_data: Data<Mutex<Addr<Drive>>>,
     hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
-) -> Result<(), io::Error> {
-    HttpServer::new(move || {
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+    access: ReqData<Access>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let read_only = *access == Access::ReadOnly;
+    ws::start(
+        RosBridge::new(
+            mode_manager_data,
+            drive_data,
+            hc_sr04_data,
+            sessions_data,
+            read_only,
+        ),
+        &req,
+        stream,
+    )
+}
+
+/// Server settings which don't involve actors
+struct ServerConfig {
+    auth: Data<AuthConfig>,
+    assets: Data<AssetsConfig>,
+    /// Serves `https` and `wss` if set
+    tls: Option<rustls::ServerConfig>,
+}
+
+#[allow(clippy::too_many_arguments)] // shared state of the handlers
+fn start_server(
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    drive_data: Data<Mutex<Addr<Drive>>>,
+    hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+    telemetry_data: Data<Mutex<Addr<Telemetry>>>,
+    estop: Data<EStop>,
+    config: ServerConfig,
+) -> Result<Server, io::Error> {
+    let ServerConfig { auth, assets, tls } = config;
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(auth.clone())
+            .app_data(assets.clone())
+            .app_data(mode_manager_data.clone())
             .app_data(drive_data.clone())
             .app_data(hc_sr04_data.clone())
-            .service(index)
-            .service(Files::new("/static", "./static").show_files_listing())
+            .app_data(sessions_data.clone())
+            .app_data(jobs_data.clone())
+            .app_data(telemetry_data.clone())
+            .app_data(estop.clone())
+            .configure(assets::configure)
             .service(ws_connect)
-            .wrap(middleware::Logger::default())
+            .service(rosbridge_connect)
+            .configure(api::configure)
+            .service(metrics::serve_metrics)
+            .wrap(middleware::from_fn(auth::authorize))
+            .wrap(
+                middleware::Logger::new(ACCESS_LOG_FORMAT)
+                    .custom_request_replace("method", |req| req.method().to_string()),
+            )
     })
     .workers(2)
-    .bind(("0.0.0.0", 7878))?
-    .run()
-    .await?;
-    Ok(())
+    // Signals are handled by `shut_down`, which stops the robot first
+    .disable_signals()
+    .shutdown_timeout(SERVER_SHUTDOWN_TIMEOUT);
+    let server = match tls {
+        Some(tls) => server.bind_rustls_0_23(ADDRESS, tls)?,
+        None => server.bind(ADDRESS)?,
+    };
+    Ok(server.run())
+}
+
+/// Waits for `SIGINT` or `SIGTERM`, then stops all behaviours and the motors, closes the
+/// connections and stops the server, so that the robot doesn't keep driving after the program exits
+async fn shut_down(
+    drive_addr: Addr<Drive>,
+    sessions_addr: Addr<Sessions>,
+    jobs_addr: Addr<Jobs>,
+    server_handle: ServerHandle,
+) {
+    match shutdown::wait_for_signal().await {
+        Ok(signal) => info!("received {signal}, shutting down"),
+        Err(e) => {
+            error!("failed to listen for signals: {e}");
+            return;
+        }
+    }
+    shutdown::exit_after(SHUTDOWN_TIMEOUT);
+
+    // Behaviours first, so that they don't start the motors again
+    if let Err(e) = jobs_addr.send(Shutdown).await {
+        error!("failed to stop jobs: {e}");
+    }
+    if let Err(e) = sessions_addr.send(Shutdown).await {
+        error!("failed to close sessions: {e}");
+    }
+    if let Err(e) = drive_addr.send(Shutdown).await {
+        error!("failed to stop drive: {e}");
+    }
+    if let Err(e) = Recorder::from_registry().send(Shutdown).await {
+        error!("failed to flush recorder: {e}");
+    }
+
+    server_handle.stop(true).await;
+}
+
+/// Startup error for a TLS configuration with only one of the certificate and key
+fn missing_tls_var(var: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{TLS_CERT_VAR} and {TLS_KEY_VAR} must be set together, {var} is missing"),
+    )
 }
 
 #[actix_web::main]
@@ -89,9 +283,44 @@ async fn main() -> Result<(), io::Error> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     log_panics::init();
 
+    // Flight recorder initialization, before anything gets recorded
+    let recordings_dir = env::var_os(RECORDINGS_DIR_VAR).unwrap_or(recorder::DEFAULT_DIR.into());
+    SystemRegistry::set(Recorder::new(recordings_dir.into()).start());
+
     // Gpio initialization
     let gpio = Gpio::new().expect("Couldn't initialize GPIO");
 
+    // Sessions initialization, before the devices which report their restarts to it
+    let sessions_addr = Sessions::new().start();
+    let sessions_mutex = Mutex::new(sessions_addr.clone());
+    let sessions_data = Data::new(sessions_mutex);
+
+    // HcSr04 initialization
+    let mut hc_sr04 = HcSr04::new(
+        &gpio,
+        DISTANCE_SENSOR_TRIG,
+        DISTANCE_SENSOR_ECHO,
+        25.0,
+        sessions_addr.clone().recipient(),
+    )
+    .expect("Couldn't initialize HC-SR04");
+
+    // For some reason without this line the distance measurement doesn't work
+    println!(
+        "{}",
+        hc_sr04
+            .measure_distance()
+            .expect("Distance measurement failed")
+            .distance
+    );
+
+    // Measurements block their thread, so the sensor gets its own, instead of stalling the drive,
+    // the mode manager and the watchdog
+    let hc_sr04_addr = Supervisor::start_in_arbiter(&Arbiter::new().handle(), move |_| hc_sr04);
+    metrics::sample_mailbox("hc_sr04", hc_sr04_addr.clone());
+    let hc_sr04_mutex = Mutex::new(hc_sr04_addr.clone());
+    let hc_sr04_data = Data::new(hc_sr04_mutex);
+
     // Drive initialization
     let drive = Drive::new(
         &gpio,
@@ -102,33 +331,158 @@ async fn main() -> Result<(), io::Error> {
             (MOTOR3_FWD, MOTOR3_BWD, MOTOR3_PWM),
         ],
         MOTOR_PWM_FREQUENCY,
-        None,
+        sessions_addr.clone(),
+        hc_sr04_addr.clone(),
     )
     .expect("Couldn't initialize drive");
     drive.list_motors();
+    let brake = drive.brake();
 
-    let drive_addr = drive.start();
-    let drive_mutex = Mutex::new(drive_addr);
+    let drive_addr = Supervisor::start(move |_| drive);
+    metrics::sample_mailbox("drive", drive_addr.clone());
+    let drive_mutex = Mutex::new(drive_addr.clone());
     let drive_data = Data::new(drive_mutex);
 
-    // HcSr04 initialization
-    let mut hc_sr04 = HcSr04::new(&gpio, DISTANCE_SENSOR_TRIG, DISTANCE_SENSOR_ECHO, 25.0)
-        .expect("Couldn't initialize HC-SR04");
+    // Collision guard initialization, drive commands go through it if it's enabled
+    let commands = match (
+        env::var(COLLISION_GUARD_SLOW_VAR),
+        env::var(COLLISION_GUARD_STOP_VAR),
+    ) {
+        (Ok(slow_distance), Ok(stop_distance)) => {
+            let config = GuardConfig::parse(&slow_distance, &stop_distance)
+                .expect("Invalid collision guard distances");
+            CollisionGuard::new(
+                config,
+                drive_addr.clone(),
+                hc_sr04_addr.clone(),
+                sessions_addr.clone(),
+            )
+            .start()
+            .recipient()
+        }
+        _ => drive_addr.clone().recipient(),
+    };
 
-    // For some reason without this line the distance measurement doesn't work
-    println!(
-        "{}",
-        hc_sr04
-            .measure_distance()
-            .expect("Distance measurement failed")
-            .distance
+    // Mode manager initialization, all drive commands go through it
+    let mode_manager_addr = ModeManager::new(
+        drive_addr.clone(),
+        commands,
+        brake.clone(),
+        sessions_addr.clone(),
+    )
+    .start();
+    let mode_manager_mutex = Mutex::new(mode_manager_addr.clone());
+    let mode_manager_data = Data::new(mode_manager_mutex);
+
+    // Emergency stop initialization
+    let estop = EStop::new(brake, mode_manager_addr.clone());
+    // Watched until the pin is dropped when the program exits
+    let _estop_button = env::var(ESTOP_BUTTON_PIN_VAR).ok().map(|pin| {
+        let pin = pin.parse().expect("Invalid emergency stop button pin");
+        estop::watch_button(&gpio, pin, estop.clone())
+            .expect("Couldn't initialize emergency stop button")
+    });
+
+    // Telemetry initialization
+    let telemetry_addr = Telemetry::new(
+        drive_addr.clone(),
+        hc_sr04_addr.clone(),
+        TELEMETRY_INTERVAL,
+        CPU_TEMPERATURE_PATH.into(),
+    )
+    .start();
+    let telemetry_mutex = Mutex::new(telemetry_addr.clone());
+    let telemetry_data = Data::new(telemetry_mutex);
+
+    // Watchdog initialization, it needs the running behaviours from telemetry
+    let watchdog = Watchdog::new(
+        estop.clone(),
+        drive_addr.clone(),
+        hc_sr04_addr,
+        mode_manager_addr,
+        telemetry_addr.clone(),
     );
+    // On its own thread, so that it keeps checking while the other actors are stuck
+    Watchdog::start_in_arbiter(&Arbiter::new().handle(), move |_| watchdog);
 
-    let hc_sr04_addr = hc_sr04.start();
-    let hc_sr04_mutex = Mutex::new(hc_sr04_addr);
-    let hc_sr04_data = Data::new(hc_sr04_mutex);
+    // Jobs initialization
+    let missions_dir = env::var_os(MISSIONS_DIR_VAR).unwrap_or(mission::DEFAULT_DIR.into());
+    let jobs_addr = Jobs::new(
+        mode_manager_data.clone(),
+        drive_addr.clone(),
+        hc_sr04_data.clone(),
+        sessions_addr.clone(),
+        telemetry_addr.clone(),
+        MissionLibrary::new(missions_dir.into()),
+    )
+    .start();
+    let jobs_mutex = Mutex::new(jobs_addr.clone());
+    let jobs_data = Data::new(jobs_mutex);
+
+    // MQTT initialization
+    if let Ok(broker) = env::var(MQTT_BROKER_VAR) {
+        let config = MqttConfig::parse(&broker, MQTT_CLIENT_ID, MQTT_TOPIC_PREFIX)
+            .expect("Invalid MQTT broker");
+        Mqtt::new(
+            config,
+            mode_manager_data.clone(),
+            sessions_data.clone(),
+            jobs_data.clone(),
+            telemetry_addr,
+        )
+        .start();
+    }
+
+    // Authentication and TLS initialization
+    let auth_config = AuthConfig {
+        token: env::var(AUTH_TOKEN_VAR)
+            .ok()
+            .filter(|token| !token.is_empty()),
+        read_only_access: env::var(READ_ONLY_ACCESS_VAR)
+            .is_ok_and(|value| matches!(value.as_str(), "1" | "true")),
+    };
+    if auth_config.token.is_none() {
+        warn!("{AUTH_TOKEN_VAR} is not set, anyone on the network can control the robot");
+    }
+    let tls_config = match (env::var(TLS_CERT_VAR), env::var(TLS_KEY_VAR)) {
+        (Ok(cert_path), Ok(key_path)) => {
+            Some(tls::load_config(cert_path.as_ref(), key_path.as_ref())?)
+        }
+        // Don't silently serve plain HTTP when TLS was asked for
+        (Ok(_), Err(_)) => return Err(missing_tls_var(TLS_KEY_VAR)),
+        (Err(_), Ok(_)) => return Err(missing_tls_var(TLS_CERT_VAR)),
+        (Err(_), Err(_)) => None,
+    };
+
+    // Web UI initialization
+    let assets_config = AssetsConfig {
+        override_dir: env::var_os(STATIC_DIR_VAR).map(Into::into),
+        robot_name: env::var(ROBOT_NAME_VAR).unwrap_or(DEFAULT_ROBOT_NAME.to_string()),
+    };
 
     // Start the server
-    start_server(drive_data, hc_sr04_data).await?;
+    let server = start_server(
+        mode_manager_data,
+        drive_data,
+        hc_sr04_data,
+        sessions_data,
+        jobs_data,
+        telemetry_data,
+        Data::new(estop),
+        ServerConfig {
+            auth: Data::new(auth_config),
+            assets: Data::new(assets_config),
+            tls: tls_config,
+        },
+    )?;
+    actix_web::rt::spawn(shut_down(
+        drive_addr,
+        sessions_addr,
+        jobs_addr,
+        server.handle(),
+    ));
+    server.await?;
+    info!("shut down");
+    log::logger().flush();
     Ok(())
 }
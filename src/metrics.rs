@@ -0,0 +1,149 @@
+use std::{sync::LazyLock, time::Duration};
+
+use actix::{dev::ToEnvelope, prelude::*};
+use actix_web::{error::ErrorInternalServerError, get, HttpResponse};
+use prometheus::{
+    exponential_buckets, CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+/// How often the mailbox depth of each actor is sampled
+const MAILBOX_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prometheus metrics of the robot, served by `GET /metrics`
+pub struct Metrics {
+    registry: Registry,
+    /// Received `DriveMessage`s, by variant
+    pub drive_commands: IntCounterVec,
+    /// Seconds the wheels spent in each motion, `Velocity` when driven with
+    /// `DriveMessage::Velocity`
+    pub motion_seconds: CounterVec,
+    /// HC-SR04 measurement duration, by status
+    pub hc_sr04_measurements: HistogramVec,
+    /// Open WebSocket connections, by endpoint
+    pub websocket_connections: IntGaugeVec,
+    /// Connections closed because the client stopped answering pings, by endpoint
+    pub heartbeat_timeouts: IntCounterVec,
+    /// Messages handled by each actor
+    pub actor_messages: IntCounterVec,
+    /// Messages waiting in each actor's mailbox, see `sample_mailbox`
+    pub mailbox_depth: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("mecanum".to_string()), None)?;
+        let metrics = Self {
+            drive_commands: IntCounterVec::new(
+                Opts::new("drive_commands_total", "Received drive commands"),
+                &["variant"],
+            )?,
+            motion_seconds: CounterVec::new(
+                Opts::new("motion_seconds_total", "Time spent in each motion"),
+                &["motion"],
+            )?,
+            hc_sr04_measurements: HistogramVec::new(
+                HistogramOpts::new(
+                    "hc_sr04_measurement_seconds",
+                    "Duration of HC-SR04 distance measurements",
+                )
+                .buckets(exponential_buckets(0.005, 2., 10)?),
+                &["status"],
+            )?,
+            websocket_connections: IntGaugeVec::new(
+                Opts::new("websocket_connections", "Open WebSocket connections"),
+                &["endpoint"],
+            )?,
+            heartbeat_timeouts: IntCounterVec::new(
+                Opts::new(
+                    "websocket_heartbeat_timeouts_total",
+                    "WebSocket connections closed after a heartbeat timeout",
+                ),
+                &["endpoint"],
+            )?,
+            actor_messages: IntCounterVec::new(
+                Opts::new("actor_messages_total", "Messages handled by each actor"),
+                &["actor"],
+            )?,
+            mailbox_depth: IntGaugeVec::new(
+                Opts::new(
+                    "actor_mailbox_depth",
+                    "Messages waiting in each actor's mailbox",
+                ),
+                &["actor"],
+            )?,
+            registry,
+        };
+        metrics
+            .registry
+            .register(Box::new(metrics.drive_commands.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.motion_seconds.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.hc_sr04_measurements.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.websocket_connections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.heartbeat_timeouts.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.actor_messages.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.mailbox_depth.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Counts a message handled by `actor`
+    pub fn handled(&self, actor: &str) {
+        self.actor_messages.with_label_values(&[actor]).inc();
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics::new().expect("Invalid metrics"));
+
+/// Answered with the number of messages the actor handled so far, see `sample_mailbox`
+#[derive(Debug, Message)]
+#[rtype(result = "u64")]
+pub struct MailboxProbe;
+
+/// Periodically measures how many messages are waiting in the mailbox of `addr`. actix doesn't
+/// expose the mailbox length, so a `MailboxProbe` is queued behind them instead: the messages the
+/// actor handled before answering it were the ones waiting when it was sent
+pub fn sample_mailbox<A>(actor: &'static str, addr: Addr<A>)
+where
+    A: Actor + Handler<MailboxProbe>,
+    A::Context: ToEnvelope<A, MailboxProbe>,
+{
+    let handled = METRICS.actor_messages.with_label_values(&[actor]);
+    let depth = METRICS.mailbox_depth.with_label_values(&[actor]);
+    actix::spawn(async move {
+        let mut interval = actix::clock::interval(MAILBOX_SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let before = handled.get();
+            match addr.send(MailboxProbe).await {
+                Ok(after) => depth.set(after.saturating_sub(before) as i64),
+                // The actor stopped
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// Metrics in the Prometheus text format
+#[get("/metrics")]
+pub async fn serve_metrics() -> Result<HttpResponse, actix_web::Error> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
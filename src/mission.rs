@@ -0,0 +1,658 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix::{fut::wrap_future, prelude::*};
+use actix_web::web::Data;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    drive::{Drive, DriveMessage, Motion, MotionFinished, WatchMotion},
+    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response},
+    mode::{Command, Mode, ModeManager, Preempted, Release},
+    protocol::{ErrorCode, SocketError},
+    telemetry::{Behaviour, BehaviourMessage, Telemetry},
+};
+
+/// Directory of the mission files, unless configured otherwise
+pub const DEFAULT_DIR: &str = "missions";
+
+/// Longest `Wait` step
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+
+/// One step of a mission, e.g. `{"step": "Drive", "variant": "Move", ...}`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "step")]
+pub enum Step {
+    /// `Move`, `Rotate` and `ApproachDistance` complete once the drive has finished them, other
+    /// commands right away, so an `Enable` is usually followed by a `Wait`
+    Drive(DriveMessage),
+    /// The reading is reported in the progress
+    MeasureDistance,
+    Wait {
+        seconds: f64,
+    },
+}
+
+impl Step {
+    /// Rejects steps which the drive would refuse or which would crash the runner, e.g. a `Move`
+    /// with a rotation or a `Wait` too long to be timed
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            Step::Drive(message) => message
+                .validate()
+                .map_err(|e| format!("invalid step {self:?}: {e}")),
+            Step::Wait { seconds } if !(0. ..=MAX_WAIT.as_secs_f64()).contains(&seconds) => {
+                Err(format!(
+                    "invalid step {self:?}: waits have to be within 0..={} s",
+                    MAX_WAIT.as_secs()
+                ))
+            }
+            Step::MeasureDistance | Step::Wait { .. } => Ok(()),
+        }
+    }
+
+    /// The part of the step left after it ran for `elapsed`, estimated for timed moves
+    fn remaining(self, elapsed: Duration) -> Self {
+        // Steps whose time can't be estimated are invalid, nothing of them is left
+        let left = |total: Result<Duration, _>| {
+            total.map_or(0., |total: Duration| {
+                1. - (elapsed.as_secs_f64() / total.as_secs_f64()).min(1.)
+            })
+        };
+        match self {
+            Step::Drive(DriveMessage::Move {
+                motion,
+                speed,
+                distance,
+            }) => Step::Drive(DriveMessage::Move {
+                motion,
+                speed,
+                distance: distance * left(speed.get_travel_time(distance)),
+            }),
+            Step::Drive(DriveMessage::Rotate {
+                motion,
+                speed,
+                angle,
+            }) => Step::Drive(DriveMessage::Rotate {
+                motion,
+                speed,
+                angle: angle * left(speed.get_rotation_time(angle)),
+            }),
+            Step::Wait { seconds } => Step::Wait {
+                seconds: (seconds - elapsed.as_secs_f64()).max(0.),
+            },
+            // Approaches are started again, they measure what's left anyway
+            step => step,
+        }
+    }
+}
+
+/// Contents of a mission file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Mission {
+    pub steps: Vec<Step>,
+}
+
+impl Mission {
+    pub fn validate(&self) -> Result<(), SocketError> {
+        if self.steps.is_empty() {
+            return Err(SocketError::new(
+                ErrorCode::InvalidMessage,
+                "mission has no steps",
+            ));
+        }
+        self.steps
+            .iter()
+            .try_for_each(Step::validate)
+            .map_err(|e| SocketError::new(ErrorCode::InvalidMessage, e))
+    }
+}
+
+/// Mission files in a directory, `<name>.json` or `<name>.toml`
+#[derive(Clone, Debug)]
+pub struct MissionLibrary {
+    dir: PathBuf,
+}
+
+impl MissionLibrary {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Only plain names are accepted, so that files outside the directory can't be read
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    /// Names of all mission files, sorted
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let is_mission = path
+                .extension()
+                .is_some_and(|ext| ext == "json" || ext == "toml");
+            let name = path.file_stem().and_then(|name| name.to_str());
+            if let Some(name) = name.filter(|name| is_mission && Self::is_valid_name(name)) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Reads and validates the mission file `name`
+    pub fn load(&self, name: &str) -> Result<Mission, SocketError> {
+        let not_found = || SocketError::new(ErrorCode::NotFound, format!("no mission {name}"));
+        if !Self::is_valid_name(name) {
+            return Err(not_found());
+        }
+        let path = |ext| self.dir.join(format!("{name}.{ext}"));
+        let (path, text) = [path("json"), path("toml")]
+            .into_iter()
+            .find_map(|path| fs::read_to_string(&path).ok().map(|text| (path, text)))
+            .ok_or_else(not_found)?;
+        let mission = Self::parse(&path, &text).map_err(|e| {
+            SocketError::new(
+                ErrorCode::InvalidMessage,
+                format!("invalid mission file {}: {e}", path.display()),
+            )
+        })?;
+        mission.validate()?;
+        Ok(mission)
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<Mission, String> {
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(text).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_str(text).map_err(|e| e.to_string())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissionStatus {
+    /// Waiting for `Start`, not reported
+    Idle,
+    Running,
+    Paused,
+    Finished,
+    Aborted,
+    /// A step failed, e.g. an approach lost the distance readings
+    Failed,
+}
+
+/// Executes the steps of a mission strictly one after another, each starting when the previous
+/// one completed. Progress is reported to `progress` after every change
+pub struct MissionRunner {
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    drive_addr: Addr<Drive>,
+    hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    telemetry_addr: Addr<Telemetry>,
+    progress: Recipient<MissionProgress>,
+    /// Name of the mission file, `None` for steps sent by a client
+    name: Option<String>,
+    steps: Vec<Step>,
+    /// Index of the current step, `steps.len()` once all of them completed
+    current: usize,
+    status: MissionStatus,
+    /// Changes whenever a step is (re)started or interrupted, so that completions of steps which
+    /// were skipped or paused are ignored
+    attempt: usize,
+    /// When the current step was started, to estimate what's left of it when paused
+    step_started: Instant,
+    /// Completes the current `Wait` step
+    timer: Option<SpawnHandle>,
+    /// `Enable` or `Velocity` command the robot keeps driving with, sent again on resume
+    continuing: Option<DriveMessage>,
+    /// Readings of the `MeasureDistance` steps (in meters)
+    measurements: Vec<f32>,
+}
+
+impl MissionRunner {
+    pub fn new(
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+        drive_addr: Addr<Drive>,
+        hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        telemetry_addr: Addr<Telemetry>,
+        progress: Recipient<MissionProgress>,
+        name: Option<String>,
+        mission: Mission,
+    ) -> Self {
+        Self {
+            mode_manager_data,
+            drive_addr,
+            hc_sr04_data,
+            telemetry_addr,
+            progress,
+            name,
+            steps: mission.steps,
+            current: 0,
+            status: MissionStatus::Idle,
+            attempt: 0,
+            step_started: Instant::now(),
+            timer: None,
+            continuing: None,
+            measurements: Vec::new(),
+        }
+    }
+
+    /// Logs a change and reports the progress
+    fn report(&self, description: String) {
+        info!("{description}");
+        self.progress.do_send(MissionProgress {
+            name: self.name.clone(),
+            status: self.status,
+            step: self.current,
+            steps: self.steps.len(),
+            description,
+            measurements: self.measurements.clone(),
+        });
+    }
+
+    /// Sends a drive command on behalf of the `Mission` mode, returns the request
+    fn send_command(
+        &self,
+        ctx: &mut <Self as Actor>::Context,
+        message: DriveMessage,
+    ) -> Result<Request<ModeManager, Command>, SocketError> {
+        let command = Command::behaviour(Mode::Mission, ctx.address().recipient(), message);
+        Ok(self.mode_manager_data.lock()?.send(command))
+    }
+
+    /// Invalidates completions of the current step and cancels its timer
+    fn interrupt(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.attempt += 1;
+        if let Some(timer) = self.timer.take() {
+            ctx.cancel_future(timer);
+        }
+    }
+
+    /// Starts the current step, or finishes the mission if all steps completed
+    fn run_step(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.interrupt(ctx);
+        let Some(step) = self.steps.get(self.current).copied() else {
+            self.finish(ctx, MissionStatus::Finished, "Mission finished".to_string());
+            return;
+        };
+        self.step_started = Instant::now();
+        self.report(format!(
+            "Step {}/{}: {step:?}",
+            self.current + 1,
+            self.steps.len()
+        ));
+        match step {
+            Step::Drive(message) => self.drive(ctx, message),
+            Step::MeasureDistance => {
+                let message = HcSr04Message::Single(ctx.address().recipient());
+                let sent = self
+                    .hc_sr04_data
+                    .lock()
+                    .map(|addr| addr.do_send(message))
+                    .map_err(SocketError::from);
+                if let Err(e) = sent {
+                    self.fail(ctx, format!("Distance measurement failed: {e}"));
+                }
+            }
+            Step::Wait { seconds } => {
+                let timer = ctx.run_later(Duration::from_secs_f64(seconds), |act, ctx| {
+                    act.timer = None;
+                    act.next_step(ctx);
+                });
+                self.timer = Some(timer);
+            }
+        }
+    }
+
+    /// Sends the command of a `Drive` step, then waits for timed moves and approaches to finish
+    fn drive(&mut self, ctx: &mut <Self as Actor>::Context, message: DriveMessage) {
+        self.continuing = match message {
+            DriveMessage::Enable { motion, .. } if motion != Motion::Stop => Some(message),
+            DriveMessage::Velocity { .. } => Some(message),
+            _ => None,
+        };
+        let request = match self.send_command(ctx, message) {
+            Ok(request) => request,
+            Err(e) => return self.fail(ctx, format!("{} failed: {e}", message.variant())),
+        };
+        let attempt = self.attempt;
+        ctx.spawn(
+            wrap_future(request).map(move |result, act: &mut Self, ctx| {
+                if act.attempt != attempt {
+                    return;
+                }
+                if let Err(e) = result.map_err(SocketError::from).and_then(|result| result) {
+                    act.fail(ctx, format!("{} failed: {e}", message.variant()));
+                    return;
+                }
+                match message {
+                    DriveMessage::Move { .. }
+                    | DriveMessage::Rotate { .. }
+                    | DriveMessage::ApproachDistance { .. } => {
+                        act.drive_addr.do_send(WatchMotion {
+                            id: attempt,
+                            recipient: ctx.address().recipient(),
+                        });
+                    }
+                    _ => act.next_step(ctx),
+                }
+            }),
+        );
+    }
+
+    fn next_step(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.current += 1;
+        self.run_step(ctx);
+    }
+
+    fn fail(&mut self, ctx: &mut <Self as Actor>::Context, description: String) {
+        error!("{description}");
+        self.finish(ctx, MissionStatus::Failed, description);
+    }
+
+    /// Reports the outcome and stops, which stops the robot. A mission which never started, e.g.
+    /// because it didn't get its mode, isn't reported
+    fn finish(
+        &mut self,
+        ctx: &mut <Self as Actor>::Context,
+        status: MissionStatus,
+        description: String,
+    ) {
+        self.interrupt(ctx);
+        if self.status != MissionStatus::Idle {
+            self.status = status;
+            self.report(description);
+        }
+        ctx.stop();
+    }
+
+    /// Stops the robot, remembering what's left of the current step
+    fn pause(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.interrupt(ctx);
+        if let Some(step) = self.steps.get_mut(self.current) {
+            *step = step.remaining(self.step_started.elapsed());
+        }
+        self.status = MissionStatus::Paused;
+        if let Ok(request) = self.send_command(ctx, DriveMessage::Disable) {
+            ctx.spawn(wrap_future(request).map(|_, _, _| ()));
+        }
+        self.report(format!("Mission paused at step {}", self.current + 1));
+    }
+
+    /// Continues with the rest of the current step, after restoring the motion it was started in
+    fn resume(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.status = MissionStatus::Running;
+        if let Some(message) = self.continuing {
+            if let Ok(request) = self.send_command(ctx, message) {
+                ctx.spawn(wrap_future(request).map(|_, _, _| ()));
+            }
+        }
+        self.run_step(ctx);
+    }
+
+    /// Moves on to the next step, stopping a timed move or approach of the current one
+    fn skip(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.interrupt(ctx);
+        if let Some(Step::Drive(
+            DriveMessage::Move { .. }
+            | DriveMessage::Rotate { .. }
+            | DriveMessage::ApproachDistance { .. },
+        )) = self.steps.get(self.current)
+        {
+            if let Ok(request) = self.send_command(ctx, DriveMessage::Disable) {
+                ctx.spawn(wrap_future(request).map(|_, _, _| ()));
+            }
+        }
+        info!("skipping step {}", self.current + 1);
+        match self.status {
+            MissionStatus::Running => self.next_step(ctx),
+            _ => {
+                self.current += 1;
+                self.report(format!("Step {} skipped, mission paused", self.current));
+            }
+        }
+    }
+}
+
+impl Actor for MissionRunner {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Started(Behaviour::Mission));
+        info!("actor started");
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Stopped(Behaviour::Mission));
+        // Stops the robot
+        match self.mode_manager_data.lock() {
+            Ok(mode_manager_addr) => mode_manager_addr.do_send(Release(ctx.address().recipient())),
+            Err(e) => error!("{e:?}"),
+        }
+        info!("actor stopped");
+    }
+}
+
+/// Runs the mission file `name` or the given `steps`, and controls the running mission
+#[derive(Clone, Debug, Deserialize, Serialize, Message)]
+#[rtype(result = "()")]
+#[serde(tag = "variant")]
+pub enum MissionMessage {
+    Start {
+        name: String,
+    },
+    Run {
+        steps: Vec<Step>,
+    },
+    /// Stops the robot, `Resume` continues with the rest of the interrupted step
+    Pause,
+    Resume,
+    /// Continues with the next step, also while paused
+    Skip,
+    Abort,
+}
+
+impl Handler<MissionMessage> for MissionRunner {
+    type Result = ();
+
+    fn handle(&mut self, msg: MissionMessage, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        match (msg, self.status) {
+            // The steps were given to `new`
+            (MissionMessage::Start { .. } | MissionMessage::Run { .. }, MissionStatus::Idle) => {
+                self.status = MissionStatus::Running;
+                self.run_step(ctx);
+            }
+            (MissionMessage::Pause, MissionStatus::Running) => self.pause(ctx),
+            (MissionMessage::Resume, MissionStatus::Paused) => self.resume(ctx),
+            (MissionMessage::Skip, MissionStatus::Running | MissionStatus::Paused) => {
+                self.skip(ctx)
+            }
+            (MissionMessage::Abort, _) => {
+                self.finish(ctx, MissionStatus::Aborted, "Mission aborted".to_string())
+            }
+            (msg, status) => info!("ignoring {msg:?} while {status:?}"),
+        }
+    }
+}
+
+impl Handler<Preempted> for MissionRunner {
+    type Result = ();
+
+    fn handle(&mut self, msg: Preempted, ctx: &mut Self::Context) -> Self::Result {
+        self.finish(
+            ctx,
+            MissionStatus::Aborted,
+            format!("Mission preempted by {:?} mode", msg.0),
+        );
+    }
+}
+
+impl Handler<MotionFinished> for MissionRunner {
+    type Result = ();
+
+    fn handle(&mut self, msg: MotionFinished, ctx: &mut Self::Context) -> Self::Result {
+        if msg.id != self.attempt || self.status != MissionStatus::Running {
+            return;
+        }
+        match msg.result {
+            Ok(()) => self.next_step(ctx),
+            Err(e) => self.fail(ctx, format!("Step {} failed: {e}", self.current + 1)),
+        }
+    }
+}
+
+impl Handler<HcSr04Response> for MissionRunner {
+    type Result = ();
+
+    fn handle(&mut self, msg: HcSr04Response, ctx: &mut Self::Context) -> Self::Result {
+        // Readings requested before a pause or skip may still arrive
+        if self.status != MissionStatus::Running
+            || self.steps.get(self.current) != Some(&Step::MeasureDistance)
+        {
+            return;
+        }
+        match msg {
+            HcSr04Response::Ok(HcSr04Measurement::Single(result)) => {
+                self.measurements.push(result.distance);
+                self.report(format!("Measured {} m", result.distance));
+                self.next_step(ctx);
+            }
+            HcSr04Response::Ok(_) => {}
+            HcSr04Response::Err(e) => self.fail(ctx, format!("Distance measurement failed: {e}")),
+        }
+    }
+}
+
+/// State of a mission, sent after every change
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+pub struct MissionProgress {
+    pub name: Option<String>,
+    pub status: MissionStatus,
+    /// Index of the current step, equal to `steps` once all of them completed
+    pub step: usize,
+    pub steps: usize,
+    pub description: String,
+    /// Readings of the `MeasureDistance` steps so far (in meters)
+    pub measurements: Vec<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drive::Speed;
+
+    fn forward(speed: Speed, distance: f64) -> Step {
+        Step::Drive(DriveMessage::Move {
+            motion: Motion::Forward,
+            speed,
+            distance,
+        })
+    }
+
+    fn validate(steps: Vec<Step>) -> Result<(), ErrorCode> {
+        Mission { steps }.validate().map_err(|e| e.code)
+    }
+
+    #[test]
+    fn validate_accepts_executable_missions() {
+        let steps = vec![
+            forward(Speed::Medium, 0.5),
+            Step::Drive(DriveMessage::Rotate {
+                motion: Motion::LeftRot,
+                speed: Speed::High,
+                angle: 90.,
+            }),
+            Step::MeasureDistance,
+            Step::Wait { seconds: 1.5 },
+            Step::Drive(DriveMessage::Disable),
+        ];
+        assert_eq!(validate(steps), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_steps_which_can_not_be_executed() {
+        assert_eq!(validate(vec![]), Err(ErrorCode::InvalidMessage));
+        let invalid = [
+            // Too slow to move, the travel time can't be estimated
+            forward(Speed::Manual(0.1), 0.5),
+            forward(Speed::Manual(1.5), 0.5),
+            forward(Speed::High, -1.),
+            forward(Speed::High, f64::INFINITY),
+            // Would take longer than the drive allows
+            forward(Speed::Low, 1e300),
+            Step::Drive(DriveMessage::Rotate {
+                motion: Motion::Forward,
+                speed: Speed::High,
+                angle: 90.,
+            }),
+            Step::Wait { seconds: -1. },
+            Step::Wait { seconds: f64::NAN },
+            Step::Wait { seconds: 1e300 },
+        ];
+        for step in invalid {
+            assert_eq!(
+                validate(vec![Step::MeasureDistance, step]),
+                Err(ErrorCode::InvalidMessage),
+                "{step:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn remaining_scales_timed_moves() {
+        let step = forward(Speed::High, 1.);
+        let total = Speed::High.get_travel_time(1.).unwrap();
+        assert_eq!(step.remaining(Duration::ZERO), step);
+        let Step::Drive(DriveMessage::Move { distance, .. }) = step.remaining(total / 4) else {
+            panic!("expected a move");
+        };
+        assert!((distance - 0.75).abs() < 1e-6, "{distance}");
+        assert_eq!(step.remaining(total * 2), forward(Speed::High, 0.));
+    }
+
+    #[test]
+    fn remaining_counts_down_waits() {
+        let step = Step::Wait { seconds: 10. };
+        assert_eq!(
+            step.remaining(Duration::from_secs(4)),
+            Step::Wait { seconds: 6. }
+        );
+        assert_eq!(
+            step.remaining(Duration::from_secs(20)),
+            Step::Wait { seconds: 0. }
+        );
+    }
+
+    #[test]
+    fn remaining_restarts_other_steps() {
+        let approach = Step::Drive(DriveMessage::ApproachDistance {
+            target: 0.2,
+            speed: Speed::Low,
+        });
+        assert_eq!(approach.remaining(Duration::from_secs(5)), approach);
+        assert_eq!(
+            Step::MeasureDistance.remaining(Duration::from_secs(5)),
+            Step::MeasureDistance
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use actix::prelude::*;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{
+    drive::{Brake, Drive, DriveMessage, Halt},
+    estop::{EmergencyStop, EmergencyStopped, ResetEmergencyStop},
+    protocol::{ErrorCode, SocketError},
+    sessions::Sessions,
+};
+
+/// What is currently allowed to drive the robot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, MessageResponse, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Nothing is driving the robot
+    Idle,
+    /// Driven by commands of clients
+    Manual,
+    Calibrating,
+    Scanning,
+    /// Driven by the autopilot or the wall follower
+    Autonomous,
+    /// Running a sequence of drive commands, see `MissionRunner`
+    Mission,
+    /// Latched by the emergency stop, nothing can drive the robot until it's reset
+    EStopped,
+}
+
+impl Mode {
+    /// A behaviour holding a mode can only be preempted by a mode of higher priority, so manual
+    /// commands override behaviours, but behaviours can't override each other. Nothing overrides
+    /// the emergency stop
+    fn priority(self) -> u8 {
+        match self {
+            Mode::Idle => 0,
+            Mode::Calibrating | Mode::Scanning | Mode::Autonomous | Mode::Mission => 1,
+            Mode::Manual => 2,
+            Mode::EStopped => 3,
+        }
+    }
+}
+
+/// Owns drive access: all drive commands go through it and are rejected unless they belong to the
+/// current mode. Behaviours acquire their mode before they start, manual commands take over from
+/// any behaviour
+pub struct ModeManager {
+    drive_addr: Addr<Drive>,
+    /// Drive commands are sent to it, `Drive` or the `CollisionGuard` in front of it
+    commands: Recipient<DriveMessage>,
+    brake: Brake,
+    sessions_addr: Addr<Sessions>,
+    mode: Mode,
+    /// Behaviour holding `mode`, told to stop when it's preempted
+    owner: Option<Recipient<Preempted>>,
+}
+
+impl ModeManager {
+    pub fn new(
+        drive_addr: Addr<Drive>,
+        commands: Recipient<DriveMessage>,
+        brake: Brake,
+        sessions_addr: Addr<Sessions>,
+    ) -> Self {
+        Self {
+            drive_addr,
+            commands,
+            brake,
+            sessions_addr,
+            mode: Mode::Idle,
+            owner: None,
+        }
+    }
+
+    /// Checks whether `owner` may enter `mode`, preempting the current owner if `mode` has a higher
+    /// priority
+    fn take_over(
+        &self,
+        mode: Mode,
+        owner: Option<&Recipient<Preempted>>,
+    ) -> Result<(), SocketError> {
+        match self.mode {
+            // Nothing owns the emergency stop, it's only left by resetting it
+            Mode::EStopped => {
+                return Err(SocketError::new(
+                    ErrorCode::EmergencyStopped,
+                    "emergency stop is engaged, reset it first",
+                ))
+            }
+            Mode::Idle => return Ok(()),
+            _ => (),
+        }
+        // Owners which stopped without releasing their mode don't hold it anymore. The manual mode
+        // has no owner, it's shared by all clients until they stop the robot
+        let current = self.owner.as_ref().filter(|owner| owner.connected());
+        if self.mode != Mode::Manual && current.is_none() {
+            return Ok(());
+        }
+        // The owner acquiring its mode again, or another manual command
+        if (owner.is_some() && owner == current) || (owner.is_none() && mode == self.mode) {
+            return Ok(());
+        }
+        if mode.priority() <= self.mode.priority() {
+            return Err(SocketError::new(
+                ErrorCode::ModeConflict,
+                format!("robot is in {:?} mode, stop it first", self.mode),
+            ));
+        }
+        info!("{mode:?} mode preempts {:?} mode", self.mode);
+        if let Some(current) = current {
+            current.do_send(Preempted(mode));
+        }
+        Ok(())
+    }
+
+    /// Switches to `mode`, broadcasting the transition
+    fn set_mode(&mut self, mode: Mode, owner: Option<Recipient<Preempted>>) {
+        self.owner = owner;
+        if mode == self.mode {
+            return;
+        }
+        let previous = std::mem::replace(&mut self.mode, mode);
+        info!("mode changed from {previous:?} to {mode:?}");
+        self.sessions_addr.do_send(ModeChanged { mode, previous });
+    }
+}
+
+impl Actor for ModeManager {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(1024);
+        info!("actor started");
+    }
+}
+
+/// Sent to a behaviour when it loses its mode to one of higher priority, it should stop
+#[derive(Clone, Copy, Debug, Message)]
+#[rtype(result = "()")]
+pub struct Preempted(pub Mode);
+
+/// Acquires `mode` for the behaviour `owner`, before it starts driving
+#[derive(Debug, Message)]
+#[rtype(result = "Result<(), SocketError>")]
+pub struct Acquire {
+    pub mode: Mode,
+    pub owner: Recipient<Preempted>,
+}
+
+impl Handler<Acquire> for ModeManager {
+    type Result = Result<(), SocketError>;
+
+    fn handle(&mut self, msg: Acquire, _ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        self.take_over(msg.mode, Some(&msg.owner))?;
+        self.set_mode(msg.mode, Some(msg.owner));
+        Ok(())
+    }
+}
+
+/// Gives up the mode held by `owner` and stops the robot, if it still holds the mode. Sent by
+/// behaviours when they stop
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct Release(pub Recipient<Preempted>);
+
+impl Handler<Release> for ModeManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: Release, _ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        if self.owner.as_ref() == Some(&msg.0) {
+            self.commands.do_send(DriveMessage::Disable);
+            self.set_mode(Mode::Idle, None);
+        }
+    }
+}
+
+/// Drive command sent on behalf of `mode`, result contains duty cycles of all wheels after the
+/// command was handled
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "Result<[f64; 4], SocketError>")]
+pub struct Command {
+    pub mode: Mode,
+    /// Behaviour holding `mode`, `None` for manual commands
+    pub owner: Option<Recipient<Preempted>>,
+    pub message: DriveMessage,
+}
+
+impl Command {
+    /// Command sent by a client
+    pub fn manual(message: DriveMessage) -> Self {
+        Self {
+            mode: Mode::Manual,
+            owner: None,
+            message,
+        }
+    }
+
+    /// Command sent by the behaviour `owner`, which acquired `mode`
+    pub fn behaviour(mode: Mode, owner: Recipient<Preempted>, message: DriveMessage) -> Self {
+        Self {
+            mode,
+            owner: Some(owner),
+            message,
+        }
+    }
+}
+
+impl Handler<Command> for ModeManager {
+    type Result = ResponseFuture<Result<[f64; 4], SocketError>>;
+
+    fn handle(&mut self, msg: Command, _ctx: &mut Self::Context) -> Self::Result {
+        // Invalid commands can't take over the robot
+        if let Err(e) = msg.message.validate() {
+            info!("rejected {msg:?}: {e}");
+            return Box::pin(async move { Err(SocketError::new(ErrorCode::InvalidMessage, e)) });
+        }
+        let result = match msg.mode {
+            // Stopping the robot hands it back, so that behaviours can be started
+            Mode::Manual => self.take_over(Mode::Manual, None).map(|()| {
+                let mode = match msg.message {
+                    DriveMessage::Disable => Mode::Idle,
+                    _ => Mode::Manual,
+                };
+                self.set_mode(mode, None);
+            }),
+            mode if mode == self.mode && msg.owner.is_some() && msg.owner == self.owner => Ok(()),
+            mode if mode == self.mode => Err(SocketError::new(
+                ErrorCode::ModeConflict,
+                format!("{mode:?} mode is held by another behaviour"),
+            )),
+            // e.g. a preempted behaviour which hasn't stopped yet
+            mode => Err(SocketError::new(
+                ErrorCode::ModeConflict,
+                format!("robot is in {:?} mode, not {mode:?}", self.mode),
+            )),
+        };
+        if let Err(e) = result {
+            info!("rejected {msg:?}: {e}");
+            return Box::pin(async move { Err(e) });
+        }
+        let request = self.commands.send(msg.message);
+        Box::pin(async move {
+            request
+                .await?
+                .map_err(|e| SocketError::new(ErrorCode::DriveError, e))
+        })
+    }
+}
+
+/// Requests the current `Mode`
+#[derive(Debug, Message)]
+#[rtype(result = "Mode")]
+pub struct GetMode;
+
+impl Handler<GetMode> for ModeManager {
+    type Result = Mode;
+
+    fn handle(&mut self, _msg: GetMode, _ctx: &mut Self::Context) -> Self::Result {
+        self.mode
+    }
+}
+
+/// Sent to `Sessions` on every mode transition, broadcast to all clients
+#[derive(Clone, Copy, Debug, Message)]
+#[rtype(result = "()")]
+pub struct ModeChanged {
+    pub mode: Mode,
+    pub previous: Mode,
+}
+
+impl Handler<EmergencyStop> for ModeManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: EmergencyStop, _ctx: &mut Self::Context) -> Self::Result {
+        // Usually engaged by whoever triggered the emergency stop already
+        self.brake.engage();
+        if self.mode == Mode::EStopped {
+            info!("emergency stop already engaged, ignoring {msg:?}");
+            return;
+        }
+        error!("emergency stop by {:?}: {}", msg.cause, msg.reason);
+        if let Some(owner) = self.owner.take() {
+            owner.do_send(Preempted(Mode::EStopped));
+        }
+        self.drive_addr.do_send(Halt);
+        // Also goes through the collision guard, so that it forgets the last command
+        self.commands.do_send(DriveMessage::Disable);
+        self.set_mode(Mode::EStopped, None);
+        self.sessions_addr.do_send(EmergencyStopped {
+            cause: msg.cause,
+            reason: msg.reason,
+        });
+    }
+}
+
+impl Handler<ResetEmergencyStop> for ModeManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: ResetEmergencyStop, _ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        if self.mode != Mode::EStopped {
+            return;
+        }
+        self.brake.release();
+        self.set_mode(Mode::Idle, None);
+    }
+}
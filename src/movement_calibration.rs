@@ -2,17 +2,29 @@ use actix::fut::wrap_future;
 use actix::prelude::*;
 use actix_web::rt::time;
 use actix_web::web::Data;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::drive::{Drive, DriveMessage, Motion, Speed};
+use crate::drive::{DriveMessage, Motion, Speed};
 use crate::hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response};
+use crate::mode::{Command, Mode, ModeManager, Preempted, Release};
+use crate::protocol::{ErrorCode, SocketError};
+use crate::telemetry::{Behaviour, BehaviourMessage, Telemetry};
 
 use std::fs::File;
 use std::io::Write;
 use std::sync::Mutex;
 use std::time::Duration;
 
-#[derive(Clone, Copy, Debug, Deserialize)]
+/// Most distance measurements per repetition
+const MAX_MEASUREMENTS: usize = 50;
+
+/// Most repetitions per duty cycle
+const MAX_REPETITIONS: usize = 20;
+
+/// Most duty cycles calibrated in one run
+const MAX_STEPS: f64 = 100.;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub struct CalibratorParams {
     min_duty_cycle: f64,
     max_duty_cycle: f64,
@@ -39,6 +51,43 @@ pub struct CalibratorParams {
 //     }
 // }
 
+impl CalibratorParams {
+    /// Checks the parameters, so that a calibration which wouldn't move the robot or wouldn't end
+    /// is rejected before it acquires its mode
+    pub fn validate(&self) -> Result<(), SocketError> {
+        let error =
+            |description: String| Err(SocketError::new(ErrorCode::InvalidMessage, description));
+        for duty_cycle in [self.min_duty_cycle, self.max_duty_cycle] {
+            Speed::Manual(duty_cycle)
+                .validate_moving()
+                .map_err(|e| SocketError::new(ErrorCode::InvalidMessage, e))?;
+        }
+        if self.min_duty_cycle > self.max_duty_cycle {
+            return error(format!(
+                "min duty cycle {} is above max duty cycle {}",
+                self.min_duty_cycle, self.max_duty_cycle
+            ));
+        }
+        if !(self.step.is_finite() && self.step > 0.)
+            || (self.max_duty_cycle - self.min_duty_cycle) / self.step > MAX_STEPS
+        {
+            return error(format!(
+                "step {} must be positive and calibrate at most {MAX_STEPS} duty cycles",
+                self.step
+            ));
+        }
+        if !(1..=MAX_MEASUREMENTS).contains(&self.measurements_per_repetition) {
+            return error(format!(
+                "measurements per repetition must be within 1..={MAX_MEASUREMENTS}"
+            ));
+        }
+        if !(1..=MAX_REPETITIONS).contains(&self.repetitions) {
+            return error(format!("repetitions must be within 1..={MAX_REPETITIONS}"));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct CalibratorState {
     motion: Motion,
@@ -57,43 +106,64 @@ impl CalibratorState {
 }
 
 pub struct Calibrator {
-    drive_data: Data<Mutex<Addr<Drive>>>,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
     hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    telemetry_addr: Addr<Telemetry>,
     params: CalibratorParams,
     state: CalibratorState,
 }
 
 impl Calibrator {
     pub fn new(
-        drive_data: Data<Mutex<Addr<Drive>>>,
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
         hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        telemetry_addr: Addr<Telemetry>,
         params: CalibratorParams,
     ) -> Self {
         Self {
-            drive_data,
+            mode_manager_data,
             hc_sr04_data,
+            telemetry_addr,
             params,
             state: CalibratorState::new(params.min_duty_cycle),
         }
     }
+
+    /// Sends a drive command on behalf of the `Calibrating` mode
+    fn send_command(&self, ctx: &mut <Self as Actor>::Context, message: DriveMessage) {
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Command::behaviour(
+                Mode::Calibrating,
+                ctx.address().recipient(),
+                message,
+            ));
+    }
 }
 
 impl Actor for Calibrator {
     type Context = Context<Self>;
 
     fn started(&mut self, _ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Started(Behaviour::Calibrating));
         println!("Calibrator actor started");
     }
 
-    fn stopped(&mut self, _ctx: &mut Self::Context) {
-        let drive_addr = self.drive_data.lock().unwrap();
-        // Stop the robot
-        drive_addr.do_send(DriveMessage::Disable);
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        // Stops the robot
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Release(ctx.address().recipient()));
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Stopped(Behaviour::Calibrating));
         println!("Calibrator actor stopped");
     }
 }
 
-#[derive(Debug, Deserialize, Message)]
+#[derive(Clone, Debug, Deserialize, Serialize, Message)]
 #[rtype(result = "()")]
 #[serde(tag = "variant")]
 pub enum CalibratorMessage {
@@ -105,22 +175,26 @@ impl Handler<CalibratorMessage> for Calibrator {
     type Result = ();
 
     fn handle(&mut self, msg: CalibratorMessage, ctx: &mut Self::Context) -> Self::Result {
-        let drive_addr = self.drive_data.lock().unwrap();
-        let hc_sr04_addr = self.hc_sr04_data.lock().unwrap();
         match msg {
             CalibratorMessage::Start(params) => {
                 self.params = params;
                 println!("Performing calibration: {:?}", self.state);
                 // Move robot
-                drive_addr.do_send(DriveMessage::Enable {
-                    motion: self.state.motion,
-                    speed: Speed::Manual(self.state.duty_cycle),
-                });
+                self.send_command(
+                    ctx,
+                    DriveMessage::Enable {
+                        motion: self.state.motion,
+                        speed: Speed::Manual(self.state.duty_cycle),
+                    },
+                );
                 // Start measurement
-                hc_sr04_addr.do_send(HcSr04Message::Multiple(
-                    self.params.measurements_per_repetition,
-                    crate::hc_sr04::Recipient::Calibrator(ctx.address()),
-                ));
+                self.hc_sr04_data
+                    .lock()
+                    .unwrap()
+                    .do_send(HcSr04Message::Multiple(
+                        self.params.measurements_per_repetition,
+                        ctx.address().recipient(),
+                    ));
             }
             CalibratorMessage::Stop => {
                 ctx.stop();
@@ -129,16 +203,27 @@ impl Handler<CalibratorMessage> for Calibrator {
     }
 }
 
+impl Handler<Preempted> for Calibrator {
+    type Result = ();
+
+    fn handle(&mut self, msg: Preempted, ctx: &mut Self::Context) -> Self::Result {
+        println!("Calibration preempted by {:?} mode", msg.0);
+        ctx.stop();
+    }
+}
+
 impl Handler<HcSr04Response> for Calibrator {
     type Result = ();
 
     fn handle(&mut self, msg: HcSr04Response, ctx: &mut Self::Context) -> Self::Result {
-        let drive_addr = self.drive_data.lock().unwrap();
         // Stop the robot
-        drive_addr.do_send(DriveMessage::Enable {
-            motion: Motion::Stop,
-            speed: Speed::Low,
-        });
+        self.send_command(
+            ctx,
+            DriveMessage::Enable {
+                motion: Motion::Stop,
+                speed: Speed::Low,
+            },
+        );
         // Process the result
         let result = match msg {
             HcSr04Response::Ok(measurement) => match measurement {
@@ -172,8 +257,9 @@ impl Handler<HcSr04Response> for Calibrator {
             self.state.repetition = 0;
             self.state.duty_cycle += self.params.step;
         }
-        // Return if measurement is completed
+        // Stop if measurement is completed
         if self.state.duty_cycle > self.params.max_duty_cycle {
+            ctx.stop();
             return;
         }
         // Send message after some time for next measurement if not
@@ -181,3 +267,65 @@ impl Handler<HcSr04Response> for Calibrator {
         ctx.address().do_send(CalibratorMessage::Start(self.params));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> CalibratorParams {
+        CalibratorParams {
+            min_duty_cycle: 0.4,
+            max_duty_cycle: 1.,
+            step: 0.1,
+            measurements_per_repetition: 10,
+            repetitions: 3,
+        }
+    }
+
+    #[test]
+    fn validate_rejects_endless_or_stalling_calibrations() {
+        assert!(params().validate().is_ok());
+        let invalid = [
+            CalibratorParams {
+                min_duty_cycle: 0.1,
+                ..params()
+            },
+            CalibratorParams {
+                max_duty_cycle: 1.5,
+                ..params()
+            },
+            CalibratorParams {
+                min_duty_cycle: 0.9,
+                max_duty_cycle: 0.5,
+                ..params()
+            },
+            CalibratorParams {
+                step: 0.,
+                ..params()
+            },
+            CalibratorParams {
+                step: -0.1,
+                ..params()
+            },
+            CalibratorParams {
+                step: 1e-6,
+                ..params()
+            },
+            CalibratorParams {
+                measurements_per_repetition: 1_000_000,
+                ..params()
+            },
+            CalibratorParams {
+                repetitions: 0,
+                ..params()
+            },
+        ];
+        for params in invalid {
+            assert_eq!(
+                params.validate().map_err(|e| e.code).unwrap_err(),
+                ErrorCode::InvalidMessage,
+                "{params:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::{future::Future, sync::Mutex, time::Duration};
+
+use actix::{clock, fut::wrap_future, prelude::*};
+use actix_web::web::Data;
+use log::{debug, error, info};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, Publish, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{JobMessage, Jobs},
+    distance_scan::{ScanResult, ScannerMessage},
+    drive::DriveMessage,
+    mode::{Command, ModeManager},
+    movement_calibration::CalibratorMessage,
+    protocol::{ErrorCode, SocketError},
+    sessions::{GetSessions, ObserveScans, ScanObserver, Sessions},
+    shutdown::Shutdown,
+    telemetry::{Telemetry, TelemetryMessage, TelemetrySnapshot},
+};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// How long to wait before reconnecting after the connection to the broker failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Number of outgoing requests buffered while the broker is unreachable
+const REQUEST_CAPACITY: usize = 16;
+
+/// Retained on `<prefix>/status`, the broker publishes `OFFLINE` as the Last Will
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Broker connection settings
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Prepended to all topics, e.g. `mecanum-car/distance`
+    pub topic_prefix: String,
+}
+
+impl MqttConfig {
+    /// Parses `host` or `host:port`, the port defaults to 1883
+    pub fn parse(broker: &str, client_id: &str, topic_prefix: &str) -> Result<Self, String> {
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|e| format!("invalid MQTT broker port {port}: {e}"))?;
+                (host, port)
+            }
+            None => (broker, 1883),
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            client_id: client_id.to_string(),
+            topic_prefix: topic_prefix.to_string(),
+        })
+    }
+}
+
+/// Commands accepted on `<prefix>/command`, using the same JSON as the WebSocket messages
+#[derive(Debug, Deserialize)]
+#[serde(tag = "message")]
+enum MqttCommand {
+    Move(DriveMessage),
+    ScanDistance(ScannerMessage),
+    CalibrateMovement(CalibratorMessage),
+}
+
+/// Connects the robot to an MQTT broker. Publishes telemetry, distance readings and scan results,
+/// and accepts commands while no WebSocket session is the pilot
+pub struct Mqtt {
+    config: MqttConfig,
+    client: AsyncClient,
+    /// Taken by the polling task once the actor starts
+    event_loop: Option<EventLoop>,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+    telemetry_addr: Addr<Telemetry>,
+}
+
+impl Mqtt {
+    pub fn new(
+        config: MqttConfig,
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+        sessions_data: Data<Mutex<Addr<Sessions>>>,
+        jobs_data: Data<Mutex<Addr<Jobs>>>,
+        telemetry_addr: Addr<Telemetry>,
+    ) -> Self {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            format!("{}/status", config.topic_prefix),
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, event_loop) = AsyncClient::new(options, REQUEST_CAPACITY);
+        Self {
+            config,
+            client,
+            event_loop: Some(event_loop),
+            mode_manager_data,
+            sessions_data,
+            jobs_data,
+            telemetry_addr,
+        }
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{name}", self.config.topic_prefix)
+    }
+
+    /// Queues a message for the broker, dropped if the queue is full
+    fn publish(&self, name: &str, qos: QoS, retain: bool, payload: impl Into<Vec<u8>>) {
+        if let Err(e) = self
+            .client
+            .try_publish(self.topic(name), qos, retain, payload)
+        {
+            debug!("failed to publish to {name}: {e}");
+        }
+    }
+
+    fn publish_json(&self, name: &str, value: &impl Serialize) {
+        let payload = serde_json::to_vec(value).expect("Failed to serialize message");
+        self.publish(name, QoS::AtMostOnce, false, payload);
+    }
+
+    fn publish_error(&self, error: SocketError) {
+        error!("command failed: {error}");
+        self.publish_json("error", &error);
+    }
+
+    /// Subscriptions and the online status don't survive a reconnect
+    fn connected_handler(&self) {
+        info!("connected to {}:{}", self.config.host, self.config.port);
+        if let Err(e) = self
+            .client
+            .try_subscribe(self.topic("command"), QoS::AtLeastOnce)
+        {
+            error!("failed to subscribe to commands: {e}");
+        }
+        self.publish("status", QoS::AtLeastOnce, true, ONLINE);
+    }
+
+    fn command_handler(&self, payload: &[u8], ctx: &mut <Self as Actor>::Context) {
+        let command: MqttCommand = match serde_json::from_slice(payload) {
+            Ok(command) => command,
+            Err(e) => return self.publish_error(SocketError::new(ErrorCode::InvalidMessage, e)),
+        };
+        info!("received {command:?}");
+        let result = self.run_command(command);
+        ctx.spawn(wrap_future(result).map(|result, act: &mut Self, _ctx| {
+            if let Err(e) = result {
+                act.publish_error(e);
+            }
+        }));
+    }
+
+    fn run_command(
+        &self,
+        command: MqttCommand,
+    ) -> impl Future<Output = Result<(), SocketError>> + 'static {
+        let mode_manager_data = self.mode_manager_data.clone();
+        let sessions_data = self.sessions_data.clone();
+        let jobs_data = self.jobs_data.clone();
+        async move {
+            // Like REST clients, MQTT may only control the robot while nobody is piloting it
+            let sessions_addr = sessions_data.lock()?.clone();
+            if let Some(pilot) = sessions_addr.send(GetSessions).await?.pilot {
+                return Err(SocketError::new(
+                    ErrorCode::NotPilot,
+                    format!("session {pilot} is the pilot, ask it to release control"),
+                ));
+            }
+            match command {
+                MqttCommand::Move(message) => {
+                    let mode_manager_addr = mode_manager_data.lock()?.clone();
+                    mode_manager_addr.send(Command::manual(message)).await??;
+                }
+                MqttCommand::ScanDistance(message) => {
+                    let jobs_addr = jobs_data.lock()?.clone();
+                    jobs_addr.send(JobMessage::Scan(message)).await??;
+                }
+                MqttCommand::CalibrateMovement(message) => {
+                    let jobs_addr = jobs_data.lock()?.clone();
+                    jobs_addr.send(JobMessage::Calibration(message)).await??;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+impl Actor for Mqtt {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("actor started");
+        let addr = ctx.address();
+        self.telemetry_addr
+            .do_send(TelemetryMessage::Subscribe(addr.clone().recipient()));
+        match self.sessions_data.lock() {
+            Ok(sessions_addr) => sessions_addr.do_send(ObserveScans(ScanObserver {
+                scans: addr.clone().recipient(),
+                shutdown: addr.clone().recipient(),
+            })),
+            Err(e) => error!("{e:?}"),
+        }
+
+        // The event loop has to be polled for the client to make progress, it reconnects on the
+        // next poll after an error
+        let mut event_loop = self.event_loop.take().expect("Event loop already taken");
+        actix::spawn(async move {
+            while addr.connected() {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => addr.do_send(MqttEvent::Connected),
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        addr.do_send(MqttEvent::Publish(publish))
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        error!("MQTT connection failed: {e}");
+                        clock::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Forwarded from the event loop
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+enum MqttEvent {
+    Connected,
+    Publish(Publish),
+}
+
+impl Handler<MqttEvent> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqttEvent, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            MqttEvent::Connected => self.connected_handler(),
+            MqttEvent::Publish(publish) if publish.topic == self.topic("command") => {
+                self.command_handler(&publish.payload, ctx)
+            }
+            MqttEvent::Publish(publish) => debug!("ignoring message on {}", publish.topic),
+        }
+    }
+}
+
+impl Handler<TelemetrySnapshot> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, msg: TelemetrySnapshot, _ctx: &mut Self::Context) -> Self::Result {
+        // Plain number, so that dashboards can use it without parsing JSON
+        if let Some(distance) = msg.distance {
+            self.publish("distance", QoS::AtMostOnce, false, distance.to_string());
+        }
+        self.publish_json("state", &msg);
+    }
+}
+
+/// Disconnects cleanly, so the broker doesn't publish the Last Will
+impl Handler<Shutdown> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        self.publish("status", QoS::AtLeastOnce, true, OFFLINE);
+        if let Err(e) = self.client.try_disconnect() {
+            error!("failed to disconnect: {e}");
+        }
+    }
+}
+
+impl Handler<ScanResult> for Mqtt {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScanResult, _ctx: &mut Self::Context) -> Self::Result {
+        self.publish_json("scan", &msg);
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    f64::consts::PI,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix::{fut::wrap_future, prelude::*};
+use actix_web::web::Data;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    drive::{Drive, DriveMessage, GetDriveState, Pose},
+    mode::{Command, Mode, ModeManager, Preempted, Release},
+    protocol::{ErrorCode, SocketError},
+    telemetry::{Behaviour, BehaviourMessage, Telemetry},
+};
+
+/// How often the pose is read and the velocity corrected
+const CONTROL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The path is abandoned if the pose doesn't change for this long, e.g. because the speeds are too
+/// low to turn the wheels
+const STALL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Rotation (as a fraction of the maximum duty cycle) per radian of heading error
+const HEADING_GAIN: f64 = 1.;
+
+/// Most waypoints of one path
+const MAX_WAYPOINTS: usize = 1000;
+
+/// Point of the path in the odometry frame, see `Pose`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Waypoint {
+    x: f64,
+    y: f64,
+    /// Heading (counterclockwise, in radians) the robot turns to while heading for the waypoint,
+    /// the heading is kept if it's missing
+    heading: Option<f64>,
+}
+
+impl Waypoint {
+    fn position(&self) -> (f64, f64) {
+        (self.x, self.y)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PathFollowerParams {
+    waypoints: Vec<Waypoint>,
+    /// Distance (in meters) of the point on the path the robot steers toward
+    lookahead: f64,
+    /// Speeds are fractions of the maximum duty cycle, as in `DriveMessage::Velocity`. The robot
+    /// slows down from `max_speed` when approaching the goal, but not below `min_speed`, as the
+    /// wheels stall at low duty cycles
+    min_speed: f64,
+    max_speed: f64,
+    /// Highest rotation used to turn to the waypoint headings
+    max_rotation: f64,
+    /// Distance (in meters) from the last waypoint at which the goal is reached
+    goal_tolerance: f64,
+    /// Heading error (in radians) at which the heading of the last waypoint is reached
+    heading_tolerance: f64,
+}
+
+impl PathFollowerParams {
+    /// Checks the parameters, so that an invalid path is rejected before it acquires its mode
+    pub fn validate(&self) -> Result<(), SocketError> {
+        let error =
+            |description: String| Err(SocketError::new(ErrorCode::InvalidMessage, description));
+        let positive = |value: f64| value.is_finite() && value > 0.;
+        if self.waypoints.is_empty() || self.waypoints.len() > MAX_WAYPOINTS {
+            return error(format!(
+                "path has {} waypoints, expected 1..={MAX_WAYPOINTS}",
+                self.waypoints.len()
+            ));
+        }
+        if let Some(waypoint) = self.waypoints.iter().find(|waypoint| {
+            !(waypoint.x.is_finite()
+                && waypoint.y.is_finite()
+                && waypoint.heading.is_none_or(f64::is_finite))
+        }) {
+            return error(format!("invalid waypoint {waypoint:?}"));
+        }
+        if !(positive(self.min_speed) && self.min_speed <= self.max_speed && self.max_speed <= 1.) {
+            return error(format!(
+                "speeds {}..={} are not within 0..=1",
+                self.min_speed, self.max_speed
+            ));
+        }
+        if !(0. ..=1.).contains(&self.max_rotation) {
+            return error(format!(
+                "max rotation {} is not within 0..=1",
+                self.max_rotation
+            ));
+        }
+        if !(positive(self.lookahead)
+            && positive(self.goal_tolerance)
+            && positive(self.heading_tolerance))
+        {
+            return error("lookahead and tolerances must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Point of the segment `a`-`b` the robot at `position` steers toward: the farthest intersection
+/// with the circle of radius `lookahead` around the robot, or the closest point of the segment if
+/// the circle doesn't reach it
+fn lookahead_point(
+    a: (f64, f64),
+    b: (f64, f64),
+    position: (f64, f64),
+    lookahead: f64,
+) -> (f64, f64) {
+    let d = (b.0 - a.0, b.1 - a.1);
+    let f = (a.0 - position.0, a.1 - position.1);
+    let length_squared = d.0 * d.0 + d.1 * d.1;
+    if length_squared == 0. {
+        return b;
+    }
+    let dot = f.0 * d.0 + f.1 * d.1;
+    let discriminant = dot * dot - length_squared * (f.0 * f.0 + f.1 * f.1 - lookahead * lookahead);
+    let t = if discriminant >= 0. {
+        (-dot + discriminant.sqrt()) / length_squared
+    } else {
+        -dot / length_squared
+    };
+    let t = t.clamp(0., 1.);
+    (a.0 + t * d.0, a.1 + t * d.1)
+}
+
+/// Wraps `angle` (in radians) to `-PI..=PI`
+fn normalize_angle(angle: f64) -> f64 {
+    let angle = angle.rem_euclid(2. * PI);
+    if angle > PI {
+        angle - 2. * PI
+    } else {
+        angle
+    }
+}
+
+/// Velocity command moving the robot at `pose` straight toward `target` with `speed`, while
+/// rotating counterclockwise with `rotation`. The chassis is holonomic, so it strafes instead of
+/// turning toward the target first
+fn steer(pose: Pose, target: (f64, f64), speed: f64, rotation: f64) -> DriveMessage {
+    let (dx, dy) = (target.0 - pose.x, target.1 - pose.y);
+    let distance = dx.hypot(dy);
+    let (x, y) = if distance > 0. {
+        let (sin, cos) = pose.heading.sin_cos();
+        (
+            (dx * cos + dy * sin) / distance * speed,
+            (dy * cos - dx * sin) / distance * speed,
+        )
+    } else {
+        (0., 0.)
+    };
+    // `Pose` uses ROS conventions, the drive takes right and clockwise as positive
+    DriveMessage::Velocity {
+        x,
+        y: -y,
+        rotation: -rotation,
+    }
+}
+
+/// Drives along a list of waypoints with pure pursuit: the robot steers toward the point of the
+/// path `lookahead` ahead of it, using the pose estimated by dead reckoning
+pub struct PathFollower {
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    drive_addr: Addr<Drive>,
+    telemetry_addr: Addr<Telemetry>,
+    /// Receives the progress, usually the client which requested the path
+    recipient: Recipient<PathFollowerResponse>,
+    params: PathFollowerParams,
+    /// Position at the start of the path, where the first segment begins
+    start: Option<(f64, f64)>,
+    /// Index of the waypoint the robot is heading for
+    waypoint: usize,
+    pose: Pose,
+    /// When the pose last changed
+    last_motion: Instant,
+    control: Option<SpawnHandle>,
+    /// Whether a pose request is pending
+    updating: bool,
+}
+
+impl PathFollower {
+    pub fn new(
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+        drive_addr: Addr<Drive>,
+        telemetry_addr: Addr<Telemetry>,
+        recipient: Recipient<PathFollowerResponse>,
+        params: PathFollowerParams,
+    ) -> Self {
+        Self {
+            mode_manager_data,
+            drive_addr,
+            telemetry_addr,
+            recipient,
+            params,
+            start: None,
+            waypoint: 0,
+            pose: Pose::default(),
+            last_motion: Instant::now(),
+            control: None,
+            updating: false,
+        }
+    }
+
+    /// Sends a drive command on behalf of the `Autonomous` mode
+    fn send_command(&self, ctx: &mut <Self as Actor>::Context, message: DriveMessage) {
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Command::behaviour(
+                Mode::Autonomous,
+                ctx.address().recipient(),
+                message,
+            ));
+    }
+
+    /// Logs the progress and reports it to the recipient
+    fn report(&self, state: PathFollowerState, error: Option<String>, description: String) {
+        info!("{description}");
+        self.recipient.do_send(PathFollowerResponse {
+            state,
+            waypoint: self.waypoint,
+            waypoints: self.params.waypoints.len(),
+            pose: self.pose,
+            error,
+            description,
+        });
+    }
+
+    fn fail(&self, ctx: &mut <Self as Actor>::Context, error: String, description: String) {
+        error!("{error}");
+        self.report(PathFollowerState::Stopped, Some(error), description);
+        ctx.stop();
+    }
+
+    /// Requests the pose, unless the previous request is still pending
+    fn update(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.updating {
+            return;
+        }
+        self.updating = true;
+        let request = self.drive_addr.send(GetDriveState);
+        ctx.spawn(wrap_future(request).map(|result, act: &mut Self, ctx| {
+            act.updating = false;
+            match result {
+                Ok(state) => act.follow(ctx, state.pose),
+                Err(e) => act.fail(
+                    ctx,
+                    format!("pose request failed: {e}"),
+                    format!("Pose request failed: {e}, stopping"),
+                ),
+            }
+        }));
+    }
+
+    /// Steers toward the lookahead point from `pose`, or finishes once the goal is reached
+    fn follow(&mut self, ctx: &mut <Self as Actor>::Context, pose: Pose) {
+        let moved = (pose.x, pose.y, pose.heading) != (self.pose.x, self.pose.y, self.pose.heading);
+        self.pose = pose;
+        if moved {
+            self.last_motion = Instant::now();
+        } else if self.last_motion.elapsed() > STALL_TIMEOUT {
+            return self.fail(
+                ctx,
+                "robot isn't moving".to_string(),
+                format!("Robot isn't moving for {STALL_TIMEOUT:?}, stopping"),
+            );
+        }
+        let position = (pose.x, pose.y);
+        let start = *self.start.get_or_insert(position);
+        let distance = |(x, y): (f64, f64)| (x - pose.x).hypot(y - pose.y);
+
+        let PathFollowerParams {
+            ref waypoints,
+            lookahead,
+            min_speed,
+            max_speed,
+            max_rotation,
+            goal_tolerance,
+            heading_tolerance,
+        } = self.params;
+        // Intermediate waypoints are passed once they're within the lookahead
+        let mut advanced = false;
+        while self.waypoint + 1 < waypoints.len()
+            && distance(waypoints[self.waypoint].position()) < lookahead
+        {
+            self.waypoint += 1;
+            advanced = true;
+        }
+        let waypoint = waypoints[self.waypoint];
+        let segment_start = match self.waypoint {
+            0 => start,
+            i => waypoints[i - 1].position(),
+        };
+        let last = self.waypoint + 1 == waypoints.len();
+
+        let heading_error = waypoint
+            .heading
+            .map_or(0., |heading| normalize_angle(heading - pose.heading));
+        let remaining = distance(waypoint.position());
+        if last && remaining < goal_tolerance && heading_error.abs() < heading_tolerance {
+            self.waypoint += 1;
+            self.report(
+                PathFollowerState::Finished,
+                None,
+                format!("Reached the goal at ({:.2}, {:.2})", pose.x, pose.y),
+            );
+            // Stops the robot
+            return ctx.stop();
+        }
+        if advanced {
+            self.report(
+                PathFollowerState::Following,
+                None,
+                format!(
+                    "Heading for waypoint {}/{}",
+                    self.waypoint + 1,
+                    waypoints.len()
+                ),
+            );
+        }
+
+        let rotation = if heading_error.abs() < heading_tolerance {
+            0.
+        } else {
+            (HEADING_GAIN * heading_error.abs())
+                .max(min_speed)
+                .min(max_rotation)
+                .copysign(heading_error)
+        };
+        let (target, speed) = if last && remaining < goal_tolerance {
+            // Only the heading is left
+            (waypoint.position(), 0.)
+        } else if last {
+            let target = lookahead_point(segment_start, waypoint.position(), position, lookahead);
+            let speed = (max_speed * remaining / lookahead).clamp(min_speed, max_speed);
+            (target, speed)
+        } else {
+            let target = lookahead_point(segment_start, waypoint.position(), position, lookahead);
+            (target, max_speed)
+        };
+        self.send_command(ctx, steer(pose, target, speed, rotation));
+    }
+}
+
+impl Actor for PathFollower {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Started(Behaviour::PathFollowing));
+        info!("actor started");
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Stopped(Behaviour::PathFollowing));
+        // Stops the robot
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Release(ctx.address().recipient()));
+        info!("actor stopped");
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Message)]
+#[rtype(result = "()")]
+#[serde(tag = "variant")]
+pub enum PathFollowerMessage {
+    /// Replaces the path of a running follower, which continues from where the robot is
+    Start(PathFollowerParams),
+    Stop,
+}
+
+impl Handler<PathFollowerMessage> for PathFollower {
+    type Result = ();
+
+    fn handle(&mut self, msg: PathFollowerMessage, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        match msg {
+            PathFollowerMessage::Start(params) => {
+                self.params = params;
+                self.start = None;
+                self.waypoint = 0;
+                self.last_motion = Instant::now();
+                self.report(
+                    PathFollowerState::Following,
+                    None,
+                    format!(
+                        "Following path of {} waypoints",
+                        self.params.waypoints.len()
+                    ),
+                );
+                if self.control.is_none() {
+                    let control = ctx.run_interval(CONTROL_INTERVAL, |act, ctx| act.update(ctx));
+                    self.control = Some(control);
+                    self.update(ctx);
+                }
+            }
+            PathFollowerMessage::Stop => {
+                self.report(
+                    PathFollowerState::Stopped,
+                    None,
+                    "Path following stopped".to_string(),
+                );
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Handler<Preempted> for PathFollower {
+    type Result = ();
+
+    fn handle(&mut self, msg: Preempted, ctx: &mut Self::Context) -> Self::Result {
+        self.report(
+            PathFollowerState::Stopped,
+            Some(format!("preempted by {:?} mode", msg.0)),
+            format!("Path following preempted by {:?} mode", msg.0),
+        );
+        ctx.stop();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum PathFollowerState {
+    Following,
+    /// The goal was reached, the follower stops
+    Finished,
+    Stopped,
+}
+
+/// Progress of the path follower, sent when it starts, passes a waypoint and stops
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+pub struct PathFollowerResponse {
+    pub state: PathFollowerState,
+    /// Index of the waypoint the robot is heading for, all of them were passed once it equals
+    /// `waypoints`
+    pub waypoint: usize,
+    pub waypoints: usize,
+    /// Latest estimated pose
+    pub pose: Pose,
+    /// Why the follower stopped before reaching the goal, unless it was asked to
+    pub error: Option<String>,
+    /// Human-readable summary, only meant to be displayed
+    pub description: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(waypoints: Vec<Waypoint>) -> PathFollowerParams {
+        PathFollowerParams {
+            waypoints,
+            lookahead: 0.3,
+            min_speed: 0.4,
+            max_speed: 0.8,
+            max_rotation: 0.6,
+            goal_tolerance: 0.05,
+            heading_tolerance: 0.1,
+        }
+    }
+
+    fn waypoint(x: f64, y: f64) -> Waypoint {
+        Waypoint {
+            x,
+            y,
+            heading: None,
+        }
+    }
+
+    fn assert_near((x, y): (f64, f64), (expected_x, expected_y): (f64, f64)) {
+        assert!(
+            (x - expected_x).abs() < 1e-9 && (y - expected_y).abs() < 1e-9,
+            "({x}, {y}) != ({expected_x}, {expected_y})"
+        );
+    }
+
+    #[test]
+    fn lookahead_point_is_ahead_on_the_path() {
+        // On the path, the point is `lookahead` further along
+        assert_near(
+            lookahead_point((0., 0.), (2., 0.), (0.5, 0.), 0.3),
+            (0.8, 0.),
+        );
+        // Next to the path, the circle intersects it ahead of the robot
+        assert_near(
+            lookahead_point((0., 0.), (2., 0.), (0.5, 0.3), 0.5),
+            (0.9, 0.),
+        );
+        // Near the end, the end is the target
+        assert_near(
+            lookahead_point((0., 0.), (2., 0.), (1.9, 0.), 0.3),
+            (2., 0.),
+        );
+    }
+
+    #[test]
+    fn lookahead_point_returns_to_distant_paths() {
+        // The circle doesn't reach the path, so the robot heads for the closest point
+        assert_near(lookahead_point((0., 0.), (2., 0.), (1., 1.), 0.3), (1., 0.));
+        assert_near(lookahead_point((0., 0.), (0., 0.), (1., 1.), 0.3), (0., 0.));
+    }
+
+    #[test]
+    fn normalize_angle_takes_the_shorter_way() {
+        assert!((normalize_angle(1.5 * PI) + 0.5 * PI).abs() < 1e-9);
+        assert!((normalize_angle(-1.5 * PI) - 0.5 * PI).abs() < 1e-9);
+        assert!((normalize_angle(0.25) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn steer_strafes_toward_the_target() {
+        let pose = Pose {
+            x: 1.,
+            y: 1.,
+            heading: PI / 2.,
+        };
+        // Facing +y, a target in +x is to the right
+        let DriveMessage::Velocity { x, y, rotation } = steer(pose, (2., 1.), 0.5, 0.2) else {
+            panic!("expected a velocity");
+        };
+        assert_near((x, y), (0., 0.5));
+        assert_eq!(rotation, -0.2);
+        // At the target, only the rotation is left
+        let DriveMessage::Velocity { x, y, rotation } = steer(pose, (1., 1.), 0.5, 0.3) else {
+            panic!("expected a velocity");
+        };
+        assert_near((x, y), (0., 0.));
+        assert_eq!(rotation, -0.3);
+    }
+
+    #[test]
+    fn validate_rejects_invalid_paths() {
+        assert!(params(vec![waypoint(1., 0.), waypoint(1., 1.)])
+            .validate()
+            .is_ok());
+        let invalid = [
+            params(vec![]),
+            params(vec![waypoint(f64::NAN, 0.)]),
+            params(vec![Waypoint {
+                x: 1.,
+                y: 0.,
+                heading: Some(f64::INFINITY),
+            }]),
+            PathFollowerParams {
+                min_speed: 0.9,
+                ..params(vec![waypoint(1., 0.)])
+            },
+            PathFollowerParams {
+                max_speed: 1.5,
+                ..params(vec![waypoint(1., 0.)])
+            },
+            PathFollowerParams {
+                lookahead: 0.,
+                ..params(vec![waypoint(1., 0.)])
+            },
+            PathFollowerParams {
+                max_rotation: -0.1,
+                ..params(vec![waypoint(1., 0.)])
+            },
+        ];
+        for params in invalid {
+            assert_eq!(
+                params.validate().map_err(|e| e.code).unwrap_err(),
+                ErrorCode::InvalidMessage,
+                "{params:?}"
+            );
+        }
+    }
+}
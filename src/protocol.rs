@@ -0,0 +1,507 @@
+use std::{
+    fmt::{self, Display},
+    sync::PoisonError,
+};
+
+use actix::prelude::{MailboxError, SendError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    autopilot::{AutopilotMessage, AutopilotResponse},
+    collision_guard::{GuardIntervened, Intervention},
+    distance_scan::{ScanResult, ScannerMessage},
+    drive::{DriveMessage, DriveResponse},
+    estop::{Cause, EmergencyStopped},
+    mission::{Mission, MissionMessage, MissionProgress},
+    mode::{Mode, ModeChanged},
+    movement_calibration::CalibratorMessage,
+    path_following::{PathFollowerMessage, PathFollowerResponse},
+    supervision::{Device, DeviceRestarted},
+    telemetry::TelemetrySnapshot,
+    wall_following::{WallFollowerMessage, WallFollowerResponse},
+};
+
+/// Version of the WebSocket protocol spoken by the server
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// Client-supplied request identifier (any JSON value), echoed back in frames answering the request
+pub type RequestId = Value;
+
+/// Client to WebSocket messages
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "message")]
+pub enum SocketMessage {
+    Move(DriveMessage),
+    MeasureDistance,
+    CalibrateMovement(CalibratorMessage),
+    ScanDistance(ScannerMessage),
+    Autopilot(AutopilotMessage),
+    FollowWall(WallFollowerMessage),
+    FollowPath(PathFollowerMessage),
+    Mission(MissionMessage),
+    ListMissions,
+    TakeControl,
+    ReleaseControl,
+    HandOverControl {
+        session: usize,
+    },
+    /// Binary subscribers receive telemetry in the `binary_protocol` format
+    SubscribeTelemetry {
+        #[serde(default)]
+        binary: bool,
+    },
+    UnsubscribeTelemetry,
+    /// Allowed for every session which isn't read-only, not just the pilot
+    EmergencyStop,
+    ResetEmergencyStop,
+}
+
+impl SocketMessage {
+    /// Whether only the pilot is allowed to send the message
+    pub fn requires_pilot(&self) -> bool {
+        !matches!(
+            self,
+            SocketMessage::MeasureDistance
+                | SocketMessage::ListMissions
+                | SocketMessage::TakeControl
+                | SocketMessage::ReleaseControl
+                | SocketMessage::HandOverControl { .. }
+                | SocketMessage::SubscribeTelemetry { .. }
+                | SocketMessage::UnsubscribeTelemetry
+                | SocketMessage::EmergencyStop
+        )
+    }
+
+    /// Checks the parameters of the message, so that invalid behaviours are rejected before they
+    /// acquire their mode
+    pub fn validate(&self) -> Result<(), SocketError> {
+        match self {
+            SocketMessage::Move(message) => message
+                .validate()
+                .map_err(|e| SocketError::new(ErrorCode::InvalidMessage, e)),
+            SocketMessage::CalibrateMovement(CalibratorMessage::Start(params)) => params.validate(),
+            SocketMessage::ScanDistance(message) => message.validate(),
+            SocketMessage::Autopilot(AutopilotMessage::Start(params)) => params.validate(),
+            SocketMessage::FollowWall(WallFollowerMessage::Start(params)) => params.validate(),
+            SocketMessage::FollowPath(PathFollowerMessage::Start(params)) => params.validate(),
+            SocketMessage::Mission(MissionMessage::Run { steps }) => Mission {
+                steps: steps.clone(),
+            }
+            .validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Client request - a `SocketMessage` with optional `version` and `id` fields
+#[derive(Debug)]
+pub struct SocketRequest {
+    pub id: Option<RequestId>,
+    pub message: SocketMessage,
+}
+
+impl SocketRequest {
+    /// Parses a client frame. On failure the request id is returned along with the error, if it
+    /// could be read
+    pub fn parse(text: &str) -> Result<Self, (Option<RequestId>, SocketError)> {
+        let value: Value = serde_json::from_str(text)
+            .map_err(|e| (None, SocketError::new(ErrorCode::InvalidJson, e)))?;
+        let id = value.get("id").cloned();
+
+        // Requests without a version are assumed to use the current one
+        if let Some(version) = value.get("version") {
+            if version.as_u64() != Some(PROTOCOL_VERSION) {
+                let description =
+                    format!("unsupported protocol version {version}, expected {PROTOCOL_VERSION}");
+                return Err((
+                    id,
+                    SocketError::new(ErrorCode::UnsupportedVersion, description),
+                ));
+            }
+        }
+
+        let message = SocketMessage::deserialize(value)
+            .map_err(|e| SocketError::new(ErrorCode::InvalidMessage, e))
+            .and_then(|message| message.validate().map(|()| message));
+        match message {
+            Ok(message) => Ok(Self { id, message }),
+            Err(e) => Err((id, e)),
+        }
+    }
+}
+
+/// Machine-readable error categories
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Frame is not valid JSON
+    InvalidJson,
+    /// Frame is valid JSON, but not a valid `SocketMessage`
+    InvalidMessage,
+    UnsupportedVersion,
+    /// Actor handling the request is not running or its mailbox is full
+    DeviceUnavailable,
+    /// Drive failed to control the motors
+    DriveError,
+    /// Distance sensor failed to perform a measurement
+    SensorError,
+    ApproachFailed,
+    /// Request requires drive control, which belongs to another session
+    NotPilot,
+    /// Drive control can't be taken or handed over
+    ControlUnavailable,
+    /// Command conflicts with the current mode, e.g. a scan while calibrating
+    ModeConflict,
+    /// Drive commands are refused until the emergency stop is reset
+    EmergencyStopped,
+    /// Request has no valid token
+    Unauthorized,
+    /// Requested resource doesn't exist
+    NotFound,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SocketError {
+    pub code: ErrorCode,
+    pub description: String,
+}
+
+impl SocketError {
+    pub fn new(code: ErrorCode, description: impl Display) -> Self {
+        Self {
+            code,
+            description: description.to_string(),
+        }
+    }
+}
+
+impl Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.description)
+    }
+}
+
+impl<M> From<SendError<M>> for SocketError {
+    fn from(e: SendError<M>) -> Self {
+        let description = match e {
+            SendError::Full(_) => "mailbox full",
+            SendError::Closed(_) => "actor stopped",
+        };
+        Self::new(ErrorCode::DeviceUnavailable, description)
+    }
+}
+
+impl From<MailboxError> for SocketError {
+    fn from(e: MailboxError) -> Self {
+        Self::new(ErrorCode::DeviceUnavailable, e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for SocketError {
+    fn from(e: PoisonError<T>) -> Self {
+        Self::new(ErrorCode::DeviceUnavailable, e)
+    }
+}
+
+/// Machine-readable contents of responses and events
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "variant")]
+pub enum SocketPayload {
+    /// Drive executed `command`, leaving the wheels with `duty_cycles` (positive: forward,
+    /// negative: backward)
+    Move {
+        command: DriveMessage,
+        duty_cycles: [f64; 4],
+    },
+    /// `DriveMessage::ApproachDistance` finished at `distance` meters
+    ApproachFinished {
+        distance: f32,
+    },
+    /// Average of the `measurements` (in meters), infinite distances are serialized as `null`
+    MeasureDistance {
+        measurement: f32,
+        measurements: Vec<f32>,
+    },
+    /// `(angle, distance)` pairs, angles in degrees measured clockwise
+    ScanDistance {
+        measurements: Vec<(f32, f32)>,
+    },
+    /// Decisions of the autopilot, `description` is only meant to be displayed
+    Autopilot(AutopilotResponse),
+    /// Wall following events, `description` is only meant to be displayed
+    FollowWall(WallFollowerResponse),
+    /// Progress of a path requested by the receiving session, `description` is only meant to be
+    /// displayed
+    FollowPath(PathFollowerResponse),
+    /// State of the mission after every change
+    Mission(MissionProgress),
+    /// Names of the mission files
+    Missions {
+        names: Vec<String>,
+    },
+    /// Id of the receiving `session`, the current `pilot` and all connected `sessions`
+    Sessions {
+        session: usize,
+        pilot: Option<usize>,
+        sessions: Vec<usize>,
+    },
+    Telemetry(TelemetrySnapshot),
+    /// The robot switched from the `previous` mode to `mode`
+    Mode {
+        mode: Mode,
+        previous: Mode,
+    },
+    /// The emergency stop was engaged by `cause`, drive commands are refused until it's reset
+    EmergencyStop {
+        cause: Cause,
+        reason: String,
+    },
+    /// The collision guard started or stopped (`intervention` is `null`) limiting motion toward
+    /// the obstacle `distance` meters in front of the sensor
+    CollisionGuard {
+        intervention: Option<Intervention>,
+        distance: Option<f32>,
+    },
+    /// `device` crashed and is re-initialized in `delay` seconds, `restarts` counts all its
+    /// restarts
+    DeviceRestarted {
+        device: Device,
+        restarts: usize,
+        delay: f64,
+    },
+}
+
+impl SocketPayload {
+    /// `MeasureDistance` payload averaging the `measurements`
+    pub fn measure_distance(measurements: Vec<f32>) -> Self {
+        let measurement = measurements.iter().sum::<f32>() / measurements.len() as f32;
+        Self::MeasureDistance {
+            measurement,
+            measurements,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SocketBody {
+    /// Request was accepted, its results will arrive as events
+    Ack,
+    /// Result of a request
+    Response(SocketPayload),
+    /// Something happened on the robot, regardless of who caused it
+    Event(SocketPayload),
+    Error(SocketError),
+}
+
+/// WebSocket to client frame
+#[derive(Debug, Serialize)]
+pub struct SocketFrame {
+    version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<RequestId>,
+    #[serde(flatten)]
+    body: SocketBody,
+}
+
+impl SocketFrame {
+    fn new(id: Option<RequestId>, body: SocketBody) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            body,
+        }
+    }
+
+    pub fn ack(id: Option<RequestId>) -> Self {
+        Self::new(id, SocketBody::Ack)
+    }
+
+    pub fn response(id: Option<RequestId>, payload: SocketPayload) -> Self {
+        Self::new(id, SocketBody::Response(payload))
+    }
+
+    pub fn event(payload: SocketPayload) -> Self {
+        Self::new(None, SocketBody::Event(payload))
+    }
+
+    pub fn error(id: Option<RequestId>, error: SocketError) -> Self {
+        Self::new(id, SocketBody::Error(error))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Failed to serialize message")
+    }
+}
+
+impl From<DriveResponse> for SocketFrame {
+    fn from(response: DriveResponse) -> Self {
+        match response {
+            DriveResponse::Ok {
+                message,
+                duty_cycles,
+            } => SocketFrame::event(SocketPayload::Move {
+                command: message,
+                duty_cycles,
+            }),
+            DriveResponse::Approached(distance) => {
+                SocketFrame::event(SocketPayload::ApproachFinished { distance })
+            }
+            DriveResponse::ApproachFailed(reason) => {
+                SocketFrame::error(None, SocketError::new(ErrorCode::ApproachFailed, reason))
+            }
+            DriveResponse::Err(e) => {
+                SocketFrame::error(None, SocketError::new(ErrorCode::DriveError, e))
+            }
+        }
+    }
+}
+
+impl From<ModeChanged> for SocketFrame {
+    fn from(changed: ModeChanged) -> Self {
+        SocketFrame::event(SocketPayload::Mode {
+            mode: changed.mode,
+            previous: changed.previous,
+        })
+    }
+}
+
+impl From<EmergencyStopped> for SocketFrame {
+    fn from(stopped: EmergencyStopped) -> Self {
+        SocketFrame::event(SocketPayload::EmergencyStop {
+            cause: stopped.cause,
+            reason: stopped.reason,
+        })
+    }
+}
+
+impl From<GuardIntervened> for SocketFrame {
+    fn from(intervened: GuardIntervened) -> Self {
+        SocketFrame::event(SocketPayload::CollisionGuard {
+            intervention: intervened.intervention,
+            distance: intervened.distance,
+        })
+    }
+}
+
+impl From<DeviceRestarted> for SocketFrame {
+    fn from(restarted: DeviceRestarted) -> Self {
+        SocketFrame::event(SocketPayload::DeviceRestarted {
+            device: restarted.device,
+            restarts: restarted.restarts,
+            delay: restarted.delay.as_secs_f64(),
+        })
+    }
+}
+
+impl From<ScanResult> for SocketFrame {
+    fn from(result: ScanResult) -> Self {
+        SocketFrame::event(SocketPayload::ScanDistance {
+            measurements: result.measurements,
+        })
+    }
+}
+
+impl From<AutopilotResponse> for SocketFrame {
+    fn from(response: AutopilotResponse) -> Self {
+        SocketFrame::event(SocketPayload::Autopilot(response))
+    }
+}
+
+impl From<MissionProgress> for SocketFrame {
+    fn from(progress: MissionProgress) -> Self {
+        SocketFrame::event(SocketPayload::Mission(progress))
+    }
+}
+
+impl From<WallFollowerResponse> for SocketFrame {
+    fn from(response: WallFollowerResponse) -> Self {
+        SocketFrame::event(SocketPayload::FollowWall(response))
+    }
+}
+
+impl From<PathFollowerResponse> for SocketFrame {
+    fn from(response: PathFollowerResponse) -> Self {
+        SocketFrame::event(SocketPayload::FollowPath(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse_error(text: &str) -> (Option<RequestId>, ErrorCode) {
+        let (id, e) = SocketRequest::parse(text).unwrap_err();
+        (id, e.code)
+    }
+
+    #[test]
+    fn parse_accepts_valid_requests() {
+        let request = SocketRequest::parse(
+            r#"{"version": 1, "id": 7, "message": "Move", "variant": "Move", "motion": "Forward", "speed": "Medium", "distance": 0.5}"#,
+        )
+        .unwrap();
+        assert_eq!(request.id, Some(json!(7)));
+        assert!(matches!(
+            request.message,
+            SocketMessage::Move(DriveMessage::Move { distance, .. }) if distance == 0.5
+        ));
+
+        // Version and id are optional
+        let request = SocketRequest::parse(r#"{"message": "MeasureDistance"}"#).unwrap();
+        assert_eq!(request.id, None);
+        assert!(matches!(request.message, SocketMessage::MeasureDistance));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_json() {
+        assert_eq!(parse_error("{\"id\": 1,"), (None, ErrorCode::InvalidJson));
+    }
+
+    #[test]
+    fn parse_rejects_unsupported_versions() {
+        assert_eq!(
+            parse_error(r#"{"version": 2, "id": "a", "message": "MeasureDistance"}"#),
+            (Some(json!("a")), ErrorCode::UnsupportedVersion)
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_messages() {
+        assert_eq!(
+            parse_error(r#"{"id": 3, "message": "Fly"}"#),
+            (Some(json!(3)), ErrorCode::InvalidMessage)
+        );
+    }
+
+    #[test]
+    fn parse_rejects_commands_which_can_not_be_executed() {
+        // Too slow to move
+        assert_eq!(
+            parse_error(
+                r#"{"id": 4, "message": "Move", "variant": "Move", "motion": "Forward", "speed": {"Manual": 0.1}, "distance": 0.5}"#
+            ),
+            (Some(json!(4)), ErrorCode::InvalidMessage)
+        );
+        // Rotations aren't moves
+        assert_eq!(
+            parse_error(
+                r#"{"id": 5, "message": "Move", "variant": "Move", "motion": "LeftRot", "speed": "High", "distance": 0.5}"#
+            ),
+            (Some(json!(5)), ErrorCode::InvalidMessage)
+        );
+        assert_eq!(
+            parse_error(
+                r#"{"id": 6, "message": "FollowWall", "variant": "Start", "distance": 0.2, "speed": 0.5, "max_correction": -1, "max_deviation": 0.3, "kp": 1, "ki": 0, "kd": 0}"#
+            ),
+            (Some(json!(6)), ErrorCode::InvalidMessage)
+        );
+        assert_eq!(
+            parse_error(r#"{"id": 8, "message": "Mission", "variant": "Run", "steps": []}"#),
+            (Some(json!(8)), ErrorCode::InvalidMessage)
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix::prelude::*;
+use log::{debug, error, info};
+use serde::Serialize;
+
+use crate::{
+    distance_scan::ScanResult, drive::DriveMessage, hc_sr04::HcSr04Result, protocol::SocketMessage,
+    shutdown::Shutdown,
+};
+
+/// Directory of the session files, unless configured otherwise
+pub const DEFAULT_DIR: &str = "recordings";
+
+/// A new session file is started once the current one grows beyond this size
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024; // in bytes
+
+/// The oldest session files are deleted to keep at most this many
+const MAX_FILES: usize = 16;
+
+/// How often buffered records are written to the session file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+const FILE_PREFIX: &str = "session-";
+const FILE_EXTENSION: &str = "jsonl";
+
+/// Flight recorder, writes everything sent to and measured by the robot to rotating JSON Lines
+/// session files. Registered as a system service, so that any actor can send it `Record`s with
+/// `record`
+pub struct Recorder {
+    dir: PathBuf,
+    /// Timestamps of records are relative to it, as it's monotonic
+    start: Instant,
+    file: Option<BufWriter<File>>,
+    /// Bytes written to `file`
+    file_size: u64,
+    /// Whether the last record couldn't be written, so that failures are only logged once
+    failing: bool,
+}
+
+impl Recorder {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            start: Instant::now(),
+            file: None,
+            file_size: 0,
+            failing: false,
+        }
+    }
+
+    fn is_session_file(name: &str) -> bool {
+        name.starts_with(FILE_PREFIX)
+            && Path::new(name)
+                .extension()
+                .is_some_and(|ext| ext == FILE_EXTENSION)
+    }
+
+    /// Session files, oldest first
+    fn session_files(&self) -> io::Result<Vec<(String, fs::Metadata)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if let Some(name) = entry.file_name().to_str() {
+                if metadata.is_file() && Self::is_session_file(name) {
+                    files.push((name.to_string(), metadata));
+                }
+            }
+        }
+        // Names contain the creation time
+        files.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then(a.cmp(b)));
+        Ok(files)
+    }
+
+    /// Closes the current session file, starts a new one and deletes the oldest ones
+    fn rotate(&mut self) -> io::Result<()> {
+        self.flush();
+        self.file = None;
+        fs::create_dir_all(&self.dir)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards");
+        let path = self
+            .dir
+            .join(format!("{FILE_PREFIX}{}.{FILE_EXTENSION}", now.as_millis()));
+        info!("recording to {}", path.display());
+        self.file = Some(BufWriter::new(File::create(path)?));
+        self.file_size = 0;
+
+        let files = self.session_files()?;
+        for (name, _) in files.iter().take(files.len().saturating_sub(MAX_FILES)) {
+            info!("deleting old session file {name}");
+            fs::remove_file(self.dir.join(name))?;
+        }
+
+        // Relates the monotonic timestamps of the file to the wall-clock time
+        self.write(&Record::Session {
+            unix_time: now.as_secs_f64(),
+        })
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.file.is_none() || self.file_size > MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        let line = RecordLine {
+            t: self.start.elapsed().as_secs_f64(),
+            record,
+        };
+        let mut text = serde_json::to_string(&line)?;
+        text.push('\n');
+        if let Some(file) = &mut self.file {
+            file.write_all(text.as_bytes())?;
+            self.file_size += text.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.flush() {
+                error!("failed to flush session file: {e}");
+            }
+        }
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new(DEFAULT_DIR.into())
+    }
+}
+
+impl Actor for Recorder {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(1024);
+        ctx.run_interval(FLUSH_INTERVAL, |act, _ctx| act.flush());
+        info!("actor started");
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.flush();
+        info!("actor stopped");
+    }
+}
+
+impl Supervised for Recorder {}
+
+impl SystemService for Recorder {}
+
+/// Something that happened on the robot, written as a line of the session file
+#[derive(Debug, Message, Serialize)]
+#[rtype(result = "()")]
+#[serde(tag = "variant")]
+pub enum Record {
+    /// First record of every session file
+    Session {
+        unix_time: f64,
+    },
+    /// Request received from a WebSocket session
+    SocketMessage {
+        session: Option<usize>,
+        message: SocketMessage,
+    },
+    /// Command applied by `Drive`
+    Drive {
+        message: DriveMessage,
+        duty_cycles: [f64; 4],
+    },
+    Distance(HcSr04Result),
+    Scan(ScanResult),
+}
+
+/// `Record` with the seconds elapsed since the recorder started
+#[derive(Serialize)]
+struct RecordLine<'a> {
+    t: f64,
+    #[serde(flatten)]
+    record: &'a Record,
+}
+
+impl Handler<Record> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, msg: Record, _ctx: &mut Self::Context) -> Self::Result {
+        match self.write(&msg) {
+            Ok(()) => self.failing = false,
+            Err(e) => {
+                // Don't keep writing to a file which may be broken
+                self.file = None;
+                if !self.failing {
+                    error!("failed to record {msg:?}: {e}");
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
+
+/// Sends `record` to the flight recorder
+pub fn record(record: Record) {
+    debug!("recording {record:?}");
+    Recorder::from_registry().do_send(record);
+}
+
+impl Handler<Shutdown> for Recorder {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        self.flush();
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionFile {
+    pub name: String,
+    /// In bytes
+    pub size: u64,
+}
+
+/// Lists the session files, oldest first
+#[derive(Debug, Message)]
+#[rtype(result = "io::Result<Vec<SessionFile>>")]
+pub struct ListSessionFiles;
+
+impl Handler<ListSessionFiles> for Recorder {
+    type Result = io::Result<Vec<SessionFile>>;
+
+    fn handle(&mut self, _msg: ListSessionFiles, _ctx: &mut Self::Context) -> Self::Result {
+        self.flush();
+        Ok(self
+            .session_files()?
+            .into_iter()
+            .map(|(name, metadata)| SessionFile {
+                name,
+                size: metadata.len(),
+            })
+            .collect())
+    }
+}
+
+/// Requests the path of a session file, `None` if there is no such file
+#[derive(Debug, Message)]
+#[rtype(result = "Option<PathBuf>")]
+pub struct GetSessionFile(pub String);
+
+impl Handler<GetSessionFile> for Recorder {
+    type Result = Option<PathBuf>;
+
+    fn handle(&mut self, msg: GetSessionFile, _ctx: &mut Self::Context) -> Self::Result {
+        // Only plain names of session files, so that other files can't be read
+        let is_name = Path::new(&msg.0).file_name() == Some(msg.0.as_ref());
+        if !is_name || !Self::is_session_file(&msg.0) {
+            return None;
+        }
+        // Downloads include everything recorded so far
+        self.flush();
+        let path = self.dir.join(msg.0);
+        path.is_file().then_some(path)
+    }
+}
@@ -0,0 +1,742 @@
+use std::{
+    collections::BTreeSet,
+    f32::consts::PI,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix::{fut::wrap_future, prelude::*};
+use actix_web::web::Data;
+use actix_web_actors::ws;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    distance_scan::ScanResult,
+    drive::{Drive, DriveMessage, DriveState, GetDriveState, Speed, ROBOT_RADIUS},
+    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response, MAX_RANGE},
+    metrics::METRICS,
+    mode::{Command, ModeManager},
+    protocol::{ErrorCode, SocketError},
+    server::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL},
+    sessions::{GetSessions, ObserveScans, ScanObserver, Sessions},
+    shutdown::Shutdown,
+};
+
+/// How often `/range` is measured while subscribed
+const RANGE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often `/odom` is published while subscribed
+const ODOM_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the robot keeps moving after the last `/cmd_vel` message
+const CMD_VEL_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MIN_RANGE: f32 = 0.02; // in meters
+
+/// Opening angle of the HC-SR04 (about 15 deg)
+const FIELD_OF_VIEW: f32 = 0.26; // in radians
+
+/// Reported for readings without an echo. JSON has no infinity, and ROS clients discard values
+/// outside of `[min_range, max_range]`
+const NO_ECHO_RANGE: f32 = MAX_RANGE + 1.;
+
+const BASE_FRAME_ID: &str = "base_link";
+const ODOM_FRAME_ID: &str = "odom";
+
+const HEADER_DEFINITION: &str = "
+================================================================================
+MSG: std_msgs/Header
+uint32 seq
+time stamp
+string frame_id
+";
+
+const VECTOR3_DEFINITION: &str = "
+================================================================================
+MSG: geometry_msgs/Vector3
+float64 x
+float64 y
+float64 z
+";
+
+const TWIST_DEFINITION: &str = "geometry_msgs/Vector3 linear
+geometry_msgs/Vector3 angular
+";
+
+const RANGE_DEFINITION: &str = "Header header
+uint8 ULTRASOUND=0
+uint8 INFRARED=1
+uint8 radiation_type
+float32 field_of_view
+float32 min_range
+float32 max_range
+float32 range
+";
+
+const LASER_SCAN_DEFINITION: &str = "Header header
+float32 angle_min
+float32 angle_max
+float32 angle_increment
+float32 time_increment
+float32 scan_time
+float32 range_min
+float32 range_max
+float32[] ranges
+float32[] intensities
+";
+
+const ODOMETRY_DEFINITION: &str = "Header header
+string child_frame_id
+geometry_msgs/PoseWithCovariance pose
+geometry_msgs/TwistWithCovariance twist
+";
+
+const ODOMETRY_DEPENDENCIES: &str = "
+================================================================================
+MSG: geometry_msgs/PoseWithCovariance
+geometry_msgs/Pose pose
+float64[36] covariance
+================================================================================
+MSG: geometry_msgs/Pose
+geometry_msgs/Point position
+geometry_msgs/Quaternion orientation
+================================================================================
+MSG: geometry_msgs/Point
+float64 x
+float64 y
+float64 z
+================================================================================
+MSG: geometry_msgs/Quaternion
+float64 x
+float64 y
+float64 z
+float64 w
+================================================================================
+MSG: geometry_msgs/TwistWithCovariance
+geometry_msgs/Twist twist
+float64[36] covariance
+================================================================================
+MSG: geometry_msgs/Twist
+geometry_msgs/Vector3 linear
+geometry_msgs/Vector3 angular
+";
+
+/// Topics supported by the bridge
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Topic {
+    /// Subscribed by the robot, drives it
+    CmdVel,
+    Scan,
+    Range,
+    Odom,
+}
+
+impl Topic {
+    const ALL: [Topic; 4] = [Topic::CmdVel, Topic::Scan, Topic::Range, Topic::Odom];
+
+    fn parse(name: &str) -> Result<Self, SocketError> {
+        Self::ALL
+            .into_iter()
+            .find(|topic| topic.name() == name)
+            .ok_or_else(|| {
+                SocketError::new(ErrorCode::InvalidMessage, format!("unknown topic {name}"))
+            })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Topic::CmdVel => "/cmd_vel",
+            Topic::Scan => "/scan",
+            Topic::Range => "/range",
+            Topic::Odom => "/odom",
+        }
+    }
+
+    fn message_type(self) -> &'static str {
+        match self {
+            Topic::CmdVel => "geometry_msgs/Twist",
+            Topic::Scan => "sensor_msgs/LaserScan",
+            Topic::Range => "sensor_msgs/Range",
+            Topic::Odom => "nav_msgs/Odometry",
+        }
+    }
+
+    /// Full text of the message definition, including dependencies, as in `rosapi`
+    fn definition(self) -> String {
+        match self {
+            Topic::CmdVel => [TWIST_DEFINITION, VECTOR3_DEFINITION].concat(),
+            Topic::Scan => [LASER_SCAN_DEFINITION, HEADER_DEFINITION].concat(),
+            Topic::Range => [RANGE_DEFINITION, HEADER_DEFINITION].concat(),
+            Topic::Odom => [
+                ODOMETRY_DEFINITION,
+                HEADER_DEFINITION,
+                ODOMETRY_DEPENDENCIES,
+                VECTOR3_DEFINITION,
+            ]
+            .concat(),
+        }
+    }
+}
+
+/// Client to bridge operations of the rosbridge v2 protocol, unsupported fields are ignored
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum RosBridgeRequest {
+    Advertise { topic: String },
+    Unadvertise { topic: String },
+    Publish { topic: String, msg: Value },
+    Subscribe { topic: String },
+    Unsubscribe { topic: String },
+    CallService { service: String, id: Option<Value> },
+}
+
+/// Bridge to client operations
+#[derive(Debug, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum RosBridgeFrame {
+    Publish {
+        topic: &'static str,
+        msg: Value,
+    },
+    ServiceResponse {
+        service: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<Value>,
+        values: Value,
+        result: bool,
+    },
+    Status {
+        level: &'static str,
+        msg: String,
+    },
+}
+
+// ROS 1 messages
+
+#[derive(Debug, Serialize)]
+struct Time {
+    secs: u32,
+    nsecs: u32,
+}
+
+impl Time {
+    /// `since_epoch` as used by `HcSr04Result`
+    fn from_duration(since_epoch: Duration) -> Self {
+        Self {
+            secs: since_epoch.as_secs() as u32,
+            nsecs: since_epoch.subsec_nanos(),
+        }
+    }
+
+    fn now() -> Self {
+        Self::from_duration(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards"),
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Header {
+    seq: u32,
+    stamp: Time,
+    frame_id: &'static str,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Vector3 {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct TwistMessage {
+    linear: Vector3,
+    angular: Vector3,
+}
+
+#[derive(Debug, Serialize)]
+struct RangeMessage {
+    header: Header,
+    radiation_type: u8,
+    field_of_view: f32,
+    min_range: f32,
+    max_range: f32,
+    range: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct LaserScanMessage {
+    header: Header,
+    angle_min: f32,
+    angle_max: f32,
+    angle_increment: f32,
+    time_increment: f32,
+    scan_time: f32,
+    range_min: f32,
+    range_max: f32,
+    ranges: Vec<f32>,
+    intensities: Vec<f32>,
+}
+
+/// Serializes a `nav_msgs/Odometry` message, ROS and `Pose` share the same conventions
+fn odometry_message(header: Header, drive: &DriveState) -> Value {
+    let (pose, twist) = (drive.pose, drive.twist);
+    let covariance = [0.; 36].to_vec();
+    json!({
+        "header": header,
+        "child_frame_id": BASE_FRAME_ID,
+        "pose": {
+            "pose": {
+                "position": {"x": pose.x, "y": pose.y, "z": 0.},
+                "orientation": {
+                    "x": 0.,
+                    "y": 0.,
+                    "z": (pose.heading / 2.).sin(),
+                    "w": (pose.heading / 2.).cos(),
+                },
+            },
+            "covariance": covariance,
+        },
+        "twist": {
+            "twist": {
+                "linear": {"x": twist.x, "y": twist.y, "z": 0.},
+                "angular": {"x": 0., "y": 0., "z": twist.rotation},
+            },
+            "covariance": covariance,
+        },
+    })
+}
+
+/// WebSocket speaking the rosbridge v2 JSON protocol, for ROS tools like Foxglove or roslibjs.
+/// Drives the robot with `/cmd_vel` while no WebSocket session is the pilot, and publishes `/scan`,
+/// `/range` and `/odom` to subscribers
+pub struct RosBridge {
+    hb: Instant,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    drive_data: Data<Mutex<Addr<Drive>>>,
+    hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+    subscriptions: BTreeSet<Topic>,
+    /// Sequence number of the last published header
+    seq: u32,
+    /// Whether a `/range` measurement was requested and hasn't arrived yet
+    range_pending: bool,
+    /// Stops the robot if `/cmd_vel` messages stop arriving
+    cmd_vel_timeout: Option<SpawnHandle>,
+    /// Whether the client was told that `/cmd_vel` is ignored because of the pilot
+    cmd_vel_rejected: bool,
+    /// Unauthenticated connections can't publish `/cmd_vel`
+    read_only: bool,
+}
+
+impl RosBridge {
+    pub fn new(
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+        drive_data: Data<Mutex<Addr<Drive>>>,
+        hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        sessions_data: Data<Mutex<Addr<Sessions>>>,
+        read_only: bool,
+    ) -> Self {
+        Self {
+            hb: Instant::now(),
+            mode_manager_data,
+            drive_data,
+            hc_sr04_data,
+            sessions_data,
+            subscriptions: BTreeSet::new(),
+            seq: 0,
+            range_pending: false,
+            cmd_vel_timeout: None,
+            cmd_vel_rejected: false,
+            read_only,
+        }
+    }
+
+    fn send_frame(&self, frame: RosBridgeFrame, ctx: &mut <Self as Actor>::Context) {
+        let text = serde_json::to_string(&frame).expect("Failed to serialize message");
+        debug!("sending {text} to client");
+        ctx.text(text);
+    }
+
+    fn send_status(&self, level: &'static str, msg: String, ctx: &mut <Self as Actor>::Context) {
+        info!("{level}: {msg}");
+        self.send_frame(RosBridgeFrame::Status { level, msg }, ctx);
+    }
+
+    fn header(&mut self, stamp: Time, frame_id: &'static str) -> Header {
+        self.seq = self.seq.wrapping_add(1);
+        Header {
+            seq: self.seq,
+            stamp,
+            frame_id,
+        }
+    }
+
+    /// Publishes `msg` on `topic` if the client is subscribed to it
+    fn publish(&self, topic: Topic, msg: impl Serialize, ctx: &mut <Self as Actor>::Context) {
+        if !self.subscriptions.contains(&topic) {
+            return;
+        }
+        let msg = serde_json::to_value(msg).expect("Failed to serialize message");
+        self.send_frame(
+            RosBridgeFrame::Publish {
+                topic: topic.name(),
+                msg,
+            },
+            ctx,
+        );
+    }
+
+    fn request_handler(&mut self, text: &str, ctx: &mut <Self as Actor>::Context) {
+        let result = serde_json::from_str(text)
+            .map_err(|e| SocketError::new(ErrorCode::InvalidMessage, e))
+            .and_then(|request| self.handle_request(request, ctx));
+        if let Err(e) = result {
+            self.send_status("error", e.description, ctx);
+        }
+    }
+
+    fn handle_request(
+        &mut self,
+        request: RosBridgeRequest,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        debug!("received {request:?}");
+        match request {
+            RosBridgeRequest::Advertise { topic } | RosBridgeRequest::Unadvertise { topic } => {
+                if Topic::parse(&topic)? != Topic::CmdVel {
+                    return Err(SocketError::new(
+                        ErrorCode::InvalidMessage,
+                        format!("only {} can be published", Topic::CmdVel.name()),
+                    ));
+                }
+            }
+            RosBridgeRequest::Publish { topic, msg } => match Topic::parse(&topic)? {
+                Topic::CmdVel => self.cmd_vel_handler(msg, ctx)?,
+                topic => {
+                    return Err(SocketError::new(
+                        ErrorCode::InvalidMessage,
+                        format!("{} can't be published", topic.name()),
+                    ))
+                }
+            },
+            RosBridgeRequest::Subscribe { topic } => {
+                self.subscriptions.insert(Topic::parse(&topic)?);
+            }
+            RosBridgeRequest::Unsubscribe { topic } => {
+                self.subscriptions.remove(&Topic::parse(&topic)?);
+            }
+            RosBridgeRequest::CallService { service, id } => self.service_handler(service, id, ctx),
+        }
+        Ok(())
+    }
+
+    /// Answers the `rosapi` topic queries used by ROS tools to discover topics
+    fn service_handler(
+        &self,
+        service: String,
+        id: Option<Value>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let topics: Vec<_> = Topic::ALL.iter().map(|topic| topic.name()).collect();
+        let types: Vec<_> = Topic::ALL
+            .iter()
+            .map(|topic| topic.message_type())
+            .collect();
+        let (values, result) = match service.as_str() {
+            "/rosapi/topics" => (json!({"topics": topics, "types": types}), true),
+            "/rosapi/topics_and_raw_types" => {
+                let typedefs_full_text: Vec<_> =
+                    Topic::ALL.iter().map(|topic| topic.definition()).collect();
+                let values = json!({
+                    "topics": topics,
+                    "types": types,
+                    "typedefs_full_text": typedefs_full_text,
+                });
+                (values, true)
+            }
+            _ => (json!(format!("service {service} is not available")), false),
+        };
+        self.send_frame(
+            RosBridgeFrame::ServiceResponse {
+                service,
+                id,
+                values,
+                result,
+            },
+            ctx,
+        );
+    }
+
+    /// Drives the robot with a `geometry_msgs/Twist`. Velocities are converted to duty cycles
+    /// linearly, which is only a rough approximation of the movement calibration
+    fn cmd_vel_handler(
+        &mut self,
+        msg: Value,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        if self.read_only {
+            return Err(SocketError::new(
+                ErrorCode::Unauthorized,
+                "read-only connection can't publish /cmd_vel",
+            ));
+        }
+        let twist: TwistMessage = serde_json::from_value(msg).map_err(|e| {
+            SocketError::new(
+                ErrorCode::InvalidMessage,
+                format!("invalid geometry_msgs/Twist: {e}"),
+            )
+        })?;
+        let max_velocity = Speed::High.get_velocity();
+        // ROS uses y left and counterclockwise rotation, drive y right and clockwise rotation
+        let message = DriveMessage::Velocity {
+            x: twist.linear.x / max_velocity,
+            y: -twist.linear.y / max_velocity,
+            rotation: -twist.angular.z * ROBOT_RADIUS / max_velocity,
+        };
+        let moving = twist.linear.x != 0. || twist.linear.y != 0. || twist.angular.z != 0.;
+
+        let sessions_addr = self.sessions_data.lock()?.clone();
+        let mode_manager_addr = self.mode_manager_data.lock()?.clone();
+        ctx.spawn(wrap_future(sessions_addr.send(GetSessions)).map(
+            move |result, act: &mut Self, ctx| {
+                match result {
+                    Ok(sessions) => match sessions.pilot {
+                        None => {
+                            act.cmd_vel_rejected = false;
+                            debug!("sending {message:?} to mode manager");
+                            mode_manager_addr.do_send(Command::manual(message));
+                            act.restart_cmd_vel_timeout(moving, ctx);
+                        }
+                        // Don't flood the client with a status for every message
+                        Some(pilot) if !act.cmd_vel_rejected => {
+                            act.cmd_vel_rejected = true;
+                            act.send_status(
+                                "warning",
+                                format!("session {pilot} is the pilot, ignoring /cmd_vel"),
+                                ctx,
+                            );
+                        }
+                        Some(_) => (),
+                    },
+                    Err(e) => act.send_status("error", e.to_string(), ctx),
+                }
+            },
+        ));
+        Ok(())
+    }
+
+    fn restart_cmd_vel_timeout(&mut self, moving: bool, ctx: &mut <Self as Actor>::Context) {
+        if let Some(handle) = self.cmd_vel_timeout.take() {
+            ctx.cancel_future(handle);
+        }
+        if moving {
+            self.cmd_vel_timeout = Some(ctx.run_later(CMD_VEL_TIMEOUT, |act, _ctx| {
+                info!("no /cmd_vel received in {CMD_VEL_TIMEOUT:?}, stopping");
+                act.cmd_vel_timeout = None;
+                act.stop_robot();
+            }));
+        }
+    }
+
+    fn stop_robot(&self) {
+        match self.mode_manager_data.lock() {
+            Ok(mode_manager_addr) => {
+                mode_manager_addr.do_send(Command::manual(DriveMessage::Disable))
+            }
+            Err(e) => error!("{e:?}"),
+        }
+    }
+
+    fn request_range(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if !self.subscriptions.contains(&Topic::Range) || self.range_pending {
+            return;
+        }
+        match self.hc_sr04_data.lock() {
+            Ok(hc_sr04_addr) => {
+                hc_sr04_addr.do_send(HcSr04Message::Single(ctx.address().recipient()));
+                self.range_pending = true;
+            }
+            Err(e) => error!("{e:?}"),
+        }
+    }
+
+    fn request_odom(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if !self.subscriptions.contains(&Topic::Odom) {
+            return;
+        }
+        let drive_addr = match self.drive_data.lock() {
+            Ok(drive_addr) => drive_addr.clone(),
+            Err(e) => {
+                error!("{e:?}");
+                return;
+            }
+        };
+        ctx.spawn(wrap_future(drive_addr.send(GetDriveState)).map(
+            |result, act: &mut Self, ctx| match result {
+                Ok(drive) => {
+                    let header = act.header(Time::now(), ODOM_FRAME_ID);
+                    act.publish(Topic::Odom, odometry_message(header, &drive), ctx);
+                }
+                Err(e) => error!("failed to get drive state: {e}"),
+            },
+        ));
+    }
+
+    /// Starts heartbeat process
+    fn hb(&self, ctx: &mut <Self as Actor>::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+                error!("client heartbeat failed, disconnecting");
+                METRICS
+                    .heartbeat_timeouts
+                    .with_label_values(&["rosbridge"])
+                    .inc();
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+}
+
+impl Actor for RosBridge {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("actor started");
+        METRICS
+            .websocket_connections
+            .with_label_values(&["rosbridge"])
+            .inc();
+        match self.sessions_data.lock() {
+            Ok(sessions_addr) => sessions_addr.do_send(ObserveScans(ScanObserver {
+                scans: ctx.address().recipient(),
+                shutdown: ctx.address().recipient(),
+            })),
+            Err(e) => error!("{e:?}"),
+        }
+        ctx.run_interval(RANGE_INTERVAL, |act, ctx| act.request_range(ctx));
+        ctx.run_interval(ODOM_INTERVAL, |act, ctx| act.request_odom(ctx));
+        self.hb(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        METRICS
+            .websocket_connections
+            .with_label_values(&["rosbridge"])
+            .dec();
+        // Don't leave the robot moving after the connection drops
+        if self.cmd_vel_timeout.is_some() {
+            self.stop_robot();
+        }
+        info!("actor stopped");
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for RosBridge {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.hb = Instant::now();
+                ctx.pong(&msg);
+            }
+            Ok(ws::Message::Pong(_)) => {
+                self.hb = Instant::now();
+            }
+            Ok(ws::Message::Text(text)) => self.request_handler(&text, ctx),
+            Ok(ws::Message::Binary(_)) => {
+                self.send_status("error", "binary messages aren't supported".to_string(), ctx)
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => ctx.stop(),
+        }
+    }
+}
+
+impl Handler<HcSr04Response> for RosBridge {
+    type Result = ();
+
+    fn handle(&mut self, msg: HcSr04Response, ctx: &mut Self::Context) {
+        self.range_pending = false;
+        match msg {
+            HcSr04Response::Ok(HcSr04Measurement::Single(result)) => {
+                let range = if result.distance.is_finite() {
+                    result.distance
+                } else {
+                    NO_ECHO_RANGE
+                };
+                let message = RangeMessage {
+                    header: self.header(Time::from_duration(result.time), BASE_FRAME_ID),
+                    radiation_type: 0,
+                    field_of_view: FIELD_OF_VIEW,
+                    min_range: MIN_RANGE,
+                    max_range: MAX_RANGE,
+                    range,
+                };
+                self.publish(Topic::Range, message, ctx);
+            }
+            HcSr04Response::Ok(HcSr04Measurement::Multiple(_)) => (),
+            HcSr04Response::Err(e) => {
+                self.send_status("warning", format!("distance measurement failed: {e}"), ctx)
+            }
+        }
+    }
+}
+
+impl Handler<Shutdown> for RosBridge {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Shutdown, ctx: &mut Self::Context) -> Self::Result {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("robot is shutting down".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<ScanResult> for RosBridge {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScanResult, ctx: &mut Self::Context) {
+        if msg.measurements.is_empty() {
+            return;
+        }
+        // Measurements are taken at roughly equal angles, put each into the nearest bin
+        let bins = msg.measurements.len();
+        let angle_increment = 2. * PI / bins as f32;
+        let mut ranges = vec![NO_ECHO_RANGE; bins];
+        for (angle, distance) in msg.measurements {
+            // Scan angles are measured clockwise in degrees, ROS angles counterclockwise
+            let angle = (-angle.to_radians()).rem_euclid(2. * PI);
+            let bin = (angle / angle_increment).round() as usize % bins;
+            if distance.is_finite() {
+                ranges[bin] = distance;
+            }
+        }
+        let message = LaserScanMessage {
+            header: self.header(Time::now(), BASE_FRAME_ID),
+            angle_min: 0.,
+            angle_max: angle_increment * (bins - 1) as f32,
+            angle_increment,
+            time_increment: 0.,
+            scan_time: 0.,
+            range_min: MIN_RANGE,
+            range_max: MAX_RANGE,
+            ranges,
+            intensities: Vec::new(),
+        };
+        self.publish(Topic::Scan, message, ctx);
+    }
+}
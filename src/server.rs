@@ -1,52 +1,255 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
+use actix::dev::ToEnvelope;
+use actix::fut::wrap_future;
 use actix::prelude::*;
 use actix_web::web::Data;
 use actix_web_actors::ws;
 
 use log::{error, info, debug};
-use serde::{Deserialize, Serialize};
 
+use crate::api::{JobMessage, Jobs, ListMissions};
+use crate::autopilot::{Autopilot, AutopilotMessage};
+use crate::binary_protocol::{self, VelocityFrame};
 use crate::distance_scan::{Scanner, ScannerMessage};
-use crate::drive::{Drive, DriveMessage, DriveResponse};
-use crate::hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response, Recipient};
+use crate::drive::{Drive, DriveMessage};
+use crate::estop::{Cause, EStop, ResetEmergencyStop};
+use crate::hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response};
+use crate::metrics::METRICS;
+use crate::mission::MissionMessage;
+use crate::mode::{Acquire, Command, Mode, ModeManager, Preempted};
 use crate::movement_calibration::{Calibrator, CalibratorMessage};
+use crate::path_following::{PathFollower, PathFollowerMessage, PathFollowerResponse};
+use crate::protocol::{
+    ErrorCode, RequestId, SocketError, SocketFrame, SocketMessage, SocketPayload, SocketRequest,
+};
+use crate::recorder::{self, Record};
+use crate::sessions::{
+    ClientFrame, Connect, ControlMessage, Disconnect, Sessions, SessionsChanged,
+};
+use crate::shutdown::Shutdown;
+use crate::telemetry::{Telemetry, TelemetryMessage, TelemetrySnapshot};
+use crate::wall_following::{WallFollower, WallFollowerMessage};
 
 /// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
 /// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the robot keeps moving after the last binary velocity frame
+const VELOCITY_TIMEOUT: Duration = Duration::from_millis(500);
 
 pub struct WebSocket {
     hb: Instant,
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
     drive_data: Data<Mutex<Addr<Drive>>>,
     hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    sessions_data: Data<Mutex<Addr<Sessions>>>,
+    telemetry_data: Data<Mutex<Addr<Telemetry>>>,
+    /// Runs missions, which all clients share
+    jobs_data: Data<Mutex<Addr<Jobs>>>,
+    estop: Data<EStop>,
+    /// Id assigned by `Sessions` once connected
+    session_id: Option<usize>,
+    /// Whether this session is allowed to control the robot
+    is_pilot: bool,
+    /// Unauthenticated sessions can't become the pilot
+    read_only: bool,
     calibrator_addr: Option<Addr<Calibrator>>,
     scanner_addr: Option<Addr<Scanner>>,
-}
-
-#[derive(Deserialize)]
-#[serde(tag = "message")]
-enum SocketMessage {
-    Move(DriveMessage),
-    MeasureDistance,
-    CalibrateMovement(CalibratorMessage),
-    ScanDistance(ScannerMessage),
+    autopilot_addr: Option<Addr<Autopilot>>,
+    wall_follower_addr: Option<Addr<WallFollower>>,
+    path_follower_addr: Option<Addr<PathFollower>>,
+    /// Ids of distance measurement requests, in the order they were sent to the HC-SR04
+    pending_measurements: VecDeque<Option<RequestId>>,
+    /// Sequence number of the last accepted binary velocity frame
+    last_velocity_sequence: Option<u32>,
+    /// Stops the robot if binary velocity frames stop arriving
+    velocity_timeout: Option<SpawnHandle>,
+    /// Whether telemetry is sent in binary frames
+    binary_telemetry: bool,
+    telemetry_sequence: u32,
 }
 
 impl WebSocket {
+    #[allow(clippy::too_many_arguments)] // shared state of the handlers
     pub fn new(
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
         drive_data: Data<Mutex<Addr<Drive>>>,
         hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        sessions_data: Data<Mutex<Addr<Sessions>>>,
+        telemetry_data: Data<Mutex<Addr<Telemetry>>>,
+        jobs_data: Data<Mutex<Addr<Jobs>>>,
+        estop: Data<EStop>,
+        read_only: bool,
     ) -> Self {
         Self {
             hb: Instant::now(),
+            mode_manager_data,
             drive_data,
             hc_sr04_data,
+            sessions_data,
+            telemetry_data,
+            jobs_data,
+            estop,
+            session_id: None,
+            is_pilot: false,
+            read_only,
             calibrator_addr: None,
             scanner_addr: None,
+            autopilot_addr: None,
+            wall_follower_addr: None,
+            path_follower_addr: None,
+            pending_measurements: VecDeque::new(),
+            last_velocity_sequence: None,
+            velocity_timeout: None,
+            binary_telemetry: false,
+            telemetry_sequence: 0,
+        }
+    }
+
+    /// Serializes `frame` and sends it to the client
+    fn send_frame(&self, frame: SocketFrame, ctx: &mut <Self as Actor>::Context) {
+        let text = frame.to_json();
+        info!("sending {text} to client");
+        ctx.text(text);
+    }
+
+    /// Parses a client frame and dispatches the request. Failures are reported to the client as
+    /// error frames
+    fn request_handler(&mut self, text: &str, ctx: &mut <Self as Actor>::Context) {
+        let request = SocketRequest::parse(text);
+        if let Ok(SocketRequest { message, .. }) = &request {
+            recorder::record(Record::SocketMessage {
+                session: self.session_id,
+                message: message.clone(),
+            });
+        }
+        let (id, result) = match request {
+            Ok(SocketRequest { id, message }) if message.requires_pilot() && !self.is_pilot => {
+                let description = "only the pilot can control the robot, take control first";
+                (id, Err(SocketError::new(ErrorCode::NotPilot, description)))
+            }
+            Ok(SocketRequest { id, message }) => {
+                let result = match message {
+                    SocketMessage::Move(message) => self.motion_handler(message, id.clone(), ctx),
+                    SocketMessage::MeasureDistance => {
+                        self.measure_distance_handler(id.clone(), ctx)
+                    }
+                    SocketMessage::CalibrateMovement(message) => {
+                        self.calibrator_handler(message, id.clone(), ctx)
+                    }
+                    SocketMessage::ScanDistance(message) => {
+                        self.scanner_handler(message, id.clone(), ctx)
+                    }
+                    SocketMessage::Autopilot(message) => {
+                        self.autopilot_handler(message, id.clone(), ctx)
+                    }
+                    SocketMessage::FollowWall(message) => {
+                        self.wall_follower_handler(message, id.clone(), ctx)
+                    }
+                    SocketMessage::FollowPath(message) => {
+                        self.path_follower_handler(message, id.clone(), ctx)
+                    }
+                    SocketMessage::Mission(message) => {
+                        self.mission_handler(message, id.clone(), ctx)
+                    }
+                    SocketMessage::ListMissions => self.list_missions_handler(id.clone(), ctx),
+                    SocketMessage::TakeControl => {
+                        self.control_handler(ControlMessage::Take, id.clone(), ctx)
+                    }
+                    SocketMessage::ReleaseControl => {
+                        self.control_handler(ControlMessage::Release, id.clone(), ctx)
+                    }
+                    SocketMessage::HandOverControl { session } => self.control_handler(
+                        |from| ControlMessage::HandOver { from, to: session },
+                        id.clone(),
+                        ctx,
+                    ),
+                    SocketMessage::SubscribeTelemetry { binary } => {
+                        self.binary_telemetry = binary;
+                        self.telemetry_handler(TelemetryMessage::Subscribe, id.clone(), ctx)
+                    }
+                    SocketMessage::UnsubscribeTelemetry => {
+                        self.telemetry_handler(TelemetryMessage::Unsubscribe, id.clone(), ctx)
+                    }
+                    SocketMessage::EmergencyStop => self.emergency_stop_handler(id.clone(), ctx),
+                    SocketMessage::ResetEmergencyStop => {
+                        self.reset_emergency_stop_handler(id.clone(), ctx)
+                    }
+                };
+                (id, result)
+            }
+            Err((id, e)) => (id, Err(e)),
+        };
+        if let Err(e) = result {
+            error!("{e:?}");
+            self.send_frame(SocketFrame::error(id, e), ctx);
+        }
+    }
+
+    /// Handles a binary velocity frame. Frames older than the last accepted one are dropped, failures
+    /// are reported to the client as JSON error frames
+    fn binary_handler(&mut self, bytes: &[u8], ctx: &mut <Self as Actor>::Context) {
+        let result =
+            VelocityFrame::parse(bytes).and_then(|frame| self.velocity_handler(frame, ctx));
+        if let Err(e) = result {
+            error!("{e:?}");
+            self.send_frame(SocketFrame::error(None, e), ctx);
+        }
+    }
+
+    fn velocity_handler(
+        &mut self,
+        frame: VelocityFrame,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        if !self.is_pilot {
+            let description = "only the pilot can control the robot, take control first";
+            return Err(SocketError::new(ErrorCode::NotPilot, description));
+        }
+        if let Some(last) = self.last_velocity_sequence {
+            if !binary_protocol::is_newer(frame.sequence, last) {
+                debug!("dropping stale {frame:?}, last sequence {last}");
+                return Ok(());
+            }
+        }
+        self.last_velocity_sequence = Some(frame.sequence);
+
+        let message = DriveMessage::Velocity {
+            x: frame.x,
+            y: frame.y,
+            rotation: frame.rotation,
+        };
+        debug!("sending {message:?} to mode manager");
+        self.mode_manager_data
+            .lock()?
+            .try_send(Command::manual(message))?;
+
+        // Don't keep driving if the connection drops mid-motion
+        self.cancel_velocity_timeout(ctx);
+        if !frame.is_stop() {
+            self.velocity_timeout = Some(ctx.run_later(VELOCITY_TIMEOUT, |act, _ctx| {
+                info!("no velocity frame received in {VELOCITY_TIMEOUT:?}, stopping");
+                act.velocity_timeout = None;
+                match act.mode_manager_data.lock() {
+                    Ok(mode_manager_addr) => {
+                        mode_manager_addr.do_send(Command::manual(DriveMessage::Disable))
+                    }
+                    Err(e) => error!("{e:?}"),
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    fn cancel_velocity_timeout(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some(handle) = self.velocity_timeout.take() {
+            ctx.cancel_future(handle);
         }
     }
 
@@ -57,6 +260,7 @@ impl WebSocket {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
                 // heartbeat timed out
                 error!("client heartbeat failed, disconnecting");
+                METRICS.heartbeat_timeouts.with_label_values(&["ws"]).inc();
                 ctx.stop();
                 return;
             }
@@ -69,44 +273,264 @@ impl WebSocket {
     fn motion_handler(
         &mut self,
         message: DriveMessage,
-        _ctx: &mut <WebSocket as Actor>::Context,
-    ) -> Result<(), Box<dyn std::error::Error + '_>> {
-        let drive_addr = self.drive_data.lock()?;
-        info!("sending {message:?} to drive");
-        drive_addr.try_send(message)?;
+        id: Option<RequestId>,
+        ctx: &mut <WebSocket as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        // Commands override joystick driving
+        self.cancel_velocity_timeout(ctx);
+        let mode_manager_addr = self.mode_manager_data.lock()?;
+        info!("sending {message:?} to mode manager");
+        let request = mode_manager_addr.send(Command::manual(message));
+        // Answer the client once drive has handled the command
+        ctx.spawn(
+            wrap_future(request).map(move |result, act: &mut Self, ctx| {
+                let frame = match result {
+                    Ok(Ok(_)) => SocketFrame::ack(id),
+                    Ok(Err(e)) => SocketFrame::error(id, e),
+                    Err(e) => SocketFrame::error(id, e.into()),
+                };
+                act.send_frame(frame, ctx);
+            }),
+        );
         Ok(())
     }
 
     fn measure_distance_handler(
         &mut self,
+        id: Option<RequestId>,
         ctx: &mut <Self as Actor>::Context,
-    ) -> Result<(), Box<dyn std::error::Error + '_>> {
+    ) -> Result<(), SocketError> {
         let hc_sr04_addr = self.hc_sr04_data.lock()?;
-        let message = HcSr04Message::Single(Recipient::WebSocket(ctx.address()));
+        let message = HcSr04Message::Single(ctx.address().recipient());
         info!("sending {message:?} to HC-SR04");
         hc_sr04_addr.try_send(message)?;
+        self.pending_measurements.push_back(id);
+        Ok(())
+    }
+
+    /// Sends the `MissionMessage` to jobs, which run missions for all clients, so that REST clients
+    /// and the web UI control the same mission. The client is answered once jobs has handled it
+    fn mission_handler(
+        &mut self,
+        message: MissionMessage,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        let jobs_addr = self.jobs_data.lock()?;
+        info!("sending {message:?} to jobs");
+        let request = jobs_addr.send(JobMessage::Mission(message));
+        ctx.spawn(
+            wrap_future(request).map(move |result, act: &mut Self, ctx| {
+                let frame = match result {
+                    Ok(Ok(_)) => SocketFrame::ack(id),
+                    Ok(Err(e)) => SocketFrame::error(id, e),
+                    Err(e) => SocketFrame::error(id, e.into()),
+                };
+                act.send_frame(frame, ctx);
+            }),
+        );
+        Ok(())
+    }
+
+    fn list_missions_handler(
+        &mut self,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        let request = self.jobs_data.lock()?.send(ListMissions);
+        ctx.spawn(
+            wrap_future(request).map(move |result, act: &mut Self, ctx| {
+                let frame = match result {
+                    Ok(Ok(names)) => SocketFrame::response(id, SocketPayload::Missions { names }),
+                    Ok(Err(e)) => SocketFrame::error(id, e),
+                    Err(e) => SocketFrame::error(id, e.into()),
+                };
+                act.send_frame(frame, ctx);
+            }),
+        );
+        Ok(())
+    }
+
+    /// Sends the `ControlMessage` created for this session by `message` to sessions
+    fn control_handler(
+        &mut self,
+        message: impl FnOnce(usize) -> ControlMessage,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        let session_id = self.session_id.ok_or_else(|| {
+            SocketError::new(ErrorCode::ControlUnavailable, "session not registered yet")
+        })?;
+        let sessions_addr = self.sessions_data.lock()?;
+        let message = message(session_id);
+        info!("sending {message:?} to sessions");
+        let request = sessions_addr.send(message);
+        // Answer the client once sessions has handled the request
+        ctx.spawn(
+            wrap_future(request).map(move |result, act: &mut Self, ctx| {
+                let frame = match result {
+                    Ok(Ok(())) => SocketFrame::ack(id),
+                    Ok(Err(e)) => SocketFrame::error(id, e),
+                    Err(e) => SocketFrame::error(id, e.into()),
+                };
+                act.send_frame(frame, ctx);
+            }),
+        );
+        Ok(())
+    }
+
+    /// Sends the `TelemetryMessage` created for this session by `message` to telemetry
+    fn telemetry_handler(
+        &mut self,
+        message: impl FnOnce(Recipient<TelemetrySnapshot>) -> TelemetryMessage,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        let telemetry_addr = self.telemetry_data.lock()?;
+        let message = message(ctx.address().recipient());
+        info!("sending {message:?} to telemetry");
+        telemetry_addr.try_send(message)?;
+        self.send_frame(SocketFrame::ack(id), ctx);
+        Ok(())
+    }
+
+    /// Engages the emergency stop. Every session which can control the robot is allowed to, not
+    /// just the pilot
+    fn emergency_stop_handler(
+        &mut self,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        if self.read_only {
+            return Err(SocketError::new(
+                ErrorCode::ControlUnavailable,
+                "read-only sessions can't stop the robot",
+            ));
+        }
+        let reason = match self.session_id {
+            Some(session) => format!("requested by session {session}"),
+            None => "requested by a client".to_string(),
+        };
+        info!("emergency stop {reason}");
+        // Brakes right away, even if the mode manager or the drive are busy
+        self.estop.trigger(Cause::Client, reason);
+        self.send_frame(SocketFrame::ack(id), ctx);
+        Ok(())
+    }
+
+    fn reset_emergency_stop_handler(
+        &mut self,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        let mode_manager_addr = self.mode_manager_data.lock()?;
+        info!("sending {ResetEmergencyStop:?} to mode manager");
+        mode_manager_addr.try_send(ResetEmergencyStop)?;
+        self.send_frame(SocketFrame::ack(id), ctx);
+        Ok(())
+    }
+
+    /// Stops the robot and all behaviours started by this session, called when it loses control
+    fn stop_robot(&mut self) {
+        if let Some(addr) = self.calibrator_addr.take() {
+            addr.do_send(CalibratorMessage::Stop);
+        }
+        if let Some(addr) = self.scanner_addr.take() {
+            addr.do_send(ScannerMessage::Stop);
+        }
+        if let Some(addr) = self.autopilot_addr.take() {
+            addr.do_send(AutopilotMessage::Stop);
+        }
+        if let Some(addr) = self.wall_follower_addr.take() {
+            addr.do_send(WallFollowerMessage::Stop);
+        }
+        if let Some(addr) = self.path_follower_addr.take() {
+            addr.do_send(PathFollowerMessage::Stop);
+        }
+        match self.mode_manager_data.lock() {
+            Ok(mode_manager_addr) => {
+                mode_manager_addr.do_send(Command::manual(DriveMessage::Disable))
+            }
+            Err(e) => error!("{e:?}"),
+        }
+    }
+
+    /// Acquires `mode` for the behaviour `addr`, then sends it `start`. The client is answered once
+    /// the mode manager decided, a behaviour which didn't get the mode is sent `stop`
+    fn start_behaviour<B, M>(
+        &self,
+        mode: Mode,
+        addr: Addr<B>,
+        start: M,
+        stop: M,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError>
+    where
+        B: Actor + Handler<M> + Handler<Preempted>,
+        B::Context: ToEnvelope<B, M> + ToEnvelope<B, Preempted>,
+        M: Message<Result = ()> + Send + Debug + 'static,
+    {
+        let acquire = Acquire {
+            mode,
+            owner: addr.clone().recipient(),
+        };
+        info!("sending {acquire:?} to mode manager");
+        let request = self.mode_manager_data.lock()?.send(acquire);
+        ctx.spawn(
+            wrap_future(request).map(move |result, act: &mut Self, ctx| {
+                let frame = match result.map_err(SocketError::from).and_then(|result| result) {
+                    Ok(()) => {
+                        info!("sending {start:?} to {mode:?} behaviour");
+                        addr.do_send(start);
+                        SocketFrame::ack(id)
+                    }
+                    Err(e) => {
+                        addr.do_send(stop);
+                        SocketFrame::error(id, e)
+                    }
+                };
+                act.send_frame(frame, ctx);
+            }),
+        );
         Ok(())
     }
 
     fn calibrator_handler(
         &mut self,
         msg: CalibratorMessage,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
         match msg {
             CalibratorMessage::Start(params) => {
-                // Send message to calibrator if it exists
-                if let Some(addr) = &self.calibrator_addr {
-                    info!("sending {msg:?} to calibrator");
-                    addr.try_send(msg)?;
-                    return Ok(());
-                }
-                // Create new calibrator otherwise
-                let calibrator =
-                    Calibrator::new(self.drive_data.clone(), self.hc_sr04_data.clone(), params);
-                let addr = calibrator.start();
+                // Use the calibrator if it exists and hasn't finished yet, create a new one otherwise
+                let addr = match self
+                    .calibrator_addr
+                    .as_ref()
+                    .filter(|addr| addr.connected())
+                {
+                    Some(addr) => addr.clone(),
+                    None => {
+                        info!("creating calibrator");
+                        Calibrator::new(
+                            self.mode_manager_data.clone(),
+                            self.hc_sr04_data.clone(),
+                            self.telemetry_data.lock()?.clone(),
+                            params,
+                        )
+                        .start()
+                    }
+                };
                 self.calibrator_addr = Some(addr.clone());
-                info!("created calibrator, sending {msg:?} to calibrator");
-                addr.try_send(msg)?;
+                self.start_behaviour(
+                    Mode::Calibrating,
+                    addr,
+                    msg,
+                    CalibratorMessage::Stop,
+                    id,
+                    ctx,
+                )
             }
             CalibratorMessage::Stop => {
                 // Stop calibration if calibrator already exists
@@ -115,66 +539,253 @@ impl WebSocket {
                     addr.try_send(CalibratorMessage::Stop)?;
                     self.calibrator_addr = None;
                 }
+                self.send_frame(SocketFrame::ack(id), ctx);
+                Ok(())
             }
         }
-        Ok(())
     }
 
-    fn scanner_handler(&mut self, msg: ScannerMessage) -> Result<(), Box<dyn std::error::Error>> {
+    fn scanner_handler(
+        &mut self,
+        msg: ScannerMessage,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
         match msg {
             ScannerMessage::Start {
                 speed,
                 slip,
                 resolution,
             } => {
-                // Send message to scanner if it exists
-                if let Some(addr) = &self.scanner_addr {
-                    info!("sending {msg:?} to scanner");
-                    addr.try_send(msg)?;
-                    return Ok(());
-                }
-                // Create new scanner otherwise
-                let scanner = Scanner::new(
-                    self.drive_data.clone(),
-                    self.hc_sr04_data.clone(),
-                    speed,
-                    slip,
-                    resolution,
-                );
-                let addr = scanner.start();
+                // Use the scanner if it exists and wasn't preempted, create a new one otherwise
+                let addr = match self.scanner_addr.as_ref().filter(|addr| addr.connected()) {
+                    Some(addr) => addr.clone(),
+                    None => {
+                        info!("creating scanner");
+                        Scanner::new(
+                            self.mode_manager_data.clone(),
+                            self.hc_sr04_data.clone(),
+                            self.telemetry_data.lock()?.clone(),
+                            self.sessions_data.lock()?.clone().recipient(),
+                            speed,
+                            slip,
+                            resolution,
+                        )
+                        .start()
+                    }
+                };
                 self.scanner_addr = Some(addr.clone());
-                info!("created scanner, sending {msg:?} to scanner");
-                addr.try_send(msg)?;
+                self.start_behaviour(Mode::Scanning, addr, msg, ScannerMessage::Stop, id, ctx)
             }
             ScannerMessage::Stop => {
                 // Stop scanning if scanner already exists
                 if let Some(addr) = &self.scanner_addr {
                     info!("sending {msg:?} to scanner");
                     addr.try_send(ScannerMessage::Stop)?;
-                    self.calibrator_addr = None;
+                    self.scanner_addr = None;
                 }
+                self.send_frame(SocketFrame::ack(id), ctx);
+                Ok(())
+            }
+        }
+    }
+
+    fn autopilot_handler(
+        &mut self,
+        msg: AutopilotMessage,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        match msg {
+            AutopilotMessage::Start(params) => {
+                // Use the autopilot if it exists and hasn't stopped on its own, create a new one
+                // otherwise
+                let addr = match self.autopilot_addr.as_ref().filter(|addr| addr.connected()) {
+                    Some(addr) => addr.clone(),
+                    None => {
+                        info!("creating autopilot");
+                        Autopilot::new(
+                            self.mode_manager_data.clone(),
+                            self.hc_sr04_data.clone(),
+                            self.sessions_data.lock()?.clone(),
+                            self.telemetry_data.lock()?.clone(),
+                            params,
+                        )
+                        .start()
+                    }
+                };
+                self.autopilot_addr = Some(addr.clone());
+                self.start_behaviour(Mode::Autonomous, addr, msg, AutopilotMessage::Stop, id, ctx)
+            }
+            AutopilotMessage::Stop => {
+                // Stop autopilot if it already exists
+                if let Some(addr) = &self.autopilot_addr {
+                    info!("sending {msg:?} to autopilot");
+                    addr.try_send(AutopilotMessage::Stop)?;
+                    self.autopilot_addr = None;
+                }
+                self.send_frame(SocketFrame::ack(id), ctx);
+                Ok(())
+            }
+        }
+    }
+
+    fn wall_follower_handler(
+        &mut self,
+        msg: WallFollowerMessage,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        match msg {
+            WallFollowerMessage::Start(params) => {
+                // Use the wall follower if it exists and hasn't stopped on its own, create a new
+                // one otherwise
+                let addr = match self
+                    .wall_follower_addr
+                    .as_ref()
+                    .filter(|addr| addr.connected())
+                {
+                    Some(addr) => addr.clone(),
+                    None => {
+                        info!("creating wall follower");
+                        WallFollower::new(
+                            self.mode_manager_data.clone(),
+                            self.hc_sr04_data.clone(),
+                            self.sessions_data.lock()?.clone(),
+                            self.telemetry_data.lock()?.clone(),
+                            params,
+                        )
+                        .start()
+                    }
+                };
+                self.wall_follower_addr = Some(addr.clone());
+                self.start_behaviour(
+                    Mode::Autonomous,
+                    addr,
+                    msg,
+                    WallFollowerMessage::Stop,
+                    id,
+                    ctx,
+                )
+            }
+            WallFollowerMessage::Stop => {
+                // Stop wall following if wall follower already exists
+                if let Some(addr) = &self.wall_follower_addr {
+                    info!("sending {msg:?} to wall follower");
+                    addr.try_send(WallFollowerMessage::Stop)?;
+                    self.wall_follower_addr = None;
+                }
+                self.send_frame(SocketFrame::ack(id), ctx);
+                Ok(())
+            }
+        }
+    }
+
+    fn path_follower_handler(
+        &mut self,
+        msg: PathFollowerMessage,
+        id: Option<RequestId>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Result<(), SocketError> {
+        match msg {
+            PathFollowerMessage::Start(ref params) => {
+                // Use the path follower if it exists and hasn't stopped on its own, create a new
+                // one otherwise
+                let addr = match self
+                    .path_follower_addr
+                    .as_ref()
+                    .filter(|addr| addr.connected())
+                {
+                    Some(addr) => addr.clone(),
+                    None => {
+                        info!("creating path follower");
+                        PathFollower::new(
+                            self.mode_manager_data.clone(),
+                            self.drive_data.lock()?.clone(),
+                            self.telemetry_data.lock()?.clone(),
+                            ctx.address().recipient(),
+                            params.clone(),
+                        )
+                        .start()
+                    }
+                };
+                self.path_follower_addr = Some(addr.clone());
+                self.start_behaviour(
+                    Mode::Autonomous,
+                    addr,
+                    msg,
+                    PathFollowerMessage::Stop,
+                    id,
+                    ctx,
+                )
+            }
+            PathFollowerMessage::Stop => {
+                // Stop path following if path follower already exists
+                if let Some(addr) = &self.path_follower_addr {
+                    info!("sending {msg:?} to path follower");
+                    addr.try_send(PathFollowerMessage::Stop)?;
+                    self.path_follower_addr = None;
+                }
+                self.send_frame(SocketFrame::ack(id), ctx);
+                Ok(())
             }
         }
-        Ok(())
     }
 }
 
 impl Actor for WebSocket {
     type Context = ws::WebsocketContext<Self>;
 
-    /// Method is called on actor start. We start the heartbeat process here.
+    /// Method is called on actor start. We register the session and start the heartbeat process
+    /// here.
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("actor started");
-        let drive_addr = self
-            .drive_data
+        METRICS
+            .websocket_connections
+            .with_label_values(&["ws"])
+            .inc();
+        let sessions_addr = self
+            .sessions_data
             .lock()
-            .expect("Failed to acquire lock on drive");
-        info!("sending address to drive");
-        drive_addr.do_send(AddrMessage(ctx.address()));
+            .expect("Failed to acquire lock on sessions");
+        info!("registering session");
+        // Wait for the session id before handling any messages
+        let connect = Connect {
+            addr: ctx.address(),
+            read_only: self.read_only,
+        };
+        wrap_future(sessions_addr.send(connect))
+            .map(
+                |result, act: &mut Self, ctx: &mut Self::Context| match result {
+                    Ok(id) => {
+                        info!("registered session {id}");
+                        act.session_id = Some(id);
+                    }
+                    Err(e) => {
+                        error!("failed to register session: {e:?}");
+                        ctx.stop();
+                    }
+                },
+            )
+            .wait(ctx);
         self.hb(ctx);
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
+        METRICS
+            .websocket_connections
+            .with_label_values(&["ws"])
+            .dec();
+        // Don't leave the robot moving without a pilot
+        if self.is_pilot {
+            self.stop_robot();
+        }
+        if let Some(id) = self.session_id {
+            match self.sessions_data.lock() {
+                Ok(sessions_addr) => sessions_addr.do_send(Disconnect(id)),
+                Err(e) => error!("{e:?}"),
+            }
+        }
         info!("actor stopped");
     }
 }
@@ -195,19 +806,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
                 self.hb = Instant::now();
             }
             // Text message
-            Ok(ws::Message::Text(text)) => {
-                let message: SocketMessage =
-                    serde_json::from_str(&text).expect("Failed to deserialize message");
-                match message {
-                    SocketMessage::Move(message) => self.motion_handler(message, ctx),
-                    SocketMessage::MeasureDistance => self.measure_distance_handler(ctx),
-                    SocketMessage::CalibrateMovement(message) => self.calibrator_handler(message),
-                    SocketMessage::ScanDistance(message) => self.scanner_handler(message),
-                }
-                .unwrap_or_else(|e| error!("{e:?}"));
-            }
+            Ok(ws::Message::Text(text)) => self.request_handler(&text, ctx),
             // Binary message
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Binary(bin)) => self.binary_handler(&bin, ctx),
             // Close page
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
@@ -218,72 +819,98 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
     }
 }
 
-// Send address to device actors
-#[derive(Debug, Message)]
-#[rtype(result = "()")]
-pub struct AddrMessage(pub Addr<WebSocket>);
+// Sessions message handling
 
-// Device actor response handling
+impl Handler<ClientFrame> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientFrame, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
 
-impl Handler<DriveResponse> for WebSocket {
+impl Handler<Shutdown> for WebSocket {
     type Result = ();
 
-    fn handle(&mut self, msg: DriveResponse, ctx: &mut Self::Context) -> Self::Result {
-        let response = serde_json::to_string(&SocketResponses::Move {
-            description: match msg {
-                DriveResponse::Ok(m) => match m {
-                    DriveMessage::Enable { motion, speed } => {
-                        format!("Moving {:?} with {:?} speed", motion, speed)
-                    }
-                    DriveMessage::Disable => "Stopped".to_string(),
-                    DriveMessage::Move {
-                        motion,
-                        speed,
-                        distance,
-                    } => format!("Moving {:?} {} m with {:?} speed", motion, distance, speed),
-                    DriveMessage::Rotate {
-                        motion,
-                        speed,
-                        angle,
-                    } => format!("Rotating {:?} {} deg with {:?} speed", motion, angle, speed),
-                },
-                DriveResponse::Err(e) => format!("Drive error: {:?}", e),
-            },
-        })
-        .expect("Failed to serialize message");
+    fn handle(&mut self, _msg: Shutdown, ctx: &mut Self::Context) {
+        self.stop_robot();
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some("robot is shutting down".to_string()),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<SessionsChanged> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, SessionsChanged(msg): SessionsChanged, ctx: &mut Self::Context) {
+        let Some(session_id) = self.session_id else {
+            return;
+        };
+        let is_pilot = msg.pilot == Some(session_id);
+        if self.is_pilot && !is_pilot {
+            info!("session {session_id} lost control, stopping the robot");
+            self.stop_robot();
+        }
+        self.is_pilot = is_pilot;
+        let frame = SocketFrame::event(SocketPayload::Sessions {
+            session: session_id,
+            pilot: msg.pilot,
+            sessions: msg.sessions,
+        });
+        self.send_frame(frame, ctx);
+    }
+}
+
+impl Handler<TelemetrySnapshot> for WebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: TelemetrySnapshot, ctx: &mut Self::Context) {
+        if self.binary_telemetry {
+            self.telemetry_sequence = self.telemetry_sequence.wrapping_add(1);
+            ctx.binary(binary_protocol::encode_telemetry(
+                self.telemetry_sequence,
+                &msg,
+            ));
+        } else {
+            self.send_frame(SocketFrame::event(SocketPayload::Telemetry(msg)), ctx);
+        }
+    }
+}
+
+impl Handler<PathFollowerResponse> for WebSocket {
+    type Result = ();
 
-        info!("sending {response} to client");
-        ctx.text(response);
+    fn handle(&mut self, msg: PathFollowerResponse, ctx: &mut Self::Context) {
+        self.send_frame(msg.into(), ctx);
     }
 }
 
+// Device actor response handling
+
 impl Handler<HcSr04Response> for WebSocket {
     type Result = ();
 
     fn handle(&mut self, msg: HcSr04Response, ctx: &mut Self::Context) {
-        // Handle the measurement result
-        let response = match msg {
-            HcSr04Response::Ok(dist) => serde_json::to_string(&SocketResponses::MeasureDistance {
-                measurement: match dist {
-                    HcSr04Measurement::Single(d) => d.distance,
+        // Responses arrive in the order the measurements were requested
+        let id = self.pending_measurements.pop_front().flatten();
+        let frame = match msg {
+            HcSr04Response::Ok(dist) => {
+                let measurements: Vec<f32> = match dist {
+                    HcSr04Measurement::Single(d) => vec![d.distance],
                     HcSr04Measurement::Multiple(d_vec) => {
-                        d_vec.iter().map(|x| x.distance).sum::<f32>() / d_vec.len() as f32
+                        d_vec.iter().map(|x| x.distance).collect()
                     }
-                },
-            })
-            .expect("Failed to serialize message"),
-            HcSr04Response::Err(e) => format!("HcSr04 error: {:?}", e),
+                };
+                SocketFrame::response(id, SocketPayload::measure_distance(measurements))
+            }
+            HcSr04Response::Err(e) => {
+                SocketFrame::error(id, SocketError::new(ErrorCode::SensorError, e))
+            }
         };
         // Send the response back to the WebSocket client
-        info!("sending {response} to client");
-        ctx.text(response);
+        self.send_frame(frame, ctx);
     }
 }
-
-/// WebSocket to client responses
-#[derive(Serialize)]
-#[serde(tag = "variant")]
-enum SocketResponses {
-    Move { description: String },
-    MeasureDistance { measurement: f32 },
-}
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use actix::prelude::*;
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    autopilot::AutopilotResponse,
+    collision_guard::GuardIntervened,
+    distance_scan::ScanResult,
+    drive::DriveResponse,
+    estop::EmergencyStopped,
+    mission::MissionProgress,
+    mode::ModeChanged,
+    protocol::{ErrorCode, SocketError, SocketFrame},
+    server::WebSocket,
+    shutdown::Shutdown,
+    supervision::DeviceRestarted,
+    wall_following::WallFollowerResponse,
+};
+
+/// Registry of connected WebSocket clients. Events are broadcast to all of them, but only one - the
+/// pilot - is allowed to control the robot, the others are read-only observers
+#[derive(Default)]
+pub struct Sessions {
+    sessions: BTreeMap<usize, Addr<WebSocket>>,
+    next_id: usize,
+    pilot: Option<usize>,
+    /// Unauthenticated sessions, which can never become the pilot
+    read_only: BTreeSet<usize>,
+    /// Bridges which receive scans but can't become the pilot
+    scan_observers: Vec<ScanObserver>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `frame` to all connected clients
+    fn broadcast(&self, frame: SocketFrame) {
+        let text = frame.to_json();
+        info!("broadcasting {text} to {} sessions", self.sessions.len());
+        for addr in self.sessions.values() {
+            addr.do_send(ClientFrame(text.clone()));
+        }
+    }
+
+    fn state(&self) -> SessionsState {
+        SessionsState {
+            pilot: self.pilot,
+            sessions: self.sessions.keys().copied().collect(),
+        }
+    }
+
+    /// Notifies all sessions about the current sessions and pilot
+    fn notify_sessions(&self) {
+        let msg = SessionsChanged(self.state());
+        info!("sending {msg:?} to all sessions");
+        for addr in self.sessions.values() {
+            addr.do_send(msg.clone());
+        }
+    }
+}
+
+impl Actor for Sessions {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("actor started");
+    }
+}
+
+/// Registers a new session, returns its id
+#[derive(Debug, Message)]
+#[rtype(result = "usize")]
+pub struct Connect {
+    pub addr: Addr<WebSocket>,
+    pub read_only: bool,
+}
+
+impl Handler<Connect> for Sessions {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut Self::Context) -> Self::Result {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(id, msg.addr);
+        if msg.read_only {
+            self.read_only.insert(id);
+        }
+        // The first client to connect while nobody is piloting becomes the pilot
+        if self.pilot.is_none() && !msg.read_only {
+            self.pilot = Some(id);
+        }
+        info!("session {id} connected, pilot: {:?}", self.pilot);
+        self.notify_sessions();
+        id
+    }
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct Disconnect(pub usize);
+
+impl Handler<Disconnect> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) -> Self::Result {
+        self.sessions.remove(&msg.0);
+        self.read_only.remove(&msg.0);
+        if self.pilot == Some(msg.0) {
+            self.pilot = None;
+        }
+        info!("session {} disconnected, pilot: {:?}", msg.0, self.pilot);
+        self.notify_sessions();
+    }
+}
+
+/// Requests to change the pilot, sent on behalf of a session
+#[derive(Debug, Message)]
+#[rtype(result = "Result<(), SocketError>")]
+pub enum ControlMessage {
+    /// Become the pilot, if there is none
+    Take(usize),
+    /// Stop being the pilot
+    Release(usize),
+    /// Pass control from the pilot to another session
+    HandOver { from: usize, to: usize },
+}
+
+impl Handler<ControlMessage> for Sessions {
+    type Result = Result<(), SocketError>;
+
+    fn handle(&mut self, msg: ControlMessage, _ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        let not_pilot = |id| {
+            SocketError::new(
+                ErrorCode::NotPilot,
+                format!("session {id} is not the pilot"),
+            )
+        };
+        let read_only = |id| {
+            SocketError::new(
+                ErrorCode::ControlUnavailable,
+                format!("session {id} is read-only"),
+            )
+        };
+        match msg {
+            ControlMessage::Take(id) if self.read_only.contains(&id) => return Err(read_only(id)),
+            ControlMessage::Take(id) => match self.pilot {
+                Some(pilot) if pilot != id => {
+                    return Err(SocketError::new(
+                        ErrorCode::ControlUnavailable,
+                        format!("session {pilot} is the pilot, ask it to hand over control"),
+                    ));
+                }
+                _ => self.pilot = Some(id),
+            },
+            ControlMessage::Release(id) => {
+                if self.pilot != Some(id) {
+                    return Err(not_pilot(id));
+                }
+                self.pilot = None;
+            }
+            ControlMessage::HandOver { from, to } => {
+                if self.pilot != Some(from) {
+                    return Err(not_pilot(from));
+                }
+                if !self.sessions.contains_key(&to) {
+                    return Err(SocketError::new(
+                        ErrorCode::ControlUnavailable,
+                        format!("session {to} is not connected"),
+                    ));
+                }
+                if self.read_only.contains(&to) {
+                    return Err(read_only(to));
+                }
+                self.pilot = Some(to);
+            }
+        }
+        self.notify_sessions();
+        Ok(())
+    }
+}
+
+/// Bridge which receives scans and is closed on shutdown
+#[derive(Debug)]
+pub struct ScanObserver {
+    pub scans: Recipient<ScanResult>,
+    pub shutdown: Recipient<Shutdown>,
+}
+
+impl ScanObserver {
+    fn connected(&self) -> bool {
+        self.scans.connected()
+    }
+}
+
+/// Registers a bridge to receive all `ScanResult`s
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct ObserveScans(pub ScanObserver);
+
+impl Handler<ObserveScans> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: ObserveScans, _ctx: &mut Self::Context) -> Self::Result {
+        self.scan_observers.retain(ScanObserver::connected);
+        self.scan_observers.push(msg.0);
+    }
+}
+
+/// Closes all sessions and bridges
+impl Handler<Shutdown> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        info!("sending {msg:?} to {} sessions", self.sessions.len());
+        for addr in self.sessions.values() {
+            addr.do_send(msg);
+        }
+        self.scan_observers.retain(ScanObserver::connected);
+        for observer in &self.scan_observers {
+            observer.shutdown.do_send(msg);
+        }
+    }
+}
+
+/// Requests the current `SessionsState`
+#[derive(Debug, Message)]
+#[rtype(result = "SessionsState")]
+pub struct GetSessions;
+
+impl Handler<GetSessions> for Sessions {
+    type Result = SessionsState;
+
+    fn handle(&mut self, _msg: GetSessions, _ctx: &mut Self::Context) -> Self::Result {
+        self.state()
+    }
+}
+
+// Events broadcast to all sessions
+
+impl Handler<DriveResponse> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: DriveResponse, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<ScanResult> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScanResult, _ctx: &mut Self::Context) -> Self::Result {
+        self.scan_observers.retain(ScanObserver::connected);
+        for observer in &self.scan_observers {
+            observer.scans.do_send(msg.clone());
+        }
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<AutopilotResponse> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: AutopilotResponse, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<WallFollowerResponse> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: WallFollowerResponse, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<MissionProgress> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: MissionProgress, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<ModeChanged> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: ModeChanged, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<EmergencyStopped> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: EmergencyStopped, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<GuardIntervened> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: GuardIntervened, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+impl Handler<DeviceRestarted> for Sessions {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeviceRestarted, _ctx: &mut Self::Context) -> Self::Result {
+        self.broadcast(msg.into());
+    }
+}
+
+/// Current pilot and ids of all connected sessions
+#[derive(Clone, Debug, MessageResponse, Serialize)]
+pub struct SessionsState {
+    pub pilot: Option<usize>,
+    pub sessions: Vec<usize>,
+}
+
+/// Sent to every session whenever the sessions or the pilot change
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct SessionsChanged(pub SessionsState);
+
+/// Serialized frame to be sent to the client
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct ClientFrame(pub String);
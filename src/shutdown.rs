@@ -0,0 +1,36 @@
+use std::{future::poll_fn, io, process, task::Poll, thread, time::Duration};
+
+use actix::prelude::*;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use log::error;
+
+/// Sent to actors when the program is about to exit, so that the robot is left stopped
+#[derive(Clone, Copy, Debug, Message)]
+#[rtype(result = "()")]
+pub struct Shutdown;
+
+/// Waits for `SIGINT` (Ctrl-C) or `SIGTERM` (e.g. `systemctl stop`), returns the signal's name
+pub async fn wait_for_signal() -> io::Result<&'static str> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(poll_fn(|cx| {
+        if interrupt.poll_recv(cx).is_ready() {
+            Poll::Ready("SIGINT")
+        } else if terminate.poll_recv(cx).is_ready() {
+            Poll::Ready("SIGTERM")
+        } else {
+            Poll::Pending
+        }
+    })
+    .await)
+}
+
+/// Exits the process if shutting down takes longer than `timeout`, e.g. because an actor is stuck
+pub fn exit_after(timeout: Duration) {
+    thread::spawn(move || {
+        thread::sleep(timeout);
+        error!("shutdown didn't finish in {timeout:?}, exiting");
+        log::logger().flush();
+        process::exit(1);
+    });
+}
@@ -0,0 +1,123 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use serde::Serialize;
+
+/// Delay before re-initializing a device after it crashed once
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The delay doubles with each crash in a row, up to this
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A crash this long after the previous one isn't considered part of a crash loop
+const STABLE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Actors controlling hardware, restarted by their `Supervisor` when they crash
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Device {
+    Drive,
+    HcSr04,
+}
+
+/// Restarts of a supervised actor, to back off when it keeps crashing
+#[derive(Debug, Default)]
+pub struct Restarts {
+    count: usize,
+    /// Restarts in a row, each within `STABLE_PERIOD` of the previous one
+    in_a_row: u32,
+    last: Option<Instant>,
+}
+
+impl Restarts {
+    /// Records a restart, returns how long to wait before re-initializing the device
+    pub fn record(&mut self) -> Duration {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < STABLE_PERIOD)
+        {
+            self.in_a_row += 1;
+        } else {
+            self.in_a_row = 0;
+        }
+        self.last = Some(now);
+        self.count += 1;
+        INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.in_a_row))
+            .min(MAX_BACKOFF)
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// Sent to `Sessions` when a device actor restarts, broadcast to all clients
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct DeviceRestarted {
+    pub device: Device,
+    /// Number of restarts since the program started
+    pub restarts: usize,
+    /// Time until the device is re-initialized
+    pub delay: Duration,
+}
+
+/// Runs `f`, returning the panic message if it panics. A panicking handler would silently kill
+/// the actor, this lets it stop instead, so that its `Supervisor` restarts it
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| panic_message(&*payload))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_while_crashing_in_a_row() {
+        let mut restarts = Restarts::default();
+        let delays: Vec<Duration> = (0..8).map(|_| restarts.record()).collect();
+        let expected = [0.5, 1., 2., 4., 8., 16., 30., 30.].map(Duration::from_secs_f64);
+        assert_eq!(delays, expected);
+        assert_eq!(restarts.count(), 8);
+    }
+
+    #[test]
+    fn backoff_resets_after_stable_period() {
+        let mut restarts = Restarts::default();
+        restarts.record();
+        restarts.record();
+        restarts.last = Some(Instant::now() - STABLE_PERIOD);
+        assert_eq!(restarts.record(), INITIAL_BACKOFF);
+        assert_eq!(restarts.count(), 3);
+    }
+
+    #[test]
+    fn catch_panic_returns_message() {
+        assert_eq!(catch_panic(|| 1), Ok(1));
+        assert_eq!(
+            catch_panic(|| -> () { panic!("echo pin blocked") }),
+            Err("echo pin blocked".to_string())
+        );
+        let pin = 20;
+        assert_eq!(
+            catch_panic(|| -> () { panic!("pin {pin} blocked") }),
+            Err("pin 20 blocked".to_string())
+        );
+    }
+}
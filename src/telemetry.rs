@@ -0,0 +1,270 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use actix::{fut::wrap_future, prelude::*};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{
+    drive::{Drive, DriveState, GetDriveState, Motion, Pose},
+    hc_sr04::{GetHcSr04State, HcSr04, HcSr04State},
+};
+
+/// Number of failed measurements in a row after which the distance sensor is considered failing
+pub const SENSOR_MAX_FAILURES: usize = 3;
+
+/// What is currently controlling the robot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Behaviour {
+    /// Only commands sent by clients
+    Manual,
+    Approaching,
+    Calibrating,
+    Scanning,
+    Autopilot,
+    WallFollowing,
+    PathFollowing,
+    Mission,
+}
+
+impl Behaviour {
+    /// Whether the behaviour keeps measuring the distance and can't work without it
+    pub fn uses_distance_sensor(self) -> bool {
+        matches!(
+            self,
+            Behaviour::Approaching
+                | Behaviour::Calibrating
+                | Behaviour::Scanning
+                | Behaviour::Autopilot
+                | Behaviour::WallFollowing
+        )
+    }
+}
+
+/// Sent by behaviour actors when they take or give up control of the robot
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub enum BehaviourMessage {
+    Started(Behaviour),
+    Stopped(Behaviour),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Forward,
+    Backward,
+    Stopped,
+}
+
+impl Direction {
+    fn from_duty_cycle(duty_cycle: f64) -> Self {
+        if duty_cycle > 0. {
+            Direction::Forward
+        } else if duty_cycle < 0. {
+            Direction::Backward
+        } else {
+            Direction::Stopped
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorHealth {
+    Ok,
+    /// Some of the latest measurements failed
+    Degraded,
+    /// At least `SENSOR_MAX_FAILURES` measurements in a row failed
+    Failing,
+}
+
+/// State of the robot, published periodically to all subscribers
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+pub struct TelemetrySnapshot {
+    /// Seconds since the server started
+    pub uptime: f64,
+    /// `null` while driven with a `Velocity` command
+    pub motion: Option<Motion>,
+    /// Per-wheel duty cycles (positive: forward, negative: backward)
+    pub duty_cycles: [f64; 4],
+    pub directions: [Direction; 4],
+    pub behaviours: Vec<Behaviour>,
+    /// Estimated by dead reckoning, drifts quickly
+    pub pose: Pose,
+    /// Most recent valid distance reading (in meters)
+    pub distance: Option<f32>,
+    pub sensor_health: SensorHealth,
+    /// In degrees Celsius, `null` if it couldn't be read
+    pub cpu_temperature: Option<f32>,
+}
+
+/// Periodically collects the state of the robot and publishes it to subscribers
+pub struct Telemetry {
+    drive_addr: Addr<Drive>,
+    hc_sr04_addr: Addr<HcSr04>,
+    interval: Duration,
+    /// Sysfs file with the CPU temperature in millidegrees Celsius
+    temperature_path: PathBuf,
+    start_time: Instant,
+    /// Number of running actors for each behaviour
+    behaviours: BTreeMap<Behaviour, usize>,
+    subscribers: Vec<Recipient<TelemetrySnapshot>>,
+    /// Whether the previous snapshot is still being collected
+    collecting: bool,
+}
+
+impl Telemetry {
+    pub fn new(
+        drive_addr: Addr<Drive>,
+        hc_sr04_addr: Addr<HcSr04>,
+        interval: Duration,
+        temperature_path: PathBuf,
+    ) -> Self {
+        Self {
+            drive_addr,
+            hc_sr04_addr,
+            interval,
+            temperature_path,
+            start_time: Instant::now(),
+            behaviours: BTreeMap::new(),
+            subscribers: Vec::new(),
+            collecting: false,
+        }
+    }
+
+    fn read_cpu_temperature(&self) -> Option<f32> {
+        let millidegrees: f32 = fs::read_to_string(&self.temperature_path)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        Some(millidegrees / 1000.)
+    }
+
+    fn snapshot(&self, drive: DriveState, sensor: HcSr04State) -> TelemetrySnapshot {
+        let mut behaviours: Vec<Behaviour> = self.behaviours.keys().copied().collect();
+        if drive.approach_target.is_some() {
+            behaviours.push(Behaviour::Approaching);
+        }
+        if behaviours.is_empty() {
+            behaviours.push(Behaviour::Manual);
+        }
+        let sensor_health = match sensor.consecutive_failures {
+            0 => SensorHealth::Ok,
+            n if n < SENSOR_MAX_FAILURES => SensorHealth::Degraded,
+            _ => SensorHealth::Failing,
+        };
+        TelemetrySnapshot {
+            uptime: self.start_time.elapsed().as_secs_f64(),
+            motion: drive.motion,
+            duty_cycles: drive.duty_cycles,
+            directions: drive.duty_cycles.map(Direction::from_duty_cycle),
+            behaviours,
+            pose: drive.pose,
+            distance: sensor.last_distance,
+            sensor_health,
+            cpu_temperature: self.read_cpu_temperature(),
+        }
+    }
+
+    /// Collects a snapshot and sends it to all subscribers
+    fn publish(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.subscribers.retain(Recipient::connected);
+        // The distance sensor blocks while measuring, don't pile up requests
+        if self.subscribers.is_empty() || self.collecting {
+            return;
+        }
+        self.collecting = true;
+        let drive_request = self.drive_addr.send(GetDriveState);
+        let hc_sr04_request = self.hc_sr04_addr.send(GetHcSr04State);
+        let fut = async move { (drive_request.await, hc_sr04_request.await) };
+        ctx.spawn(wrap_future(fut).map(|result, act: &mut Self, _ctx| {
+            act.collecting = false;
+            match result {
+                (Ok(drive), Ok(sensor)) => {
+                    let snapshot = act.snapshot(drive, sensor);
+                    for subscriber in &act.subscribers {
+                        subscriber.do_send(snapshot.clone());
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => error!("failed to collect telemetry: {e}"),
+            }
+        }));
+    }
+}
+
+impl Actor for Telemetry {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("actor started");
+        ctx.run_interval(self.interval, |act, ctx| act.publish(ctx));
+    }
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub enum TelemetryMessage {
+    Subscribe(Recipient<TelemetrySnapshot>),
+    Unsubscribe(Recipient<TelemetrySnapshot>),
+}
+
+impl Handler<TelemetryMessage> for Telemetry {
+    type Result = ();
+
+    fn handle(&mut self, msg: TelemetryMessage, _ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        match msg {
+            TelemetryMessage::Subscribe(subscriber) => {
+                if !self.subscribers.contains(&subscriber) {
+                    self.subscribers.push(subscriber);
+                }
+            }
+            TelemetryMessage::Unsubscribe(subscriber) => {
+                self.subscribers.retain(|s| *s != subscriber);
+            }
+        }
+    }
+}
+
+impl Handler<BehaviourMessage> for Telemetry {
+    type Result = ();
+
+    fn handle(&mut self, msg: BehaviourMessage, _ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        match msg {
+            BehaviourMessage::Started(behaviour) => {
+                *self.behaviours.entry(behaviour).or_default() += 1;
+            }
+            BehaviourMessage::Stopped(behaviour) => {
+                if let Some(count) = self.behaviours.get_mut(&behaviour) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.behaviours.remove(&behaviour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Requests the running behaviour actors. `Manual` and `Approaching` aren't included, they're
+/// derived from the drive state
+#[derive(Debug, Message)]
+#[rtype(result = "Vec<Behaviour>")]
+pub struct GetBehaviours;
+
+impl Handler<GetBehaviours> for Telemetry {
+    type Result = Vec<Behaviour>;
+
+    fn handle(&mut self, _msg: GetBehaviours, _ctx: &mut Self::Context) -> Self::Result {
+        self.behaviours.keys().copied().collect()
+    }
+}
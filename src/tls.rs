@@ -0,0 +1,32 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{crypto::ring, ServerConfig};
+
+fn invalid_data(description: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description.to_string())
+}
+
+/// Loads a PEM certificate chain and private key for serving `https` and `wss`
+pub fn load_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates in {}",
+            cert_path.display()
+        )));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid_data(format!("no private key in {}", key_path.display())))?;
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)
+}
@@ -0,0 +1,367 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::web::Data;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    drive::DriveMessage,
+    hc_sr04::{HcSr04, HcSr04Measurement, HcSr04Message, HcSr04Response},
+    mode::{Command, Mode, ModeManager, Preempted, Release},
+    protocol::{ErrorCode, SocketError},
+    sessions::Sessions,
+    telemetry::{Behaviour, BehaviourMessage, Telemetry},
+};
+
+/// How often the distance to the wall is measured
+const MEASUREMENT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct WallFollowerParams {
+    /// Distance to the wall (in meters) to hold
+    distance: f32,
+    /// Duty cycle of the sideways motion along the wall, positive moves right, negative left
+    speed: f64,
+    /// Maximum duty cycle used to correct the distance to the wall
+    max_correction: f64,
+    /// Deviation from `distance` (in meters) at which the wall is considered lost - a corner, a gap
+    /// or an obstacle
+    max_deviation: f32,
+    kp: f64,
+    ki: f64,
+    kd: f64,
+}
+
+impl WallFollowerParams {
+    /// Checks the parameters, so that an invalid wall follower is rejected before it acquires its
+    /// mode
+    pub fn validate(&self) -> Result<(), SocketError> {
+        let error =
+            |description: String| Err(SocketError::new(ErrorCode::InvalidMessage, description));
+        if !(self.distance.is_finite() && self.distance > 0.) {
+            return error(format!("distance {} is not positive", self.distance));
+        }
+        if !(-1. ..=1.).contains(&self.speed) {
+            return error(format!("speed {} is not within -1..=1", self.speed));
+        }
+        if !(0. ..=1.).contains(&self.max_correction) {
+            return error(format!(
+                "max correction {} is not within 0..=1",
+                self.max_correction
+            ));
+        }
+        if self.max_deviation.is_nan() || self.max_deviation <= 0. {
+            return error(format!(
+                "max deviation {} is not positive",
+                self.max_deviation
+            ));
+        }
+        if ![self.kp, self.ki, self.kd]
+            .iter()
+            .all(|gain| gain.is_finite())
+        {
+            return error("PID gains must be finite".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Simple PID controller
+#[derive(Debug, Default)]
+struct Pid {
+    integral: f64,
+    previous_error: Option<f64>,
+}
+
+impl Pid {
+    /// Returns the controller output for `error`, `dt` seconds after the previous update
+    fn update(&mut self, params: &WallFollowerParams, error: f64, dt: f64) -> f64 {
+        self.integral += error * dt;
+        let derivative = match self.previous_error {
+            Some(previous_error) if dt > 0. => (error - previous_error) / dt,
+            _ => 0.,
+        };
+        self.previous_error = Some(error);
+        let output = params.kp * error + params.ki * self.integral + params.kd * derivative;
+        output.clamp(-params.max_correction, params.max_correction)
+    }
+}
+
+/// Moves along a wall while keeping a fixed distance from it. The distance sensor faces forward, so
+/// the robot faces the wall and strafes along it, correcting the distance by moving forward or
+/// backward
+pub struct WallFollower {
+    mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+    hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+    sessions_addr: Addr<Sessions>,
+    telemetry_addr: Addr<Telemetry>,
+    params: WallFollowerParams,
+    pid: Pid,
+    last_measurement: Option<Instant>,
+    following: bool,
+}
+
+impl WallFollower {
+    pub fn new(
+        mode_manager_data: Data<Mutex<Addr<ModeManager>>>,
+        hc_sr04_data: Data<Mutex<Addr<HcSr04>>>,
+        sessions_addr: Addr<Sessions>,
+        telemetry_addr: Addr<Telemetry>,
+        params: WallFollowerParams,
+    ) -> Self {
+        Self {
+            mode_manager_data,
+            hc_sr04_data,
+            sessions_addr,
+            telemetry_addr,
+            params,
+            pid: Pid::default(),
+            last_measurement: None,
+            following: false,
+        }
+    }
+
+    /// Sends a drive command on behalf of the `Autonomous` mode
+    fn send_command(&self, ctx: &mut <Self as Actor>::Context, message: DriveMessage) {
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Command::behaviour(
+                Mode::Autonomous,
+                ctx.address().recipient(),
+                message,
+            ));
+    }
+
+    /// Logs an event and reports it to the clients
+    fn report(&self, response: WallFollowerResponse) {
+        info!("{}", response.description);
+        self.sessions_addr.do_send(response);
+    }
+
+    fn measure_distance(&self, ctx: &mut <Self as Actor>::Context) {
+        self.hc_sr04_data
+            .lock()
+            .unwrap()
+            .do_send(HcSr04Message::Single(ctx.address().recipient()));
+    }
+
+    /// Updates the velocity of the robot based on the measured `distance` to the wall
+    fn correct(&mut self, ctx: &mut <Self as Actor>::Context, distance: f32) {
+        let now = Instant::now();
+        let dt = self
+            .last_measurement
+            .map_or(0., |last| now.duration_since(last).as_secs_f64());
+        self.last_measurement = Some(now);
+
+        let error = (distance - self.params.distance) as f64;
+        let x = self.pid.update(&self.params, error, dt);
+        self.send_command(
+            ctx,
+            DriveMessage::Velocity {
+                x,
+                y: self.params.speed,
+                rotation: 0.,
+            },
+        );
+    }
+}
+
+impl Actor for WallFollower {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Started(Behaviour::WallFollowing));
+        info!("actor started");
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.telemetry_addr
+            .do_send(BehaviourMessage::Stopped(Behaviour::WallFollowing));
+        // Stops the robot
+        self.mode_manager_data
+            .lock()
+            .unwrap()
+            .do_send(Release(ctx.address().recipient()));
+        info!("actor stopped");
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Message)]
+#[rtype(result = "()")]
+#[serde(tag = "variant")]
+pub enum WallFollowerMessage {
+    Start(WallFollowerParams),
+    Stop,
+}
+
+impl Handler<WallFollowerMessage> for WallFollower {
+    type Result = ();
+
+    fn handle(&mut self, msg: WallFollowerMessage, ctx: &mut Self::Context) -> Self::Result {
+        info!("received {msg:?}");
+        match msg {
+            WallFollowerMessage::Start(params) => {
+                self.params = params;
+                self.pid = Pid::default();
+                self.last_measurement = None;
+                if !self.following {
+                    self.following = true;
+                    self.report(WallFollowerResponse::new(
+                        WallFollowerState::Following,
+                        format!("Following wall at {} m", params.distance),
+                    ));
+                    self.measure_distance(ctx);
+                }
+            }
+            WallFollowerMessage::Stop => {
+                self.report(WallFollowerResponse::new(
+                    WallFollowerState::Stopped,
+                    "Wall following stopped".to_string(),
+                ));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Handler<Preempted> for WallFollower {
+    type Result = ();
+
+    fn handle(&mut self, msg: Preempted, ctx: &mut Self::Context) -> Self::Result {
+        self.report(WallFollowerResponse::failed(
+            format!("preempted by {:?} mode", msg.0),
+            format!("Wall following preempted by {:?} mode", msg.0),
+        ));
+        ctx.stop();
+    }
+}
+
+impl Handler<HcSr04Response> for WallFollower {
+    type Result = ();
+
+    fn handle(&mut self, msg: HcSr04Response, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            HcSr04Response::Ok(HcSr04Measurement::Single(result)) => {
+                if (result.distance - self.params.distance).abs() > self.params.max_deviation {
+                    self.report(WallFollowerResponse {
+                        distance: Some(result.distance),
+                        ..WallFollowerResponse::failed(
+                            "wall lost".to_string(),
+                            format!(
+                                "Wall lost (distance {} m), stopping at corner or obstacle",
+                                result.distance
+                            ),
+                        )
+                    });
+                    ctx.stop();
+                    return;
+                }
+                self.correct(ctx, result.distance);
+                ctx.run_later(MEASUREMENT_INTERVAL, |act, ctx| act.measure_distance(ctx));
+            }
+            HcSr04Response::Ok(HcSr04Measurement::Multiple(_)) => (),
+            HcSr04Response::Err(e) => {
+                self.report(WallFollowerResponse::failed(
+                    e.to_string(),
+                    format!("Distance measurement failed: {e}, stopping"),
+                ));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum WallFollowerState {
+    Following,
+    Stopped,
+}
+
+/// Wall following event
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+pub struct WallFollowerResponse {
+    /// State the wall follower entered
+    pub state: WallFollowerState,
+    /// Measured distance (in meters) to the wall, if the event is based on it
+    pub distance: Option<f32>,
+    /// Why the wall follower stopped, unless it was asked to
+    pub error: Option<String>,
+    /// Human-readable summary, only meant to be displayed
+    pub description: String,
+}
+
+impl WallFollowerResponse {
+    fn new(state: WallFollowerState, description: String) -> Self {
+        Self {
+            state,
+            distance: None,
+            error: None,
+            description,
+        }
+    }
+
+    fn failed(error: String, description: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::new(WallFollowerState::Stopped, description)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(kp: f64, ki: f64, kd: f64) -> WallFollowerParams {
+        WallFollowerParams {
+            distance: 0.2,
+            speed: 0.5,
+            max_correction: 0.4,
+            max_deviation: 0.3,
+            kp,
+            ki,
+            kd,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn proportional_output_is_clamped() {
+        let params = params(2., 0., 0.);
+        let mut pid = Pid::default();
+        assert_close(pid.update(&params, 0.1, 0.1), 0.2);
+        assert_close(pid.update(&params, -0.1, 0.1), -0.2);
+        assert_close(pid.update(&params, 1., 0.1), 0.4);
+        assert_close(pid.update(&params, -1., 0.1), -0.4);
+    }
+
+    #[test]
+    fn integral_accumulates_over_time() {
+        let params = params(0., 1., 0.);
+        let mut pid = Pid::default();
+        assert_close(pid.update(&params, 0.1, 0.5), 0.05);
+        assert_close(pid.update(&params, 0.1, 0.5), 0.1);
+        assert_close(pid.update(&params, -0.2, 0.5), 0.);
+    }
+
+    #[test]
+    fn derivative_needs_previous_error_and_elapsed_time() {
+        let params = params(0., 0., 1.);
+        let mut pid = Pid::default();
+        // No previous error yet
+        assert_close(pid.update(&params, 0.1, 0.1), 0.);
+        assert_close(pid.update(&params, 0.12, 0.1), 0.2);
+        // The first measurement after a restart has no elapsed time
+        assert_close(pid.update(&params, 0.2, 0.), 0.);
+    }
+}
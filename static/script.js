@@ -4,13 +4,79 @@ const reconnectButton = document.getElementById("reconnect-button");
 
 let socket;
 
+// Version of the protocol spoken with the server
+const PROTOCOL_VERSION = 1;
+
+// Id of the last request sent to the server
+let requestId = 0;
+
+// Sends a request to the server, returns its id
+function sendMessage(message) {
+    requestId += 1;
+    socket.send(JSON.stringify({ version: PROTOCOL_VERSION, id: requestId, ...message }));
+    return requestId;
+}
+
+// Runtime configuration served by the robot (see src/assets.rs), used until it is loaded or if
+// loading it fails. The defaults assume the page was served by the robot as well
+let config = {
+    robot_name: "Robot control",
+    websocket_url: `${location.protocol === 'https:' ? 'wss' : 'ws'}://${location.host}/ws`,
+    speed_limits: { min_duty_cycle: 0.3, max_duty_cycle: 1.0 },
+    velocity_model: { scale: 0.3474, offset: 0.9077, exponent: 0.25, wheel_circumference: 0.25, robot_radius: 0.11 },
+};
+
+// Browsers can't set headers on WebSocket handshakes, so the page's `token` parameter is passed on
+function tokenQuery() {
+    const token = new URLSearchParams(location.search).get('token');
+    return token ? '?token=' + encodeURIComponent(token) : '';
+}
+
+async function loadConfig() {
+    try {
+        const response = await fetch('/config.json' + tokenQuery());
+        if (!response.ok) {
+            throw new Error(response.statusText);
+        }
+        config = await response.json();
+    } catch (error) {
+        console.log("Using the default configuration: " + error);
+    }
+    document.title = config.robot_name;
+    document.getElementById("robot-name").innerHTML = config.robot_name;
+    const speed = document.getElementById("speed");
+    speed.min = config.speed_limits.min_duty_cycle * 100;
+    speed.max = config.speed_limits.max_duty_cycle * 100;
+    updateSpeedLabels();
+}
+
+// Approximate velocity (in m/s) for a duty cycle, see `Speed::get_velocity`
+function velocity(dutyCycle) {
+    const model = config.velocity_model;
+    return Math.pow(Math.log(model.scale * dutyCycle + model.offset), model.exponent);
+}
+
+function updateSpeedLabels() {
+    const dutyCycle = parseFloat(document.getElementById("speed").value) / 100;
+    const wheelCircumference = config.velocity_model.wheel_circumference;
+    document.getElementById('duty-cycle-label').value = dutyCycle;
+    document.getElementById('velocity-label').value = velocity(dutyCycle).toFixed(2) + ' m/s';
+    document.getElementById('rpm-label').value = (60 * velocity(dutyCycle) / wheelCircumference).toFixed(2) + ' rpm';
+}
+
+document.getElementById("speed").addEventListener("input", updateSpeedLabels);
+
 function connectWebSocket() {
-    socket = new WebSocket('ws://192.168.1.17:7878/ws');
+    socket = new WebSocket(config.websocket_url + tokenQuery());
 
     socket.onopen = function () {
         console.log("Connection established");
         connectionStatus.innerHTML = 'Connection status: connected';
         reconnectButton.disabled = true;
+        if (telemetryEnabled.checked) {
+            sendMessage({ message: "SubscribeTelemetry" });
+        }
+        sendMessage({ message: "ListMissions" });
     };
 
     socket.onclose = function () {
@@ -24,10 +90,12 @@ function connectWebSocket() {
         connectionStatus.innerHTML = 'Connection status: error';
         reconnectButton.disabled = false;
     };
+
+    socket.onmessage = handleMessage;
 }
 
 // Start the socket connection
-connectWebSocket();
+loadConfig().then(connectWebSocket);
 
 // Move button events
 
@@ -52,8 +120,7 @@ function sendMoveMessage(motion) {
             Manual: speed_value,
         },
     };
-    const messageJson = JSON.stringify(message);
-    socket.send(messageJson)
+    sendMessage(message);
 }
 
 // Called when Calibrate movement button is clicked
@@ -64,7 +131,7 @@ function calibrateMovementStart() {
     measurementsPerRepetition = document.getElementById("measurements-per-repetition").value;
     repetitions = document.getElementById("repetitions").value;
 
-    message = JSON.stringify({
+    sendMessage({
         message: "CalibrateMovement",
         variant: "Start",
         min_duty_cycle: parseFloat(minDutyCycle),
@@ -73,7 +140,6 @@ function calibrateMovementStart() {
         measurements_per_repetition: parseInt(measurementsPerRepetition),
         repetitions: parseInt(repetitions)
     });
-    socket.send(message);
     document.getElementById("calibrate-movement-stop").disabled = false;
 }
 
@@ -85,16 +151,15 @@ function addMoveButtonEvent(id, motion) {
         message: "Move",
         variant: "Disable",
     }
-    const stopMessageJson = JSON.stringify(stopMessage);
 
     // Desktop
     button.addEventListener("mousedown", () => sendMoveMessage(motion));
-    button.addEventListener("mouseup", () => socket.send(stopMessageJson));
-    button.addEventListener("mouseout", () => socket.send(stopMessageJson));
+    button.addEventListener("mouseup", () => sendMessage(stopMessage));
+    button.addEventListener("mouseout", () => sendMessage(stopMessage));
 
     // Mobile
     button.addEventListener("touchstart", () => sendMoveMessage(motion));
-    button.addEventListener("touchend", () => socket.send(stopMessageJson));
+    button.addEventListener("touchend", () => sendMessage(stopMessage));
 
     console.log("Added move button event " + motion + " for button " + id);
 }
@@ -112,15 +177,15 @@ move_buttons = ["forward-left", "forward", "forward-right", "right", "backward-r
 move_buttons.forEach(id => addMoveButtonEvent(id, snakeToPascal(id)));
 
 // Measure distance button
-document.getElementById("measure-distance").addEventListener("click", () => socket.send(JSON.stringify({ message: "MeasureDistance" })));
+document.getElementById("measure-distance").addEventListener("click", () => sendMessage({ message: "MeasureDistance" }));
 
 // Calibrate distance
 document.getElementById("calibrate-movement-start").addEventListener("click", () => calibrateMovementStart());
 document.getElementById("calibrate-movement-stop").addEventListener("click", () => {
-    socket.send(JSON.stringify({
+    sendMessage({
         message: "CalibrateMovement",
         variant: "Stop"
-    }));
+    });
     document.getElementById("calibrate-movement-stop").disabled = true;
 });
 
@@ -136,8 +201,7 @@ document.getElementById("move-distance").addEventListener("click", () => {
         },
         distance: 0.5
     };
-    const messageJson = JSON.stringify(message);
-    socket.send(messageJson);
+    sendMessage(message);
 });
 
 // Rotate angle
@@ -153,8 +217,21 @@ document.getElementById("rotate-angle").addEventListener("click", () => {
         },
         angle: 360.0 * (1 + slip_value)
     };
-    const messageJson = JSON.stringify(message);
-    socket.send(messageJson);
+    sendMessage(message);
+});
+
+// Approach distance
+document.getElementById("approach-distance").addEventListener("click", () => {
+    speed_value = parseFloat(document.getElementById("speed").value) / 100;
+    const message = {
+        message: "Move",
+        variant: "ApproachDistance",
+        target: parseFloat(document.getElementById("approach-target").value),
+        speed: {
+            Manual: speed_value,
+        },
+    };
+    sendMessage(message);
 });
 
 // Scan distance
@@ -170,20 +247,240 @@ document.getElementById("scan-distance").addEventListener("click", () => {
         slip: slip_value,
         resolution: 20,
     };
-    const messageJson = JSON.stringify(message);
-    socket.send(messageJson);
+    sendMessage(message);
+});
+
+// Autopilot
+document.getElementById("autopilot-start").addEventListener("click", () => {
+    speed_value = parseFloat(document.getElementById("speed").value) / 100;
+    slip_value = document.getElementById("rotation-slip").value * 0.01;
+    const message = {
+        message: "Autopilot",
+        variant: "Start",
+        speed: {
+            Manual: speed_value,
+        },
+        obstacle_distance: parseFloat(document.getElementById("obstacle-distance").value),
+        slip: slip_value,
+        resolution: 20,
+    };
+    sendMessage(message);
+    document.getElementById("autopilot-stop").disabled = false;
+});
+document.getElementById("autopilot-stop").addEventListener("click", () => {
+    sendMessage({
+        message: "Autopilot",
+        variant: "Stop"
+    });
+    document.getElementById("autopilot-stop").disabled = true;
+});
+
+// Wall following
+function followWallStart(direction) {
+    speed_value = parseFloat(document.getElementById("speed").value) / 100;
+    const message = {
+        message: "FollowWall",
+        variant: "Start",
+        distance: parseFloat(document.getElementById("wall-distance").value),
+        speed: direction * speed_value,
+        max_correction: 0.6,
+        max_deviation: 0.15,
+        kp: 4.0,
+        ki: 0.0,
+        kd: 0.5,
+    };
+    sendMessage(message);
+    document.getElementById("follow-wall-stop").disabled = false;
+}
+document.getElementById("follow-wall-left").addEventListener("click", () => followWallStart(-1));
+document.getElementById("follow-wall-right").addEventListener("click", () => followWallStart(1));
+document.getElementById("follow-wall-stop").addEventListener("click", () => {
+    sendMessage({
+        message: "FollowWall",
+        variant: "Stop"
+    });
+    document.getElementById("follow-wall-stop").disabled = true;
 });
 
-socket.addEventListener("message", (msg) => {
+// Missions
+function sendMission(variant) {
+    sendMessage({ message: "Mission", variant: variant });
+}
+
+document.getElementById("mission-start").addEventListener("click", () => {
+    sendMessage({
+        message: "Mission",
+        variant: "Start",
+        name: document.getElementById("mission-name").value,
+    });
+});
+document.getElementById("mission-pause").addEventListener("click", () => sendMission("Pause"));
+document.getElementById("mission-resume").addEventListener("click", () => sendMission("Resume"));
+document.getElementById("mission-skip").addEventListener("click", () => sendMission("Skip"));
+document.getElementById("mission-abort").addEventListener("click", () => sendMission("Abort"));
+
+function updateMissionList(names) {
+    const select = document.getElementById("mission-name");
+    select.innerHTML = "";
+    for (const name of names) {
+        const option = document.createElement("option");
+        option.value = name;
+        option.textContent = name;
+        select.appendChild(option);
+    }
+    document.getElementById("mission-start").disabled = names.length === 0;
+}
+
+function updateMission(msg) {
+    const active = msg.status === "running" || msg.status === "paused";
+    document.getElementById("mission-label").innerHTML =
+        "Step " + Math.min(msg.step + 1, msg.steps) + "/" + msg.steps + ": " + msg.description;
+    document.getElementById("mission-start").disabled = active;
+    document.getElementById("mission-pause").disabled = msg.status !== "running";
+    document.getElementById("mission-resume").disabled = msg.status !== "paused";
+    document.getElementById("mission-skip").disabled = !active;
+    document.getElementById("mission-abort").disabled = !active;
+}
+
+// Control
+document.getElementById("take-control").addEventListener("click", () => sendMessage({ message: "TakeControl" }));
+document.getElementById("release-control").addEventListener("click", () => sendMessage({ message: "ReleaseControl" }));
+
+// Emergency stop, allowed for every session, not just the pilot
+document.getElementById("emergency-stop").addEventListener("click", () => sendMessage({ message: "EmergencyStop" }));
+document.getElementById("reset-emergency-stop").addEventListener("click", () => sendMessage({ message: "ResetEmergencyStop" }));
+
+function updateEmergencyStop(engaged, status) {
+    document.getElementById("emergency-stop-status").innerHTML = "Emergency stop: " + status;
+    document.getElementById("reset-emergency-stop").disabled = !engaged;
+}
+
+// Gamepad driving, sends binary velocity frames (see src/binary_protocol.rs)
+let velocitySequence = 0;
+
+function sendVelocity(x, y, rotation) {
+    const frame = new DataView(new ArrayBuffer(12));
+    velocitySequence = (velocitySequence + 1) >>> 0;
+    frame.setUint8(0, 0x01);
+    frame.setUint8(1, PROTOCOL_VERSION);
+    frame.setUint32(2, velocitySequence, true);
+    frame.setInt16(6, Math.round(x * 32767), true);
+    frame.setInt16(8, Math.round(y * 32767), true);
+    frame.setInt16(10, Math.round(rotation * 32767), true);
+    socket.send(frame.buffer);
+}
+
+let gamepadMoving = false;
+setInterval(() => {
+    const gamepad = navigator.getGamepads().find(g => g);
+    if (!gamepad || socket.readyState !== WebSocket.OPEN) {
+        return;
+    }
+    const speed_value = parseFloat(document.getElementById("speed").value) / 100;
+    const deadzone = value => Math.abs(value) < 0.1 ? 0 : value * speed_value;
+    // Left stick moves, right stick rotates
+    const x = -deadzone(gamepad.axes[1]);
+    const y = deadzone(gamepad.axes[0]);
+    const rotation = deadzone(gamepad.axes[2]);
+    const moving = x !== 0 || y !== 0 || rotation !== 0;
+    // Keep sending while moving, then stop once
+    if (moving || gamepadMoving) {
+        sendVelocity(x, y, rotation);
+    }
+    gamepadMoving = moving;
+}, 1000 / 30);
+
+// Telemetry
+const telemetryEnabled = document.getElementById("telemetry-enabled");
+telemetryEnabled.addEventListener("change", () => {
+    sendMessage({ message: telemetryEnabled.checked ? "SubscribeTelemetry" : "UnsubscribeTelemetry" });
+    if (!telemetryEnabled.checked) {
+        document.getElementById("telemetry-label").innerHTML = "";
+    }
+});
+
+// Shows who controls the robot
+function updateControl(msg) {
+    const isPilot = msg.pilot === msg.session;
+    let status;
+    if (isPilot) {
+        status = "you are the pilot";
+    } else if (msg.pilot === null) {
+        status = "nobody is the pilot";
+    } else {
+        status = "session " + msg.pilot + " is the pilot";
+    }
+    document.getElementById("control-status").innerHTML =
+        "Control: " + status + " (" + msg.sessions.length + " connected)";
+    document.getElementById("take-control").disabled = isPilot;
+    document.getElementById("release-control").disabled = !isPilot;
+}
+
+// Handles frames received from the server
+function handleMessage(msg) {
     msg = JSON.parse(msg.data);
-    // console.log(msg)
+    switch (msg.type) {
+        case "ack":
+            break;
+        case "error":
+            console.log("Request " + msg.id + " failed (" + msg.code + "): " + msg.description);
+            break;
+        case "response":
+        case "event":
+            handlePayload(msg);
+            break;
+    }
+}
+
+// Handles responses and events
+function handlePayload(msg) {
     switch (msg.variant) {
         case "Move":
-            console.log(msg.description);
+            console.log(msg.command, msg.duty_cycles);
+            break;
+        case "ApproachFinished":
+            console.log("Approached " + msg.distance + " m");
             break;
         case "MeasureDistance":
             console.log(msg.measurement);
             document.getElementById("distance-label").innerHTML = msg.measurement + " m";
             break;
+        case "ScanDistance":
+            console.log(msg.measurements);
+            break;
+        case "Autopilot":
+            console.log(msg.description);
+            document.getElementById("autopilot-label").innerHTML = msg.description;
+            break;
+        case "FollowWall":
+            console.log(msg.description);
+            document.getElementById("wall-follower-label").innerHTML = msg.description;
+            break;
+        case "FollowPath":
+            console.log(msg.description);
+            break;
+        case "Missions":
+            updateMissionList(msg.names);
+            break;
+        case "Mission":
+            console.log(msg.description);
+            updateMission(msg);
+            break;
+        case "Sessions":
+            updateControl(msg);
+            break;
+        case "EmergencyStop":
+            updateEmergencyStop(true, "engaged by " + msg.cause + ", " + msg.reason);
+            break;
+        case "Mode":
+            if (msg.previous === "e_stopped") {
+                updateEmergencyStop(false, "released");
+            }
+            break;
+        case "Telemetry": {
+            const { version, type, variant, ...telemetry } = msg;
+            document.getElementById("telemetry-label").innerHTML = JSON.stringify(telemetry, null, 2);
+            break;
+        }
     }
-});
\ No newline at end of file
+}